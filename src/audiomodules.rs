@@ -1,11 +1,20 @@
 pub mod advanced_gate;
 pub mod chorus;
 pub mod delay;
+pub mod envelope;
+pub mod event_scheduler;
 pub mod gain;
 pub mod glide;
+pub mod lfo;
 pub mod low_pass_filter;
+pub mod mixer;
+pub mod mod_envelope;
+pub mod mod_matrix;
 pub mod oscillator;
+pub mod phaser;
+pub mod preset_switch;
 pub mod reverb;
+pub mod smoother;
 pub mod modulator;
 
 pub trait AudioModule: Send + Sync {
@@ -1,14 +1,15 @@
+use crate::audiomodules::envelope::{AdsrParams, Envelope};
+use crate::audiomodules::smoother::SmoothedParam;
+use crate::params::ParamId;
 use crate::synth_state::SynthState;
 use crate::{audiomodules::AudioModule, Ordering};
 use std::sync::Arc;
 
 
-const SR: f32 = 44100.0; // sample rate per second
-const MAX: f32 = 4500.0;
-
-
 pub enum GateState {
+  Delay,
   Attack,
+  Hold,
   Decay,
   Sustain,
   Release,
@@ -16,110 +17,65 @@ pub enum GateState {
 }
 
 pub struct AdvGate {
-
-  envelop: f32,
-  gate_state: GateState,
+  envelope: Envelope,
+  sustain: SmoothedParam,
+  channels: usize,
   synth_state: Arc<SynthState>,
 }
 
 impl AdvGate {
-  pub fn new(
-    envelop: f32,
-    gate_state: GateState,
-    synth_state: Arc<SynthState>,
-  ) -> Self {
+  pub fn new(sample_rate: f32, channels: usize, synth_state: Arc<SynthState>) -> Self {
     Self {
-      envelop,
-      gate_state,
+      envelope: Envelope::new(sample_rate),
+      sustain: SmoothedParam::new(ParamId::GateSustain, sample_rate, &synth_state),
+      channels: channels.max(1),
       synth_state,
     }
   }
 
-  fn get_envelop(&self) -> f32 {
-    //GET ENVELOP
-    return self.envelop;
-  }
-
-  fn check_unpress(&mut self) {
-    if self.synth_state.has_key_pressed.load(Ordering::Relaxed) == false {
-      self.gate_state = GateState::Release;
-    }
-  }
-  fn check_press(&mut self) {
-    if self.synth_state.has_key_pressed.load(Ordering::Relaxed) == true {
-      self.gate_state = GateState::Attack;
+  fn read_params(&self, sustain: f32) -> AdsrParams {
+    let s = &self.synth_state;
+    // сильное нажатие укорачивает атаку и спад (до 10% от исходного времени)
+    let velocity = s.last_velocity.load(Ordering::Relaxed);
+    let env_amount = s.value(ParamId::VelocityToEnv);
+    let time_scale = 1.0 - 0.9 * env_amount * s.velocity_response(velocity);
+    AdsrParams {
+      delay_ms: 0.0,
+      attack_ms: s.value(ParamId::GateAttack) * time_scale,
+      hold_ms: 0.0,
+      decay_ms: s.value(ParamId::GateDecay) * time_scale,
+      sustain,
+      release_ms: s.value(ParamId::GateRelease),
+      attack_curve: s.value(ParamId::GateAttackCurve),
+      decay_curve: s.value(ParamId::GateDecayCurve),
+      release_curve: s.value(ParamId::GateReleaseCurve),
     }
   }
 
-  fn update_envelop(&mut self) {
-
-     let decay = (self.synth_state.gate_decay.load(std::sync::atomic::Ordering::Relaxed) as f32)/127.0*MAX;
-    let attack = (self.synth_state.gate_attack.load(std::sync::atomic::Ordering::Relaxed) as f32)/127.0*MAX;
-    let sustain = (self.synth_state.gate_sustain.load(std::sync::atomic::Ordering::Relaxed) as f32)/127.0;
-    let release = (self.synth_state.gate_release.load(std::sync::atomic::Ordering::Relaxed) as f32)/127.0*MAX;
-
-    match self.gate_state {
-      GateState::Idle => {
-        //IDLE
-        self.envelop = 0.0;
-        self.check_press();
-      },
-      GateState::Attack => 'block: {
-        //ATTACK
-        if attack == 0.0 {
-          self.gate_state = GateState::Decay;
-          break 'block;
-        }
-        self.envelop += 1.0 / (attack as f32 * 0.02 * SR);
-
-        if self.envelop >= 1.0 {
-          self.envelop = 1.0;
-          self.gate_state = GateState::Decay;
-        }
-        self.check_unpress();
-      },
-      GateState::Decay => 'block: {
-        //DECAY
-        if decay == 0.0 {
-          self.gate_state = GateState::Sustain;
-          break 'block;
-        }
-        self.envelop -= 1.0 / (decay  * 0.02 * SR);
-
-        if self.envelop <= (sustain ) / 255.0 {
-          self.gate_state = GateState::Sustain;
-        }
-        self.check_unpress();
-      },
-      GateState::Sustain => {
-        //SUSTAIN
-        self.envelop = sustain / 255.0;
-        self.check_unpress();
-      },
-      GateState::Release => 'block: {
-        //RELEASE
-        if release == 0.0 {
-          self.gate_state = GateState::Idle;
-          break 'block;
-        }
-        self.envelop -= 1.0 / (release * 0.02 * SR);
-
-        if self.envelop <= 0.0 {
-          self.envelop = 0.0;
-          self.gate_state = GateState::Idle;
-        }
-        self.check_press();
-      },
+  fn update_gate(&mut self) {
+    let pressed = self.synth_state.has_key_pressed.load(Ordering::Relaxed);
+    if pressed && self.envelope.is_releasing() {
+      self.envelope.gate_on();
+    } else if !pressed {
+      self.envelope.gate_off();
     }
   }
 }
 
 impl AudioModule for AdvGate {
   fn process(&mut self, output: &mut [f32]) {
-    for sample in output.iter_mut() {
-      self.update_envelop();
-      *sample *= self.get_envelop();
+    let frames = output.len() / self.channels;
+    self.sustain.update(&self.synth_state, frames);
+    let sustain = self.sustain.skip(frames);
+    let params = self.read_params(sustain);
+    self.envelope.set_params(&params);
+    self.update_gate();
+    // огибающая идёт по кадрам: все каналы кадра получают одно значение
+    for frame in output.chunks_mut(self.channels) {
+      let level = self.envelope.next();
+      for sample in frame.iter_mut() {
+        *sample *= level;
+      }
     }
   }
 }
-
@@ -1,11 +1,10 @@
+use crate::audiomodules::smoother::SmoothedParam;
 use crate::audiomodules::AudioModule;
+use crate::params::ParamId;
 use std::f32::consts::TAU;
 use crate::synth_state::SynthState;
 use std::sync::Arc;
 
-const MAX_LFO_FREQ: f32=5.0;
-const MAX_VAR_SEC: f32=0.01;
-const MAX_DELAY_SEC: f32=0.05;
 pub struct Chorus {
   sample_rate: f32,
   // максимальная задержка в сэмплах (позволяет избежать переполнения)
@@ -14,6 +13,11 @@ pub struct Chorus {
   write_pos: usize,
   lfo_phase: f32, // текущее значение фазы LFO
 
+  base_delay: SmoothedParam,
+  variation: SmoothedParam,
+  feedback: SmoothedParam,
+  mix: SmoothedParam,
+
   synthstate: Arc<SynthState>,           // 0..1 (0 = только сухой, 1 = только эффект)
 }
 
@@ -27,6 +31,10 @@ impl Chorus {
       buffer: vec![0.0; max_delay_samples + 2], // +2 safety for interpolation
       write_pos: 0,
 
+      base_delay: SmoothedParam::new(ParamId::ChorusBaseDelay, sample_rate, &synthstate),
+      variation: SmoothedParam::new(ParamId::ChorusVariation, sample_rate, &synthstate),
+      feedback: SmoothedParam::new(ParamId::ChorusFeedback, sample_rate, &synthstate),
+      mix: SmoothedParam::new(ParamId::ChorusMix, sample_rate, &synthstate),
       synthstate,
     }
   }
@@ -61,13 +69,18 @@ impl Chorus {
 
 impl AudioModule for Chorus {
   fn process(&mut self, input: &mut [f32]) {
+    // частоту LFO не сглаживаем: она меняет только скорость фазы, скачка в сигнале нет
+    let lfo_freq= self.synthstate.value(ParamId::ChorusLfoFreq);
+    self.base_delay.update(&self.synthstate, input.len());
+    self.variation.update(&self.synthstate, input.len());
+    self.feedback.update(&self.synthstate, input.len());
+    self.mix.update(&self.synthstate, input.len());
     for sample in input.iter_mut() {
 
-      let lfo_freq= (self.synthstate.chorus_lfo_freq.load(std::sync::atomic::Ordering::Relaxed) as f32) /127.0*MAX_LFO_FREQ;
-      let base_delay_sec= (self.synthstate.chorus_base_delay_sec.load(std::sync::atomic::Ordering::Relaxed) as f32) /127.0*MAX_DELAY_SEC;
-      let variation_sec= (self.synthstate.chorus_variation_sec.load(std::sync::atomic::Ordering::Relaxed) as f32) /127.0*MAX_VAR_SEC;
-      let feedback= (self.synthstate.chorus_feedback.load(std::sync::atomic::Ordering::Relaxed) as f32) /127.0;
-      let mix= (self.synthstate.chorus_mix.load(std::sync::atomic::Ordering::Relaxed) as f32) /127.0;
+      let base_delay_sec= self.base_delay.next();
+      let variation_sec= self.variation.next();
+      let feedback= self.feedback.next();
+      let mix= self.mix.next();
       let lfo = (self.lfo_phase).sin(); // -1..1
       let current_delay_sec = base_delay_sec + lfo * variation_sec;
       let current_delay_samples = current_delay_sec * self.sample_rate;
@@ -1,58 +1,134 @@
-use crate::audiomodules::{delay, AudioModule};
-use crate::synth_state::SynthState;
+use crate::audiomodules::smoother::{SmoothedParam, Smoother};
+use crate::audiomodules::AudioModule;
+use crate::params::ParamId;
+use crate::synth_state::{SynthState, MIN_TEMPO_BPM};
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
 
-const MAX_DELAY_SEC: f32 = 1.5; 
+/// Время эха в долях четверти для `ParamId::DelayDivision` 1..=11
+/// (0 -- время в секундах из `ParamId::DelayTime`)
+const DIVISIONS: [f32; 11] = [
+  4.0,        // 1 такт
+  2.0,        // 1/2
+  1.0,        // 1/4
+  2.0 / 3.0,  // 1/4 триоль
+  0.75,       // 1/8 с точкой
+  0.5,        // 1/8
+  1.0 / 3.0,  // 1/8 триоль
+  0.375,      // 1/16 с точкой
+  0.25,       // 1/16
+  1.0 / 6.0,  // 1/16 триоль
+  0.125,      // 1/32
+];
 
+/// Самое длинное эхо: такт на самом медленном темпе MIDI clock
+const MAX_DELAY_SEC: f32 = DIVISIONS[0] * 60.0 / MIN_TEMPO_BPM;
+
+/// Длина эха в четвертях для деления такта, `None` -- время в секундах
+fn division_beats(division: u8) -> Option<f32> {
+  DIVISIONS.get((division as usize).checked_sub(1)?).copied()
+}
+
+/// Эхо. Время задаётся в секундах или, если выбрано деление такта
+/// (`ParamId::DelayDivision`), по темпу MIDI clock.
 pub struct Delay {
+  // по буферу на канал, кадры идут подряд
   buffer: Vec<f32>,
   write_pos: usize,
+  frames: usize,
+  channels: usize,
   sample_rate: f32,
 
+  delay_time: Smoother,
+  feedback: SmoothedParam,
+  mix: SmoothedParam,
   synthstate: Arc<SynthState>,
 }
 
 impl Delay {
-  pub fn new(sample_rate: f32, max_delay_sec: f32, synthstate:Arc<SynthState>) -> Self {
-    let buffer_len = (sample_rate * max_delay_sec).ceil() as usize + 1;
+  pub fn new(sample_rate: f32, channels: usize, synthstate: Arc<SynthState>) -> Self {
+    let channels = channels.max(1);
+    let frames = (sample_rate * MAX_DELAY_SEC).ceil() as usize + 2;
     Self {
-      buffer: vec![0.0; buffer_len],
+      buffer: vec![0.0; frames * channels],
       write_pos: 0,
+      frames,
+      channels,
       sample_rate,
+      delay_time: Smoother::new(ParamId::DelayTime.smoothing(), sample_rate, Self::target_time(&synthstate)),
+      feedback: SmoothedParam::new(ParamId::DelayFeedback, sample_rate, &synthstate),
+      mix: SmoothedParam::new(ParamId::DelayMix, sample_rate, &synthstate),
       synthstate,
     }
   }
+
+  /// Время эха в секундах: по темпу, если выбрано деление такта
+  fn target_time(s: &SynthState) -> f32 {
+    match division_beats(s.choice(ParamId::DelayDivision)) {
+      Some(beats) => beats * 60.0 / s.tempo_bpm.load(Ordering::Relaxed).max(MIN_TEMPO_BPM),
+      None => s.value(ParamId::DelayTime),
+    }
+  }
 }
 
 
 impl AudioModule for Delay {
-  fn process(&mut self, input: &mut [f32]) {
-    let delay_time = (self.synthstate.delay_delay_time.load(std::sync::atomic::Ordering::Relaxed) as f32)/127.0*MAX_DELAY_SEC;
-    let feedback = (self.synthstate.delay_feedback.load(std::sync::atomic::Ordering::Relaxed) as f32) /127.0;
-    let mix = (self.synthstate.delay_mix.load(std::sync::atomic::Ordering::Relaxed) as f32)/127.0;
+  fn process(&mut self, output: &mut [f32]) {
+    let s = &self.synthstate;
+    let frames = output.len() / self.channels;
+    self.delay_time.set_target(Self::target_time(s));
+    self.feedback.update(s, frames);
+    self.mix.update(s, frames);
 
-    if delay_time==0.0{
-      delay_time==0.01;
+    if self.mix.is_settled() && self.mix.current() == 0.0 && self.feedback.current() == 0.0 {
+      return;
     }
 
-    let delayed_sample = delay_time * self.sample_rate;
-    let buffer_len = self.buffer.len();
+    let max_delay = (self.frames - 2) as f32;
+    for frame in output.chunks_mut(self.channels) {
+      let delay = (self.delay_time.next() * self.sample_rate).clamp(1.0, max_delay);
+      let feedback = self.feedback.next();
+      let mix = self.mix.next();
 
-    for sample in input.iter_mut() {
-      let input_sample = *sample;
+      // дробная задержка: линейная интерполяция между соседними кадрами
+      let read = (self.write_pos + self.frames) as f32 - delay;
+      let first = read.floor() as usize % self.frames;
+      let second = (first + 1) % self.frames;
+      let frac = read.fract();
 
-      // Применяем микс
-      let output = input_sample * (1.0 - mix) + delayed_sample * mix;
+      for (ch, sample) in frame.iter_mut().enumerate() {
+        let a = self.buffer[first * self.channels + ch];
+        let b = self.buffer[second * self.channels + ch];
+        let delayed = a + frac * (b - a);
+        let dry = *sample;
+        *sample = dry * (1.0 - mix) + delayed * mix;
+        self.buffer[self.write_pos * self.channels + ch] = dry + delayed * feedback;
+      }
+      self.write_pos = (self.write_pos + 1) % self.frames;
+    }
+  }
+}
 
-      // Записываем обратно в input (in-place обработка)
-      *sample = output;
+#[cfg(test)]
+mod tests {
+  use super::*;
 
-      // Обновляем буфер с учётом обратной связи
-      self.buffer[self.write_pos] = input_sample + delayed_sample * feedback;
+  #[test]
+  fn longest_division_fits_at_slowest_tempo() {
+    let state = SynthState::new();
+    state.params.set_value(ParamId::DelayDivision, 1.0);
+    state.tempo_bpm.store(120.0, Ordering::Relaxed);
+    assert_eq!(Delay::target_time(&state), 2.0);
 
-      // Продвигаем позицию записи
-      self.write_pos = (self.write_pos + 1) % buffer_len;
-    }
+    state.tempo_bpm.store(MIN_TEMPO_BPM, Ordering::Relaxed);
+    let delay = Delay::new(48_000.0, 2, state.clone());
+    let time = Delay::target_time(&state);
+    assert_eq!(time, 12.0);
+    assert!(time * 48_000.0 <= (delay.frames - 2) as f32);
+
+    // время в секундах, когда деление не выбрано
+    state.params.set_value(ParamId::DelayDivision, 0.0);
+    state.params.set_value(ParamId::DelayTime, 0.5);
+    assert!((Delay::target_time(&state) - 0.5).abs() < 1e-3);
   }
 }
-
@@ -0,0 +1,507 @@
+use crate::audiomodules::advanced_gate::GateState;
+
+// CC 1..127 -> MIN_MS..MAX_MS по экспоненте, CC 0 -> мгновенно
+const MIN_MS: f32 = 1.0;
+const MAX_MS: f32 = 10_000.0;
+
+/// Переводит положение ручки (0..1) во время стадии в миллисекундах.
+///
+/// В шагах CC: `0` -- стадия пропускается, `1..=127` раскладываются экспоненциально
+/// от 1 мс до 10 с, так что каждые 31.5 шага ручки умножают время на 10:
+/// 1 -> 1 мс, 32 -> ~9.6 мс, 64 -> 100 мс, 96 -> ~1.04 с, 127 -> 10 с.
+/// Между 0 и первым шагом время растёт линейно до 1 мс.
+pub fn norm_to_ms(value: f32) -> f32 {
+  let cc = value.clamp(0.0, 1.0) * 127.0;
+  if cc <= 1.0 {
+    return cc * MIN_MS;
+  }
+  let t = (cc - 1.0) / 126.0;
+  MIN_MS * (MAX_MS / MIN_MS).powf(t)
+}
+
+/// Обратное к `norm_to_ms`
+pub fn ms_to_norm(ms: f32) -> f32 {
+  let ms = ms.clamp(0.0, MAX_MS);
+  let cc = if ms <= MIN_MS {
+    ms / MIN_MS
+  } else {
+    1.0 + 126.0 * (ms / MIN_MS).ln() / (MAX_MS / MIN_MS).ln()
+  };
+  cc / 127.0
+}
+
+/// Параметры DAHDSR в реальных единицах. Для обычного ADSR задержка и удержание равны нулю.
+#[derive(Clone, Copy)]
+pub struct AdsrParams {
+  pub delay_ms: f32,
+  pub attack_ms: f32,
+  pub hold_ms: f32,
+  pub decay_ms: f32,
+  pub sustain: f32, // 0..1
+  pub release_ms: f32,
+  pub attack_curve: f32,
+  pub decay_curve: f32,
+  pub release_curve: f32,
+}
+
+/// Коэффициенты одной стадии: level = base + level * coef
+#[derive(Clone, Copy, Default)]
+struct StageCoeffs {
+  coef: f32,
+  base: f32,
+  instant: bool,
+}
+
+impl StageCoeffs {
+  /// Экспонента, которая за `ms` проходит путь от `from` до `to`,
+  /// целясь в точку за `to` на `ratio` размаха.
+  fn new(ms: f32, from: f32, to: f32, ratio: f32, sample_rate: f32) -> Self {
+    let samples = ms * 0.001 * sample_rate;
+    if samples < 1.0 {
+      return Self { coef: 0.0, base: to, instant: true };
+    }
+    let target = to + (to - from) * ratio;
+    let coef = (-((1.0 + ratio) / ratio).ln() / samples).exp();
+    Self {
+      coef,
+      base: target * (1.0 - coef),
+      instant: false,
+    }
+  }
+
+  #[inline]
+  fn step(&self, level: f32) -> f32 {
+    if self.instant {
+      self.base
+    } else {
+      self.base + level * self.coef
+    }
+  }
+}
+
+/// DAHDSR-генератор с экспоненциальными стадиями регулируемой формы
+pub struct Envelope {
+  stage: GateState,
+  level: f32,
+  sample_rate: f32,
+  stage_samples: f32, // сколько сэмплов прошло в стадиях Delay/Hold
+
+  delay_samples: f32,
+  hold_samples: f32,
+  sustain: f32,
+  attack: StageCoeffs,
+  decay: StageCoeffs,
+  release: StageCoeffs,
+}
+
+impl Envelope {
+  pub fn new(sample_rate: f32) -> Self {
+    Self {
+      stage: GateState::Idle,
+      level: 0.0,
+      sample_rate,
+      stage_samples: 0.0,
+      delay_samples: 0.0,
+      hold_samples: 0.0,
+      sustain: 0.0,
+      attack: StageCoeffs::default(),
+      decay: StageCoeffs::default(),
+      release: StageCoeffs::default(),
+    }
+  }
+
+  /// Пересчитывает коэффициенты. Достаточно вызывать раз в блок.
+  pub fn set_params(&mut self, params: &AdsrParams) {
+    let sustain = params.sustain.clamp(0.0, 1.0);
+    self.sustain = sustain;
+    self.delay_samples = (params.delay_ms * 0.001 * self.sample_rate).round();
+    self.hold_samples = (params.hold_ms * 0.001 * self.sample_rate).round();
+    self.attack = StageCoeffs::new(params.attack_ms, 0.0, 1.0, params.attack_curve, self.sample_rate);
+    self.decay = StageCoeffs::new(params.decay_ms, 1.0, sustain, params.decay_curve, self.sample_rate);
+    self.release = StageCoeffs::new(params.release_ms, 1.0, 0.0, params.release_curve, self.sample_rate);
+  }
+
+  pub fn gate_on(&mut self) {
+    self.stage = GateState::Delay;
+    self.stage_samples = 0.0;
+  }
+
+  pub fn gate_off(&mut self) {
+    if !matches!(self.stage, GateState::Idle) {
+      self.stage = GateState::Release;
+    }
+  }
+
+  pub fn is_releasing(&self) -> bool {
+    matches!(self.stage, GateState::Release | GateState::Idle)
+  }
+
+  pub fn next(&mut self) -> f32 {
+    match self.stage {
+      GateState::Idle => {
+        self.level = 0.0;
+      },
+      GateState::Delay => {
+        if self.stage_samples >= self.delay_samples {
+          self.stage = GateState::Attack;
+          return self.next();
+        }
+        self.stage_samples += 1.0;
+      },
+      GateState::Attack => {
+        self.level = self.attack.step(self.level);
+        if self.level >= 1.0 {
+          self.level = 1.0;
+          self.stage = GateState::Hold;
+          self.stage_samples = 0.0;
+        }
+      },
+      GateState::Hold => {
+        if self.stage_samples >= self.hold_samples {
+          self.stage = GateState::Decay;
+          return self.next();
+        }
+        self.stage_samples += 1.0;
+      },
+      GateState::Decay => {
+        self.level = self.decay.step(self.level);
+        if self.level <= self.sustain {
+          self.level = self.sustain;
+          self.stage = GateState::Sustain;
+        }
+      },
+      GateState::Sustain => {
+        self.level = self.sustain;
+      },
+      GateState::Release => {
+        self.level = self.release.step(self.level);
+        if self.level <= 0.0 {
+          self.level = 0.0;
+          self.stage = GateState::Idle;
+        }
+      },
+    }
+    self.level
+  }
+}
+
+/// Точка ломаной огибающей: за `time_ms` уровень доходит до `level`.
+/// `curve` 0 -- прямая, >0 -- быстрый старт и плавный подход, <0 -- наоборот.
+#[derive(Clone, Copy)]
+pub struct Breakpoint {
+  pub time_ms: f32,
+  pub level: f32,
+  pub curve: f32,
+}
+
+/// Форма ломаной огибающей. Пока нота держится, сегменты
+/// `loop_start..=loop_end` повторяются; после отпускания огибающая
+/// продолжает с сегмента после `loop_end`.
+#[derive(Clone)]
+pub struct BreakpointShape {
+  pub points: Vec<Breakpoint>,
+  pub loop_range: Option<(usize, usize)>,
+}
+
+/// Больше точек в форме не бывает: формы копируются в заранее выделенные буферы,
+/// чтобы аудиопоток при смене пресета не выделял память
+pub const MAX_BREAKPOINTS: usize = 64;
+
+impl Default for BreakpointShape {
+  /// Простая AR-огибающая без петли
+  fn default() -> Self {
+    Self {
+      points: vec![
+        Breakpoint { time_ms: 10.0, level: 1.0, curve: 0.0 },
+        Breakpoint { time_ms: 500.0, level: 0.0, curve: 3.0 },
+      ],
+      loop_range: None,
+    }
+  }
+}
+
+impl BreakpointShape {
+  /// Форма по умолчанию с местом под `MAX_BREAKPOINTS` точек
+  pub fn preallocated() -> Self {
+    let mut shape = Self::default();
+    shape.points.reserve(MAX_BREAKPOINTS - shape.points.len());
+    shape
+  }
+
+  /// Копирует форму на место этой, не выделяя памяти, если точки помещаются
+  pub fn copy_from(&mut self, other: &BreakpointShape) {
+    self.points.clear();
+    self.points.extend_from_slice(&other.points);
+    self.loop_range = other.loop_range;
+  }
+}
+
+#[inline]
+fn shape_curve(t: f32, curve: f32) -> f32 {
+  if curve.abs() < 1e-3 {
+    t
+  } else {
+    (1.0 - (-curve * t).exp()) / (1.0 - (-curve).exp())
+  }
+}
+
+/// Огибающая по произвольным точкам с петлёй
+pub struct BreakpointEnvelope {
+  sample_rate: f32,
+  level: f32,
+  gate: bool,
+  segment: Option<usize>, // None -- огибающая стоит
+  segment_start: f32,
+  segment_pos: f32, // сэмплов с начала сегмента
+}
+
+impl BreakpointEnvelope {
+  pub fn new(sample_rate: f32) -> Self {
+    Self {
+      sample_rate,
+      level: 0.0,
+      gate: false,
+      segment: None,
+      segment_start: 0.0,
+      segment_pos: 0.0,
+    }
+  }
+
+  fn enter_segment(&mut self, index: usize) {
+    self.segment = Some(index);
+    self.segment_start = self.level;
+    self.segment_pos = 0.0;
+  }
+
+  pub fn gate_on(&mut self) {
+    self.gate = true;
+    self.enter_segment(0);
+  }
+
+  pub fn gate_off(&mut self, shape: &BreakpointShape) {
+    self.gate = false;
+    if let (Some(segment), Some((_, loop_end))) = (self.segment, shape.loop_range) {
+      if segment <= loop_end {
+        self.enter_segment(loop_end + 1);
+      }
+    }
+  }
+
+  pub fn next(&mut self, shape: &BreakpointShape) -> f32 {
+    let Some(index) = self.segment else {
+      return self.level;
+    };
+    let Some(point) = shape.points.get(index) else {
+      self.segment = None;
+      return self.level;
+    };
+
+    let length = (point.time_ms * 0.001 * self.sample_rate).round();
+    self.segment_pos += 1.0;
+    let t = if length < 1.0 { 1.0 } else { (self.segment_pos / length).min(1.0) };
+    if t >= 1.0 {
+      // ровно в точку, иначе после релиза остаётся хвост от округления
+      self.level = point.level;
+      match shape.loop_range {
+        Some((loop_start, loop_end)) if self.gate && index == loop_end && loop_start <= loop_end => {
+          self.enter_segment(loop_start)
+        },
+        _ => self.enter_segment(index + 1),
+      }
+    } else {
+      self.level = self.segment_start + (point.level - self.segment_start) * shape_curve(t, point.curve);
+    }
+    self.level
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  // 1 сэмпл = 1 мс, чтобы длительности стадий читались прямо в сэмплах
+  const SR: f32 = 1000.0;
+
+  fn params() -> AdsrParams {
+    AdsrParams {
+      delay_ms: 5.0,
+      attack_ms: 10.0,
+      hold_ms: 5.0,
+      decay_ms: 20.0,
+      sustain: 0.5,
+      release_ms: 30.0,
+      attack_curve: 0.3,
+      decay_curve: 0.3,
+      release_curve: 0.3,
+    }
+  }
+
+  /// Крутит огибающую, пока не начнётся стадия `until`, и возвращает число сэмплов
+  fn run_until(env: &mut Envelope, until: GateState, limit: usize) -> usize {
+    let until = std::mem::discriminant(&until);
+    for n in 1..=limit {
+      env.next();
+      if std::mem::discriminant(&env.stage) == until {
+        return n;
+      }
+    }
+    panic!("stage did not finish in {} samples", limit);
+  }
+
+  #[test]
+  fn time_curve_matches_documented_points() {
+    assert_eq!(norm_to_ms(0.0), 0.0);
+    assert!((norm_to_ms(1.0 / 127.0) - 1.0).abs() < 1e-3);
+    assert!((norm_to_ms(64.0 / 127.0) - 100.0).abs() < 0.5);
+    assert!((norm_to_ms(1.0) - 10_000.0).abs() < 1.0);
+    assert!((norm_to_ms(0.5 / 127.0) - 0.5).abs() < 1e-3);
+  }
+
+  #[test]
+  fn ms_to_norm_inverts_norm_to_ms() {
+    for ms in [0.0, 0.25, 1.0, 9.6, 100.0, 1040.0, 5000.0, 10_000.0] {
+      let back = norm_to_ms(ms_to_norm(ms));
+      assert!((back - ms).abs() <= ms * 1e-3 + 1e-4, "{} ms came back as {}", ms, back);
+    }
+    for cc in 0..=127 {
+      let value = cc as f32 / 127.0;
+      assert!((ms_to_norm(norm_to_ms(value)) - value).abs() < 1e-4, "cc {}", cc);
+    }
+    assert_eq!(ms_to_norm(20_000.0), 1.0);
+  }
+
+  #[test]
+  fn dahdsr_stages_take_their_configured_time() {
+    let mut env = Envelope::new(SR);
+    env.set_params(&params());
+    assert_eq!(env.next(), 0.0);
+
+    env.gate_on();
+    assert!(!env.is_releasing());
+    for _ in 0..5 {
+      assert_eq!(env.next(), 0.0);
+    }
+
+    // первый сэмпл после задержки уже идёт в атаку
+    let attack = run_until(&mut env, GateState::Hold, 100);
+    assert!((10..=11).contains(&attack), "attack took {}", attack);
+    assert_eq!(env.level, 1.0);
+
+    for _ in 0..5 {
+      assert_eq!(env.next(), 1.0);
+    }
+    assert!(matches!(env.stage, GateState::Hold));
+
+    let decay = run_until(&mut env, GateState::Sustain, 100);
+    assert!((20..=21).contains(&decay), "decay took {}", decay);
+    assert!(matches!(env.stage, GateState::Sustain));
+    for _ in 0..50 {
+      assert_eq!(env.next(), 0.5);
+    }
+
+    env.gate_off();
+    assert!(env.is_releasing());
+    // релиз из сустейна короче полного: 0.5 -> 0 по той же экспоненте
+    let release = run_until(&mut env, GateState::Idle, 100);
+    assert!(release < 30, "release took {}", release);
+    assert!(matches!(env.stage, GateState::Idle));
+    assert_eq!(env.next(), 0.0);
+  }
+
+  #[test]
+  fn zero_times_skip_stages() {
+    let mut env = Envelope::new(SR);
+    env.set_params(&AdsrParams { delay_ms: 0.0, attack_ms: 0.0, hold_ms: 0.0, ..params() });
+    env.gate_on();
+    assert_eq!(env.next(), 1.0);
+    assert!(matches!(env.stage, GateState::Hold));
+    assert!(env.next() < 1.0);
+    assert!(matches!(env.stage, GateState::Decay));
+  }
+
+  #[test]
+  fn gate_off_during_attack_releases_from_current_level() {
+    let mut env = Envelope::new(SR);
+    env.set_params(&params());
+    env.gate_on();
+    for _ in 0..10 {
+      env.next();
+    }
+    let level = env.level;
+    assert!(level > 0.0 && level < 1.0);
+    env.gate_off();
+    assert!(env.next() < level);
+    assert!(matches!(env.stage, GateState::Release));
+  }
+
+  fn looped_shape() -> BreakpointShape {
+    BreakpointShape {
+      points: vec![
+        Breakpoint { time_ms: 10.0, level: 1.0, curve: 0.0 },
+        Breakpoint { time_ms: 10.0, level: 0.5, curve: 0.0 },
+        Breakpoint { time_ms: 10.0, level: 1.0, curve: 2.0 },
+        Breakpoint { time_ms: 20.0, level: 0.0, curve: 0.0 },
+      ],
+      loop_range: Some((1, 2)),
+    }
+  }
+
+  #[test]
+  fn breakpoint_without_loop_runs_once_and_stops() {
+    let shape = BreakpointShape::default();
+    let mut env = BreakpointEnvelope::new(SR);
+    env.gate_on();
+    let levels: Vec<f32> = (0..600).map(|_| env.next(&shape)).collect();
+    assert_eq!(levels[9], 1.0);
+    assert!(levels[5] > 0.4 && levels[5] < 0.7);
+    assert_eq!(levels[509], 0.0);
+    assert!(levels[510..].iter().all(|&l| l == 0.0));
+    assert!(env.segment.is_none());
+  }
+
+  #[test]
+  fn breakpoint_loop_repeats_while_gate_is_on() {
+    let shape = looped_shape();
+    let mut env = BreakpointEnvelope::new(SR);
+    env.gate_on();
+    let levels: Vec<f32> = (0..210).map(|_| env.next(&shape)).collect();
+    assert_eq!(levels[9], 1.0);
+    // петля 0.5 -> 1.0 длиной 20 сэмплов, без возврата к нулю
+    for lap in 0..10 {
+      let start = 10 + lap * 20;
+      assert_eq!(levels[start + 9], 0.5, "lap {}", lap);
+      assert_eq!(levels[start + 19], 1.0, "lap {}", lap);
+    }
+    assert!(levels[10..].iter().all(|&l| (0.5..=1.0).contains(&l)));
+  }
+
+  #[test]
+  fn breakpoint_release_leaves_loop_from_current_level() {
+    let shape = looped_shape();
+    let mut env = BreakpointEnvelope::new(SR);
+    env.gate_on();
+    for _ in 0..65 {
+      env.next(&shape);
+    }
+    let level = env.level;
+    env.gate_off(&shape);
+    assert!((env.next(&shape) - level * 19.0 / 20.0).abs() < 1e-5);
+    let rest: Vec<f32> = (0..30).map(|_| env.next(&shape)).collect();
+    assert_eq!(rest[18], 0.0);
+    assert!(rest.iter().skip(18).all(|&l| l == 0.0));
+  }
+
+  #[test]
+  fn breakpoint_release_before_loop_skips_it() {
+    let shape = looped_shape();
+    let mut env = BreakpointEnvelope::new(SR);
+    env.gate_on();
+    for _ in 0..5 {
+      env.next(&shape);
+    }
+    env.gate_off(&shape);
+    assert_eq!(env.segment, Some(3));
+    let rest: Vec<f32> = (0..20).map(|_| env.next(&shape)).collect();
+    assert_eq!(rest[19], 0.0);
+  }
+}
@@ -0,0 +1,90 @@
+use crate::audiomodules::AudioModule;
+use crate::midi_events::{MidiEvent, NotePlayer, Timeline};
+use crate::presets::PatchBank;
+use crate::synth_state::SynthState;
+use rtrb::Consumer;
+use std::sync::{Arc, Mutex};
+
+/// Применяет MIDI-события части с точностью до сэмпла. Блок режется на куски
+/// по времени событий: цепочка модулей считает кусок до события, потом событие
+/// применяется, потом следующий кусок.
+///
+/// События, пришедшие за время прошлого блока, звучат в этом с теми же промежутками,
+/// то есть задержка ровно в один блок и не зависит от того, когда пришло событие.
+pub struct EventScheduler {
+  modules: Vec<Arc<Mutex<dyn AudioModule>>>,
+  // модули должны быть Sync, а очередь нет; `get_mut` достаёт её без блокировки
+  events: Mutex<Consumer<MidiEvent>>,
+  player: NotePlayer,
+  timeline: Timeline,
+  sample_rate: f32,
+  channels: usize,
+  synthstate: Arc<SynthState>,
+}
+
+impl EventScheduler {
+  pub fn new(
+    modules: Vec<Arc<Mutex<dyn AudioModule>>>,
+    events: Consumer<MidiEvent>,
+    timeline: Timeline,
+    sample_rate: f32,
+    channels: usize,
+    patches: Arc<PatchBank>,
+    synthstate: Arc<SynthState>,
+  ) -> Self {
+    Self {
+      modules,
+      events: Mutex::new(events),
+      player: NotePlayer::new(patches),
+      timeline,
+      sample_rate,
+      channels: channels.max(1),
+      synthstate,
+    }
+  }
+
+  fn peek_event(&mut self) -> Option<MidiEvent> {
+    self.events.get_mut().ok()?.peek().ok().copied()
+  }
+
+  fn pop_event(&mut self) {
+    if let Ok(events) = self.events.get_mut() {
+      let _ = events.pop();
+    }
+  }
+
+  fn run_modules(&self, output: &mut [f32]) {
+    if output.is_empty() {
+      return;
+    }
+    for module in &self.modules {
+      if let Ok(mut m) = module.lock() {
+        m.process(output);
+      }
+    }
+  }
+}
+
+impl AudioModule for EventScheduler {
+  fn process(&mut self, output: &mut [f32]) {
+    let frames = output.len() / self.channels;
+    let block_us = (frames as f64 * 1_000_000.0 / self.sample_rate as f64) as u64;
+    // этот блок играет то, что пришло за время предыдущего
+    let start_us = self.timeline.now_us().saturating_sub(block_us);
+
+    self.player.flush_preset(&self.synthstate);
+    let mut done = 0;
+    while let Some(event) = self.peek_event() {
+      let offset = (event.time_us.saturating_sub(start_us) as f64 * self.sample_rate as f64 / 1_000_000.0) as usize;
+      if offset >= frames {
+        break;
+      }
+      let offset = offset.max(done);
+      self.run_modules(&mut output[done * self.channels..offset * self.channels]);
+      done = offset;
+      self.pop_event();
+      self.player.apply(&self.synthstate, &event);
+    }
+    self.run_modules(&mut output[done * self.channels..]);
+  }
+}
@@ -1,8 +1,8 @@
 use crate::{audiomodules::glide, synth_state::SynthState};
-use std::sync::{atomic::Ordering, Arc};
+use crate::params::ParamId;
+use std::sync::Arc;
 
 
-const MAX: f32 = 0.8;
 pub struct Glide {
   current_freq: f32,
   target_freq: f32,
@@ -26,11 +26,34 @@ impl Glide {
 
 
   pub fn next(&mut self) -> f32 {
-    let glide_time = self.synthstate.glide_time.load(Ordering::Relaxed) as f32 / 270.0* MAX;
-    if self.current_freq != self.target_freq {
-      let step = (self.target_freq - self.current_freq) / (glide_time * self.sample_rate);
+    let glide_samples = self.synthstate.value(ParamId::GlideTime) * self.sample_rate;
+    if glide_samples <= 1.0 {
+      // глайд короче сэмпла (или выключен): сразу на ноту, без деления на ноль
+      self.current_freq = self.target_freq;
+    } else if self.current_freq != self.target_freq {
+      let step = (self.target_freq - self.current_freq) / glide_samples;
       self.current_freq += step;
     }
     self.current_freq
   }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn zero_glide_time_jumps_to_target() {
+    let state = SynthState::new();
+    state.params.set_value(ParamId::GlideTime, 0.0);
+    let mut glide = Glide::new(220.0, state.clone(), 48_000.0);
+    glide.set_target(440.0);
+    assert_eq!(glide.next(), 440.0);
+    assert_eq!(glide.next(), 440.0);
+
+    state.params.set_value(ParamId::GlideTime, 0.1);
+    glide.set_target(220.0);
+    let first = glide.next();
+    assert!(first > 220.0 && first < 440.0, "{}", first);
+  }
+}
@@ -0,0 +1,313 @@
+use crate::audiomodules::smoother::SmoothedParam;
+use crate::audiomodules::AudioModule;
+use crate::params::{LFO_DEPTH, LFO_DIVISION, LFO_FADE, LFO_PHASE, LFO_RATE, LFO_RETRIGGER, LFO_SHAPE};
+use crate::synth_state::SynthState;
+use std::f32::consts::TAU;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+/// Формы LFO, значения `ParamId::Lfo1Shape`/`Lfo2Shape`
+pub const LFO_SINE: u8 = 0;
+pub const LFO_TRIANGLE: u8 = 1;
+pub const LFO_SAW: u8 = 2;
+pub const LFO_SQUARE: u8 = 3;
+pub const LFO_SAMPLE_HOLD: u8 = 4;
+pub const LFO_SMOOTH_RANDOM: u8 = 5;
+
+/// Длительность одного периода в долях четверти для `ParamId::Lfo1Division` 1..=13
+/// (0 -- свободная частота в Гц)
+const DIVISIONS: [f32; 13] = [
+  16.0,       // 4 такта
+  8.0,        // 2 такта
+  4.0,        // 1 такт
+  2.0,        // 1/2
+  1.0,        // 1/4
+  2.0 / 3.0,  // 1/4 триоль
+  0.75,       // 1/8 с точкой
+  0.5,        // 1/8
+  1.0 / 3.0,  // 1/8 триоль
+  0.375,      // 1/16 с точкой
+  0.25,       // 1/16
+  1.0 / 6.0,  // 1/16 триоль
+  0.125,      // 1/32
+];
+
+/// Длина периода в четвертях для деления такта, `None` -- свободная частота
+pub fn division_beats(division: u8) -> Option<f32> {
+  DIVISIONS.get((division as usize).checked_sub(1)?).copied()
+}
+
+/// Частота периода для деления такта при заданном темпе
+pub fn division_to_rate(division: u8, bpm: f32) -> Option<f32> {
+  Some(bpm / 60.0 / division_beats(division)?)
+}
+
+/// Генератор LFO без привязки к `SynthState`
+pub struct Lfo {
+  phase: f32, // 0..1
+  rng: u32,
+  held: f32,      // текущее случайное значение
+  prev_held: f32, // предыдущее, для плавного шума
+}
+
+impl Lfo {
+  pub fn new(seed: u32) -> Self {
+    let mut lfo = Self {
+      phase: 0.0,
+      rng: seed.max(1),
+      held: 0.0,
+      prev_held: 0.0,
+    };
+    lfo.held = lfo.random();
+    lfo.prev_held = lfo.random();
+    lfo
+  }
+
+  /// xorshift32, -1..1
+  fn random(&mut self) -> f32 {
+    self.rng ^= self.rng << 13;
+    self.rng ^= self.rng >> 17;
+    self.rng ^= self.rng << 5;
+    self.rng as f32 / u32::MAX as f32 * 2.0 - 1.0
+  }
+
+  pub fn reset(&mut self, phase: f32) {
+    self.phase = phase.rem_euclid(1.0);
+  }
+
+  /// Сдвигает фазу на `cycles` периодов, на каждом новом периоде берёт новое случайное значение.
+  /// Если за блок прошло несколько периодов, значений берётся столько же, чтобы
+  /// последовательность не зависела от размера блока.
+  pub fn advance(&mut self, cycles: f32) {
+    self.phase += cycles;
+    let wraps = self.phase.floor();
+    if wraps >= 1.0 {
+      self.phase -= wraps;
+      for _ in 0..wraps as u32 {
+        self.prev_held = self.held;
+        self.held = self.random();
+      }
+    }
+  }
+
+  /// Текущее значение -1..1
+  pub fn value(&self, shape: u8) -> f32 {
+    let p = self.phase;
+    match shape {
+      LFO_TRIANGLE => 1.0 - 4.0 * (p - 0.5).abs(),
+      LFO_SAW => 2.0 * p - 1.0,
+      LFO_SQUARE => {
+        if p < 0.5 {
+          1.0
+        } else {
+          -1.0
+        }
+      },
+      LFO_SAMPLE_HOLD => self.held,
+      LFO_SMOOTH_RANDOM => {
+        // косинусная интерполяция между соседними случайными значениями
+        let t = 0.5 - 0.5 * (p * std::f32::consts::PI).cos();
+        self.prev_held + (self.held - self.prev_held) * t
+      },
+      _ => (p * TAU).sin(),
+    }
+  }
+}
+
+struct LfoVoice {
+  lfo: Lfo,
+  fade_pos_ms: f32,
+  depth: SmoothedParam,
+}
+
+/// Набор LFO. Звук не трогает: раз в блок продвигает генераторы
+/// и публикует значения в `SynthState::lfo_values`.
+pub struct LfoModule {
+  lfos: Vec<LfoVoice>,
+  sample_rate: f32,
+  channels: usize,
+  last_note_on: u32,
+  last_transport: u32,
+  synthstate: Arc<SynthState>,
+}
+
+impl LfoModule {
+  pub fn new(sample_rate: f32, channels: usize, synthstate: Arc<SynthState>) -> Self {
+    Self {
+      lfos: (0..synthstate.lfo_values.len())
+        .map(|i| LfoVoice {
+          lfo: Lfo::new(0x9E37_79B9 ^ (i as u32 + 1)),
+          fade_pos_ms: f32::MAX,
+          depth: SmoothedParam::new(LFO_DEPTH[i], sample_rate, &synthstate),
+        })
+        .collect(),
+      sample_rate,
+      channels: channels.max(1),
+      last_note_on: 0,
+      last_transport: synthstate.transport_counter.load(Ordering::Relaxed),
+      synthstate,
+    }
+  }
+
+  fn rate(s: &SynthState, i: usize) -> f32 {
+    let division = s.choice(LFO_DIVISION[i]);
+    let bpm = s.tempo_bpm.load(Ordering::Relaxed);
+    division_to_rate(division, bpm).unwrap_or_else(|| s.value(LFO_RATE[i]))
+  }
+}
+
+impl AudioModule for LfoModule {
+  fn process(&mut self, output: &mut [f32]) {
+    let frames = output.len() / self.channels;
+    let block_ms = frames as f32 * 1000.0 / self.sample_rate;
+
+    let note_on = self.synthstate.note_on_counter.load(Ordering::Relaxed);
+    let new_note = note_on != self.last_note_on;
+    self.last_note_on = note_on;
+
+    // DAW запустила или перемотала песню: синхронные LFO встают в фазу по позиции
+    let transport = self.synthstate.transport_counter.load(Ordering::Relaxed);
+    let relocated = transport != self.last_transport;
+    self.last_transport = transport;
+
+    let s = &self.synthstate;
+    for (i, voice) in self.lfos.iter_mut().enumerate() {
+      let rate = Self::rate(s, i);
+      let shape = s.choice(LFO_SHAPE[i]);
+      let fade_ms = s.value(LFO_FADE[i]);
+      voice.depth.update(s, frames);
+      let depth = voice.depth.skip(frames);
+
+      if new_note {
+        voice.fade_pos_ms = 0.0;
+        if s.flag(LFO_RETRIGGER[i]) {
+          voice.lfo.reset(s.value(LFO_PHASE[i]) / 360.0);
+        }
+      }
+
+      if relocated {
+        if let Some(beats) = division_beats(s.choice(LFO_DIVISION[i])) {
+          voice.lfo.reset(s.song_position_beats() / beats + s.value(LFO_PHASE[i]) / 360.0);
+        }
+      }
+
+      voice.lfo.advance(rate * frames as f32 / self.sample_rate);
+      voice.fade_pos_ms += block_ms;
+      let fade = if fade_ms > 0.0 { (voice.fade_pos_ms / fade_ms).min(1.0) } else { 1.0 };
+
+      s.lfo_values[i].store(voice.lfo.value(shape) * depth * fade, Ordering::Relaxed);
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::params::ParamId;
+
+  fn at_phase(phase: f32) -> Lfo {
+    let mut lfo = Lfo::new(1);
+    lfo.reset(phase);
+    lfo
+  }
+
+  fn close(a: f32, b: f32) -> bool {
+    (a - b).abs() < 1e-4
+  }
+
+  #[test]
+  fn periodic_shapes() {
+    for (phase, sine, triangle, saw, square) in [
+      (0.0, 0.0, -1.0, -1.0, 1.0),
+      (0.25, 1.0, 0.0, -0.5, 1.0),
+      (0.5, 0.0, 1.0, 0.0, -1.0),
+      (0.75, -1.0, 0.0, 0.5, -1.0),
+    ] {
+      let lfo = at_phase(phase);
+      assert!(close(lfo.value(LFO_SINE), sine), "sine at {}", phase);
+      assert!(close(lfo.value(LFO_TRIANGLE), triangle), "triangle at {}", phase);
+      assert!(close(lfo.value(LFO_SAW), saw), "saw at {}", phase);
+      assert_eq!(lfo.value(LFO_SQUARE), square, "square at {}", phase);
+    }
+  }
+
+  #[test]
+  fn random_shapes_change_only_on_wrap() {
+    let mut lfo = Lfo::new(7);
+    let held = lfo.value(LFO_SAMPLE_HOLD);
+    lfo.advance(0.6);
+    assert_eq!(lfo.value(LFO_SAMPLE_HOLD), held);
+    lfo.advance(0.6);
+    let next = lfo.value(LFO_SAMPLE_HOLD);
+    assert_ne!(next, held);
+    assert!((-1.0..=1.0).contains(&next));
+
+    // плавный шум начинает период с прошлого значения и приходит к новому
+    lfo.reset(0.0);
+    assert!(close(lfo.value(LFO_SMOOTH_RANDOM), held));
+    lfo.reset(0.999_99);
+    assert!((lfo.value(LFO_SMOOTH_RANDOM) - next).abs() < 1e-3);
+  }
+
+  #[test]
+  fn several_wraps_in_one_block_draw_a_value_each() {
+    let mut stepped = Lfo::new(42);
+    for _ in 0..9 {
+      stepped.advance(0.3);
+    }
+    let mut jumped = Lfo::new(42);
+    jumped.advance(2.7);
+
+    assert!(close(stepped.phase, jumped.phase));
+    assert_eq!(stepped.held, jumped.held);
+    assert_eq!(stepped.prev_held, jumped.prev_held);
+  }
+
+  #[test]
+  fn tempo_divisions() {
+    assert_eq!(division_beats(0), None);
+    assert_eq!(division_beats(14), None);
+    assert_eq!(division_beats(3), Some(4.0));
+    assert_eq!(division_to_rate(5, 120.0), Some(2.0));
+    assert_eq!(division_to_rate(1, 120.0), Some(0.125));
+    assert!(close(division_to_rate(9, 120.0).unwrap(), 6.0));
+    assert_eq!(division_to_rate(0, 120.0), None);
+  }
+
+  #[test]
+  fn synced_rate_follows_tempo() {
+    let state = SynthState::new();
+    state.params.set_value(ParamId::Lfo1Rate, 1.0);
+    state.params.set_value(ParamId::Lfo1Division, 0.0);
+    assert!((LfoModule::rate(&state, 0) - 1.0).abs() < 1e-3);
+
+    state.params.set_value(ParamId::Lfo1Division, 5.0);
+    state.tempo_bpm.store(90.0, Ordering::Relaxed);
+    assert!(close(LfoModule::rate(&state, 0), 1.5));
+    state.tempo_bpm.store(150.0, Ordering::Relaxed);
+    assert!(close(LfoModule::rate(&state, 0), 2.5));
+  }
+
+  #[test]
+  fn note_on_retriggers_only_when_enabled() {
+    let state = SynthState::new();
+    for i in 0..2 {
+      state.params.set_value(LFO_RATE[i], 0.05);
+      state.params.set_value(LFO_PHASE[i], 90.0);
+    }
+    state.params.set_value(ParamId::Lfo1Retrigger, 1.0);
+    let mut module = LfoModule::new(48_000.0, 1, state.clone());
+    let mut block = [0.0; 64];
+
+    for voice in module.lfos.iter_mut() {
+      voice.lfo.reset(0.5);
+    }
+    state.note_on_counter.fetch_add(1, Ordering::Relaxed);
+    module.process(&mut block);
+
+    // LFO 1 встал на 90 градусов, LFO 2 продолжил с середины периода
+    assert!(close(module.lfos[0].lfo.phase, 0.25), "{}", module.lfos[0].lfo.phase);
+    assert!(close(state.lfo_values[0].load(Ordering::Relaxed), 1.0));
+    assert!(module.lfos[1].lfo.phase > 0.5 && module.lfos[1].lfo.phase < 0.501);
+  }
+}
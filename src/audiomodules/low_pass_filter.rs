@@ -1,5 +1,8 @@
+use crate::audiomodules::smoother::{SmoothedParam, Smoother};
 use crate::audiomodules::AudioModule;
+use crate::params::ParamId;
 use crate::synth_state::SynthState;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
 
 pub struct LowPassFilter {
@@ -10,8 +13,14 @@ pub struct LowPassFilter {
     b0: f32, b1: f32, b2: f32,
     a1: f32, a2: f32,
 
-    // DF2T state
-    z1: f32, z2: f32,
+    // DF2T state, (z1, z2) на каждый канал
+    state: Vec<(f32, f32)>,
+
+    // срез с модуляцией, множитель среза от силы нажатия и резонанс, сглаженные
+    cutoff: SmoothedParam,
+    velocity: Smoother,
+    res_factor: SmoothedParam,
+    last_preset: u32,
 
     // cache to avoid recomputing every sample
     last_cutoff: f32,
@@ -19,12 +28,16 @@ pub struct LowPassFilter {
 }
 
 impl LowPassFilter {
-    pub fn new(synthstate: Arc<SynthState>, sample_rate: f32) -> Self {
+    pub fn new(synthstate: Arc<SynthState>, sample_rate: f32, channels: usize) -> Self {
         let mut s = Self {
+            cutoff: SmoothedParam::new(ParamId::LpfCutoff, sample_rate, &synthstate),
+            velocity: Smoother::new(ParamId::LpfCutoff.smoothing(), sample_rate, Self::velocity_target(&synthstate)),
+            res_factor: SmoothedParam::new(ParamId::LpfResonance, sample_rate, &synthstate),
+            last_preset: synthstate.preset_counter.load(Ordering::Relaxed),
             synthstate,
             sample_rate,
             b0: 0.0, b1: 0.0, b2: 0.0, a1: 0.0, a2: 0.0,
-            z1: 0.0, z2: 0.0,
+            state: vec![(0.0, 0.0); channels.max(1)],
             last_cutoff: f32::NAN,
             last_res_factor: f32::NAN,
         };
@@ -32,13 +45,25 @@ impl LowPassFilter {
         s
     }
 
+    /// Множитель положения ручки среза от силы нажатия последней ноты
+    fn velocity_target(s: &SynthState) -> f32 {
+        let velocity = s.last_velocity.load(Ordering::Relaxed);
+        s.velocity_scale(ParamId::VelocityToCutoff, velocity)
+    }
+
+    /// Текущая сглаженная частота среза
+    #[inline]
+    fn cutoff(&self) -> f32 {
+        ParamId::LpfCutoff.info().denormalize(self.cutoff.normalized() * self.velocity.current())
+    }
+
     #[inline]
     fn update_coeffs(&mut self) {
 
-        let cutoff = self.synthstate.lpf_cutoff.load(std::sync::atomic::Ordering::Relaxed) as f32 / 127.0 * (self.sample_rate / 2.0);
-        let res_factor = self.synthstate.lpf_res_factor.load(std::sync::atomic::Ordering::Relaxed) as f32 / 127.0;
+        let cutoff = self.cutoff();
+        let res_factor = self.res_factor.current();
         let fs = self.sample_rate.max(1.0);
-        let q  = res_factor;
+        let q  = res_factor.max(0.05); // при q = 0 коэффициенты улетают в бесконечность
 
         // keep cutoff strictly inside (0, fs/2)
         let f0 = cutoff.clamp(1e-3, 0.499 * fs);
@@ -67,20 +92,26 @@ impl LowPassFilter {
         self.last_res_factor = res_factor;
     }
 
-    // Same signature you’re already using in main.
+    /// Сдвигает сглаживание на один кадр, пересчитывает коэффициенты, если параметры изменились
     #[inline]
-    fn filter(&mut self, x: f32) -> f32 {
-        // Recompute if parameters changed
-        let cutoff = self.synthstate.lpf_cutoff.load(std::sync::atomic::Ordering::Relaxed) as f32 / 127.0 * (self.sample_rate / 2.0);
-        let res_factor = self.synthstate.lpf_res_factor.load(std::sync::atomic::Ordering::Relaxed) as f32 / 127.0;
+    fn next_frame(&mut self) {
+        let cutoff = if self.cutoff.is_settled() && self.velocity.is_settled() { self.last_cutoff } else {
+            self.cutoff.next();
+            self.velocity.next();
+            self.cutoff()
+        };
+        let res_factor = self.res_factor.next();
         if cutoff != self.last_cutoff || res_factor != self.last_res_factor {
             self.update_coeffs();
         }
+    }
 
+    #[inline]
+    fn filter(&mut self, channel: usize, x: f32) -> f32 {
         // Direct Form II Transposed sample processing
-        let y = self.b0 * x + self.z1;
-        self.z1 = self.b1 * x + self.z2 - self.a1 * y;
-        self.z2 = self.b2 * x - self.a2 * y;
+        let (z1, z2) = self.state[channel];
+        let y = self.b0 * x + z1;
+        self.state[channel] = (self.b1 * x + z2 - self.a1 * y, self.b2 * x - self.a2 * y);
         y
     }
 }
@@ -88,8 +119,22 @@ impl LowPassFilter {
 impl AudioModule for LowPassFilter {
     fn process(&mut self, output: &mut [f32]) {
         // In-place: assumes `output` already contains the oscillator signal.
-        for s in output.iter_mut() {
-            *s = self.filter(*s);
+        let channels = self.state.len();
+        let frames = output.len() / channels;
+        self.cutoff.update(&self.synthstate, frames);
+        // после смены пресета множитель, как и сам срез, встаёт на место сразу
+        let preset = self.synthstate.preset_counter.load(Ordering::Relaxed);
+        if preset != self.last_preset {
+            self.last_preset = preset;
+            self.velocity.reset(Self::velocity_target(&self.synthstate));
+        }
+        self.velocity.set_target(Self::velocity_target(&self.synthstate));
+        self.res_factor.update(&self.synthstate, frames);
+        for frame in output.chunks_mut(channels) {
+            self.next_frame();
+            for (channel, s) in frame.iter_mut().enumerate() {
+                *s = self.filter(channel, *s);
+            }
         }
     }
 }
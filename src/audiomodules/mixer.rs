@@ -0,0 +1,33 @@
+use crate::audiomodules::AudioModule;
+use std::sync::{Arc, Mutex};
+
+/// Складывает выходы нескольких частей. У каждой части своя цепочка модулей,
+/// она считается в отдельный буфер с нуля, как будто играет одна.
+pub struct PartMixer {
+  parts: Vec<Vec<Arc<Mutex<dyn AudioModule>>>>,
+  scratch: Vec<f32>,
+}
+
+impl PartMixer {
+  pub fn new(parts: Vec<Vec<Arc<Mutex<dyn AudioModule>>>>) -> Self {
+    Self { parts, scratch: Vec::new() }
+  }
+}
+
+impl AudioModule for PartMixer {
+  fn process(&mut self, output: &mut [f32]) {
+    // буфер растёт только при первом блоке нового размера
+    self.scratch.resize(output.len(), 0.0);
+    for chain in &self.parts {
+      self.scratch.fill(0.0);
+      for module in chain {
+        if let Ok(mut m) = module.lock() {
+          m.process(&mut self.scratch);
+        }
+      }
+      for (out, sample) in output.iter_mut().zip(&self.scratch) {
+        *out += *sample;
+      }
+    }
+  }
+}
@@ -0,0 +1,93 @@
+use crate::audiomodules::envelope::{AdsrParams, BreakpointEnvelope, Envelope};
+use crate::audiomodules::smoother::SmoothedParam;
+use crate::audiomodules::AudioModule;
+use crate::params::{ENV_ATTACK, ENV_DECAY, ENV_DELAY, ENV_HOLD, ENV_MODE, ENV_RELEASE, ENV_SUSTAIN};
+use crate::synth_state::{SynthState, MOD_ENV_BREAKPOINT};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+// форма стадий модулирующей огибающей не настраивается отдельно
+const MOD_ENV_CURVE: f32 = 0.04;
+
+struct ModEnvVoice {
+  dahdsr: Envelope,
+  breakpoint: BreakpointEnvelope,
+  sustain: SmoothedParam,
+}
+
+/// Модулирующие огибающие. Звук не трогает: раз в блок прогоняет огибающие
+/// на длину блока и публикует их значения в `SynthState::mod_env_values`.
+pub struct ModEnvelope {
+  envs: Vec<ModEnvVoice>,
+  channels: usize,
+  was_pressed: bool,
+  synthstate: Arc<SynthState>,
+}
+
+impl ModEnvelope {
+  pub fn new(sample_rate: f32, channels: usize, synthstate: Arc<SynthState>) -> Self {
+    Self {
+      envs: (0..synthstate.mod_env_values.len())
+        .map(|i| ModEnvVoice {
+          dahdsr: Envelope::new(sample_rate),
+          breakpoint: BreakpointEnvelope::new(sample_rate),
+          sustain: SmoothedParam::new(ENV_SUSTAIN[i], sample_rate, &synthstate),
+        })
+        .collect(),
+      channels: channels.max(1),
+      was_pressed: false,
+      synthstate,
+    }
+  }
+
+  fn read_params(s: &SynthState, i: usize, sustain: f32) -> AdsrParams {
+    AdsrParams {
+      delay_ms: s.value(ENV_DELAY[i]),
+      attack_ms: s.value(ENV_ATTACK[i]),
+      hold_ms: s.value(ENV_HOLD[i]),
+      decay_ms: s.value(ENV_DECAY[i]),
+      sustain,
+      release_ms: s.value(ENV_RELEASE[i]),
+      attack_curve: MOD_ENV_CURVE,
+      decay_curve: MOD_ENV_CURVE,
+      release_curve: MOD_ENV_CURVE,
+    }
+  }
+}
+
+impl AudioModule for ModEnvelope {
+  fn process(&mut self, output: &mut [f32]) {
+    let frames = output.len() / self.channels;
+    let pressed = self.synthstate.has_key_pressed.load(Ordering::Relaxed);
+    let gate_on = pressed && !self.was_pressed;
+    let gate_off = !pressed && self.was_pressed;
+    self.was_pressed = pressed;
+
+    let s = &self.synthstate;
+    for (i, env) in self.envs.iter_mut().enumerate() {
+      env.sustain.update(s, frames);
+      let params = Self::read_params(s, i, env.sustain.skip(frames));
+      let mode = s.choice(ENV_MODE[i]);
+      let shape = s.mod_env_breakpoints[i].lock().unwrap();
+
+      if gate_on {
+        env.dahdsr.gate_on();
+        env.breakpoint.gate_on();
+      } else if gate_off {
+        env.dahdsr.gate_off();
+        env.breakpoint.gate_off(&shape);
+      }
+
+      env.dahdsr.set_params(&params);
+      let mut value = 0.0;
+      for _ in 0..frames {
+        value = if mode == MOD_ENV_BREAKPOINT {
+          env.breakpoint.next(&shape)
+        } else {
+          env.dahdsr.next()
+        };
+      }
+      s.mod_env_values[i].store(value, Ordering::Relaxed);
+    }
+  }
+}
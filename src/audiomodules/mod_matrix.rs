@@ -0,0 +1,338 @@
+use crate::audiomodules::AudioModule;
+use crate::params::{ParamId, MOD_AMOUNT, MOD_DESTINATION, MOD_SOURCE, MOD_VIA, PARAM_COUNT};
+use crate::synth_state::{HeldNote, SynthState, MOD_SLOT_COUNT, PITCH_BEND_CENTER, TIMBRE_CENTER};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+/// Источники модуляции
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[repr(u8)]
+pub enum ModSource {
+  None,
+  Lfo1,
+  Lfo2,
+  Env1,
+  Env2,
+  Velocity,
+  ModWheel,
+  Aftertouch,
+  Key,
+  Breath,
+  /// Полифоническое послекасание своей ноты
+  PolyAftertouch,
+  /// CC 74 канала ноты MPE, -1..1
+  Timbre,
+}
+
+impl ModSource {
+  pub const ALL: [ModSource; 12] = [
+    ModSource::None,
+    ModSource::Lfo1,
+    ModSource::Lfo2,
+    ModSource::Env1,
+    ModSource::Env2,
+    ModSource::Velocity,
+    ModSource::ModWheel,
+    ModSource::Aftertouch,
+    ModSource::Key,
+    ModSource::Breath,
+    ModSource::PolyAftertouch,
+    ModSource::Timbre,
+  ];
+
+  pub fn from_u8(value: u8) -> Self {
+    Self::ALL.get(value as usize).copied().unwrap_or(ModSource::None)
+  }
+}
+
+/// Приёмники модуляции. Для параметров смещение складывается с положением ручки (0..1),
+/// у `Pitch` единица -- две октавы, у `OscLevel` -- множитель громкости ноты.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ModDestination {
+  None,
+  Pitch,
+  OscLevel,
+  Param(ParamId),
+}
+
+// в параметре приёмник хранится как номер: 0, 1, 2 -- особые, дальше номера параметров
+const FIRST_PARAM_DESTINATION: usize = 3;
+/// Сколько всего приёмников
+pub const MOD_DESTINATION_COUNT: usize = PARAM_COUNT + FIRST_PARAM_DESTINATION;
+// параметр приёмника хранит номер в постоянном диапазоне 0..=255
+const _: () = assert!(MOD_DESTINATION_COUNT <= 256);
+
+impl ModDestination {
+  pub const fn to_u8(self) -> u8 {
+    match self {
+      ModDestination::None => 0,
+      ModDestination::Pitch => 1,
+      ModDestination::OscLevel => 2,
+      ModDestination::Param(id) => (id as usize + FIRST_PARAM_DESTINATION) as u8,
+    }
+  }
+
+  pub fn from_u8(value: u8) -> Self {
+    match value as usize {
+      1 => ModDestination::Pitch,
+      2 => ModDestination::OscLevel,
+      index if index >= FIRST_PARAM_DESTINATION => ParamId::from_index(index - FIRST_PARAM_DESTINATION)
+        .map(ModDestination::Param)
+        .unwrap_or(ModDestination::None),
+      _ => ModDestination::None,
+    }
+  }
+
+  pub fn name(self) -> &'static str {
+    match self {
+      ModDestination::None => "none",
+      ModDestination::Pitch => "pitch",
+      ModDestination::OscLevel => "osc level",
+      ModDestination::Param(id) => id.info().name,
+    }
+  }
+}
+
+/// Источники, которые у каждой ноты свои
+#[derive(Clone, Copy)]
+pub struct VoiceSources {
+  pub note: u8,
+  pub velocity: u8,
+  pub pressure: u8,
+  pub bend: u16,
+  pub timbre: u8,
+}
+
+impl VoiceSources {
+  pub fn from_held(held: &HeldNote) -> Self {
+    Self {
+      note: held.note,
+      velocity: held.velocity,
+      pressure: held.pressure,
+      bend: held.bend,
+      timbre: held.timbre,
+    }
+  }
+
+  /// Источники последней нажатой ноты
+  pub fn last(synthstate: &SynthState) -> Self {
+    Self::last_of(synthstate, &synthstate.nazatie_knopki.lock().unwrap())
+  }
+
+  /// То же для уже взятых нот: мьютекс нот не рекурсивный
+  pub fn last_of(synthstate: &SynthState, knopki: &[HeldNote]) -> Self {
+    let note = synthstate.last_key.load(Ordering::Relaxed);
+    // в MPE одна и та же нота может звучать на разных каналах, берём последнюю
+    let held = knopki.iter().rev().find(|held| held.note == note);
+    Self {
+      note,
+      velocity: synthstate.last_velocity.load(Ordering::Relaxed),
+      pressure: held.map_or(0, |held| held.pressure),
+      bend: held.map_or(PITCH_BEND_CENTER, |held| held.bend),
+      timbre: held.map_or(TIMBRE_CENTER, |held| held.timbre),
+    }
+  }
+}
+
+fn source_value(synthstate: &SynthState, source: ModSource, voice: &VoiceSources) -> f32 {
+  match source {
+    ModSource::None => 0.0,
+    ModSource::Lfo1 => synthstate.lfo_values[0].load(Ordering::Relaxed),
+    ModSource::Lfo2 => synthstate.lfo_values[1].load(Ordering::Relaxed),
+    ModSource::Env1 => synthstate.mod_env_values[0].load(Ordering::Relaxed),
+    ModSource::Env2 => synthstate.mod_env_values[1].load(Ordering::Relaxed),
+    ModSource::Velocity => synthstate.velocity_response(voice.velocity),
+    ModSource::ModWheel => synthstate.mod_wheel.load(Ordering::Relaxed) as f32 / 127.0,
+    ModSource::Aftertouch => synthstate.aftertouch.load(Ordering::Relaxed) as f32 / 127.0,
+    // -1 на ноте 0, 0 на C4 (60), +1 на ноте 120
+    ModSource::Key => (voice.note as f32 - 60.0) / 60.0,
+    ModSource::Breath => synthstate.breath.load(Ordering::Relaxed) as f32 / 127.0,
+    ModSource::PolyAftertouch => voice.pressure as f32 / 127.0,
+    ModSource::Timbre => (voice.timbre as f32 - TIMBRE_CENTER as f32) / 63.0,
+  }
+}
+
+fn slot_destination(synthstate: &SynthState, slot: usize) -> ModDestination {
+  ModDestination::from_u8(synthstate.choice(MOD_DESTINATION[slot]))
+}
+
+/// Ячейка `slot` матрицы: `source * amount (* via)` прибавляется к приёмнику.
+/// Настройки ячеек -- обычные параметры, модуляция на них не действует.
+fn slot_value(synthstate: &SynthState, slot: usize, voice: &VoiceSources) -> f32 {
+  let source = ModSource::from_u8(synthstate.choice(MOD_SOURCE[slot]));
+  if source == ModSource::None {
+    return 0.0;
+  }
+  let amount = synthstate.params.value(MOD_AMOUNT[slot]);
+  let mut value = source_value(synthstate, source, voice) * amount;
+  let via = ModSource::from_u8(synthstate.choice(MOD_VIA[slot]));
+  if via != ModSource::None {
+    value *= source_value(synthstate, via, voice);
+  }
+  value
+}
+
+/// Смещение приёмника для конкретной ноты. Нужно для приёмников,
+/// которые у каждой ноты свои (высота, уровень осциллятора).
+pub fn voice_offset(synthstate: &SynthState, destination: ModDestination, voice: &VoiceSources) -> f32 {
+  (0..MOD_SLOT_COUNT)
+    .filter(|&slot| slot_destination(synthstate, slot) == destination)
+    .map(|slot| slot_value(synthstate, slot, voice))
+    .sum()
+}
+
+/// Матрица модуляции. Звук не трогает: раз в блок пересчитывает все ячейки
+/// для последней нажатой ноты и публикует смещения параметров в `SynthState::mod_offsets`.
+/// Параметры общие для всех нот (фильтр и эффекты стоят после смешивания голосов),
+/// поэтому нотные источники в них берутся от последней ноты. Для каждой ноты отдельно
+/// считаются только высота и уровень осциллятора, см. `voice_offset`.
+/// Должна идти после LFO и огибающих.
+pub struct ModMatrix {
+  offsets: [f32; PARAM_COUNT],
+  synthstate: Arc<SynthState>,
+}
+
+impl ModMatrix {
+  pub fn new(synthstate: Arc<SynthState>) -> Self {
+    Self {
+      offsets: [0.0; PARAM_COUNT],
+      synthstate,
+    }
+  }
+}
+
+impl AudioModule for ModMatrix {
+  fn process(&mut self, _output: &mut [f32]) {
+    let s = &self.synthstate;
+    let voice = VoiceSources::last(s);
+
+    self.offsets = [0.0; PARAM_COUNT];
+    for slot in 0..MOD_SLOT_COUNT {
+      if let ModDestination::Param(id) = slot_destination(s, slot) {
+        self.offsets[id as usize] += slot_value(s, slot, &voice);
+      }
+    }
+    for (offset, value) in s.mod_offsets.iter().zip(self.offsets) {
+      offset.store(value, Ordering::Relaxed);
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::params::ParamStore;
+
+  fn close(a: f32, b: f32) -> bool {
+    (a - b).abs() < 1e-3
+  }
+
+  /// Состояние, в котором ни одна ячейка не подключена
+  fn empty_matrix() -> Arc<SynthState> {
+    let state = SynthState::new();
+    for slot in 0..MOD_SLOT_COUNT {
+      state.params.set_value(MOD_SOURCE[slot], ModSource::None as u8 as f32);
+      state.params.set_value(MOD_VIA[slot], ModSource::None as u8 as f32);
+      state.params.set_value(MOD_DESTINATION[slot], ModDestination::None.to_u8() as f32);
+    }
+    state
+  }
+
+  fn connect(state: &SynthState, slot: usize, source: ModSource, destination: ModDestination, amount: f32) {
+    state.params.set_value(MOD_SOURCE[slot], source as u8 as f32);
+    state.params.set_value(MOD_DESTINATION[slot], destination.to_u8() as f32);
+    state.params.set_value(MOD_AMOUNT[slot], amount);
+  }
+
+  fn voice(note: u8, pressure: u8) -> VoiceSources {
+    VoiceSources {
+      note,
+      velocity: 100,
+      pressure,
+      bend: PITCH_BEND_CENTER,
+      timbre: TIMBRE_CENTER,
+    }
+  }
+
+  #[test]
+  fn destination_round_trip() {
+    for destination in [ModDestination::None, ModDestination::Pitch, ModDestination::OscLevel] {
+      assert_eq!(ModDestination::from_u8(destination.to_u8()), destination);
+    }
+    for index in 0..PARAM_COUNT {
+      let destination = ModDestination::Param(ParamId::from_index(index).unwrap());
+      assert_eq!(ModDestination::from_u8(destination.to_u8()), destination);
+    }
+    assert_eq!(ModDestination::from_u8(MOD_DESTINATION_COUNT as u8), ModDestination::None);
+    assert_eq!(ModDestination::from_u8(u8::MAX), ModDestination::None);
+  }
+
+  #[test]
+  fn destination_position_does_not_depend_on_registry_size() {
+    // старые дампы и ручки хранят это положение, оно не должно уехать от новых параметров
+    let cutoff = ModDestination::Param(ParamId::LpfCutoff);
+    assert_eq!(cutoff.to_u8(), 34);
+    let store = ParamStore::new();
+    store.set_value(ParamId::Mod1Destination, cutoff.to_u8() as f32);
+    assert_eq!(store.raw(ParamId::Mod1Destination), 2184);
+
+    store.set_raw(ParamId::Mod1Destination, 2184);
+    assert_eq!(ModDestination::from_u8(store.value(ParamId::Mod1Destination) as u8), cutoff);
+    store.set_raw(ParamId::Mod1Source, 3171);
+    assert_eq!(ModSource::from_u8(store.value(ParamId::Mod1Source) as u8), ModSource::ModWheel);
+  }
+
+  #[test]
+  fn slot_value_with_and_without_via() {
+    let state = empty_matrix();
+    connect(&state, 0, ModSource::ModWheel, ModDestination::Pitch, 0.5);
+    state.mod_wheel.store(127, Ordering::Relaxed);
+    let voice = voice(60, 0);
+    assert!(close(slot_value(&state, 0, &voice), 0.5));
+
+    state.params.set_value(MOD_VIA[0], ModSource::Aftertouch as u8 as f32);
+    state.aftertouch.store(0, Ordering::Relaxed);
+    assert!(close(slot_value(&state, 0, &voice), 0.0));
+    state.aftertouch.store(127, Ordering::Relaxed);
+    assert!(close(slot_value(&state, 0, &voice), 0.5));
+
+    // без источника ячейка молчит, даже если глубина и via выставлены
+    state.params.set_value(MOD_SOURCE[0], ModSource::None as u8 as f32);
+    assert_eq!(slot_value(&state, 0, &voice), 0.0);
+  }
+
+  #[test]
+  fn process_sums_slots_into_offsets() {
+    let state = empty_matrix();
+    let cutoff = ModDestination::Param(ParamId::LpfCutoff);
+    connect(&state, 0, ModSource::ModWheel, cutoff, 0.5);
+    connect(&state, 3, ModSource::Breath, cutoff, -0.25);
+    connect(&state, 5, ModSource::ModWheel, ModDestination::Param(ParamId::PhaserMix), 1.0);
+    state.mod_wheel.store(127, Ordering::Relaxed);
+    state.breath.store(127, Ordering::Relaxed);
+
+    let mut matrix = ModMatrix::new(state.clone());
+    matrix.process(&mut [0.0; 64]);
+    assert!(close(state.mod_offset(ParamId::LpfCutoff), 0.25));
+    assert!(close(state.mod_offset(ParamId::PhaserMix), 1.0));
+    assert_eq!(state.mod_offset(ParamId::LpfResonance), 0.0);
+
+    // смещения пересчитываются с нуля каждый блок
+    state.mod_wheel.store(0, Ordering::Relaxed);
+    matrix.process(&mut [0.0; 64]);
+    assert!(close(state.mod_offset(ParamId::LpfCutoff), -0.25));
+    assert_eq!(state.mod_offset(ParamId::PhaserMix), 0.0);
+  }
+
+  #[test]
+  fn pitch_offset_is_per_note() {
+    let state = empty_matrix();
+    connect(&state, 0, ModSource::PolyAftertouch, ModDestination::Pitch, 1.0);
+    connect(&state, 1, ModSource::Key, ModDestination::Pitch, 0.5);
+
+    assert!(close(voice_offset(&state, ModDestination::Pitch, &voice(60, 127)), 1.0));
+    assert!(close(voice_offset(&state, ModDestination::Pitch, &voice(60, 0)), 0.0));
+    assert!(close(voice_offset(&state, ModDestination::Pitch, &voice(120, 0)), 0.5));
+    assert_eq!(voice_offset(&state, ModDestination::OscLevel, &voice(120, 127)), 0.0);
+  }
+}
@@ -1,21 +1,18 @@
-use std::f32::consts::PI;
+use std::sync::atomic::Ordering;
 
+use crate::params::ParamId;
+use crate::synth_state::SynthState;
+
+/// Вибрато: берёт значение одного из LFO и переводит его в сдвиг высоты
 pub struct Modulator {
-  pub nessesary_amplitude: f32, 
-  pub varying: f32,
-  pub freq: f32,
-  pub step: f32
+  pub lfo: usize,
 }
- 
-pub fn modulation(modulator: &mut Modulator) -> f32 {
-  if modulator.varying <= 1.0 {
-    modulator.varying -= 1.0
-  }
-  else{
-  modulator.varying += modulator.step;
+
+/// Сдвиг высоты в полутонах
+pub fn modulation(modulator: &Modulator, synthstate: &SynthState) -> f32 {
+  let depth = synthstate.value(ParamId::VibratoDepth);
+  if depth <= 0.0 {
+    return 0.0;
   }
-  if modulator.nessesary_amplitude <= 0.0 {
-     return 0.0;
-  } 
-  return modulator.nessesary_amplitude*(PI*modulator.varying*modulator.freq).sin();
-} 
+  synthstate.lfo_values[modulator.lfo].load(Ordering::Relaxed) * depth
+}
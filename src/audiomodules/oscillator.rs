@@ -1,6 +1,9 @@
 use crate::audiomodules::glide::Glide;
+use crate::audiomodules::mod_matrix::{voice_offset, ModDestination, VoiceSources};
 use crate::audiomodules::modulator::{modulation, Modulator};
+use crate::audiomodules::smoother::{Ramp, SmoothedParam, Smoother};
 use crate::audiomodules::AudioModule;
+use crate::params::{ParamId, Smoothing, OSC_FINE, OSC_LEVEL, OSC_OCTAVE, OSC_SEMITONE, OSC_VELOCITY, OSC_WAVEFORM};
 use crate::synth_state::SynthState;
 use std::f32::consts::PI;
 use std::sync::atomic::Ordering;
@@ -9,6 +12,12 @@ use std::sync::Arc;
 struct mini_oscilatorsa {
   phase: f32,
   frequenchy: f32,
+  // канал и нота, которые играет голос: у новой ноты модуляция начинается без перехода
+  key: (u8, u8),
+  pitch_mod: Ramp,
+  level_mod: Ramp,
+  // колесо своего канала MPE, в полутонах
+  note_bend: Ramp,
 }
 
 impl mini_oscilatorsa {
@@ -16,37 +25,66 @@ impl mini_oscilatorsa {
     Self {
       phase: 0.0,
       frequenchy: 0.0,
+      key: (u8::MAX, u8::MAX),
+      pitch_mod: Ramp::new(0.0),
+      level_mod: Ramp::new(1.0),
+      note_bend: Ramp::new(0.0),
+    }
+  }
+
+  /// Модуляция и колесо ноты `key` на конец блока
+  fn set_modulation(&mut self, key: (u8, u8), pitch: f32, level: f32, bend: f32, frames: usize) {
+    if self.key != key {
+      self.key = key;
+      self.pitch_mod.reset(pitch);
+      self.level_mod.reset(level);
+      self.note_bend.reset(bend);
+    } else {
+      self.pitch_mod.set_target(pitch, frames);
+      self.level_mod.set_target(level, frames);
+      self.note_bend.set_target(bend, frames);
     }
   }
 }
 
+// колесо присылает ~100 сообщений в секунду, сглаживаем ступеньки между ними
+const PITCH_BEND_SMOOTHING_MS: f32 = 15.0;
+
 pub struct Oscillator {
   phase: f32,
   frequency: f32,
   sample_rate: f32,
+  channels: usize,
   synthstate: Arc<SynthState>,
   id: usize,
   modulator: Modulator,
   glide: Glide,
+  level: SmoothedParam,
+  fine: SmoothedParam,
+  pitch_bend: Smoother,
+  // вибрато и матрица модуляции считаются раз в блок, внутри блока идут линейно
+  vibrato: Ramp,
+  mono: mini_oscilatorsa,
   mini_osilators: [mini_oscilatorsa; 8],
 }
 
 impl Oscillator {
-  pub fn new(id: usize, frequency: f32, sample_rate: f32, synthstate: Arc<SynthState>) -> Self {
+  pub fn new(id: usize, frequency: f32, sample_rate: f32, channels: usize, synthstate: Arc<SynthState>) -> Self {
     Self {
       phase: 0.0,
       frequency,
       sample_rate,
+      channels: channels.max(1),
       synthstate: synthstate.clone(),
       id,
 
-      modulator: Modulator {
-        nessesary_amplitude: 5.0,
-        varying: 0.0,
-        freq: 100.0,
-        step: 0.001,
-      },
+      modulator: Modulator { lfo: 0 },
 
+      level: SmoothedParam::new(OSC_LEVEL[id], sample_rate, &synthstate),
+      fine: SmoothedParam::new(OSC_FINE[id], sample_rate, &synthstate),
+      pitch_bend: Smoother::new(Smoothing::OnePole(PITCH_BEND_SMOOTHING_MS), sample_rate, 0.0),
+      vibrato: Ramp::new(0.0),
+      mono: mini_oscilatorsa::op(),
       glide: Glide::new(frequency, synthstate, sample_rate),
       mini_osilators: [
         mini_oscilatorsa::op(),
@@ -62,6 +100,24 @@ impl Oscillator {
   }
 }
 
+impl Oscillator {
+  /// Громкость ноты с учётом силы нажатия: общая громкость и уровень этого осциллятора
+  fn velocity_gain(&self, velocity: u8) -> f32 {
+    let s = &self.synthstate;
+    s.velocity_scale(ParamId::VelocityToAmp, velocity) * s.velocity_scale(OSC_VELOCITY[self.id], velocity)
+  }
+
+  /// Сдвиг высоты ноты из матрицы модуляции в полутонах, единица смещения -- две октавы
+  fn pitch_offset(&self, voice: &VoiceSources) -> f32 {
+    voice_offset(&self.synthstate, ModDestination::Pitch, voice) * 24.0
+  }
+
+  /// Множитель уровня ноты из матрицы модуляции, 0..2
+  fn level_offset(&self, voice: &VoiceSources) -> f32 {
+    (1.0 + voice_offset(&self.synthstate, ModDestination::OscLevel, voice)).clamp(0.0, 2.0)
+  }
+}
+
 pub fn midi_note_to_freq(note: f32) -> f32 {
   if note <= 0.0 {
     return 0.0;
@@ -71,26 +127,32 @@ pub fn midi_note_to_freq(note: f32) -> f32 {
 
 impl AudioModule for Oscillator {
   fn process(&mut self, output: &mut [f32]) {
-    let gromkost = {
-      let vol = self.synthstate.gromkost.lock().unwrap();
-      vol[self.id]
-    };
-
-    let sdvig_oktov = self.synthstate.sdvig_oktov[self.id].load(Ordering::Relaxed) as f32;
-    let nnno = self.synthstate.nnno[self.id].load(Ordering::Relaxed) as f32;
-
-    let micro_zdvig = {
-      let micros = self.synthstate.micro_zdvig.lock().unwrap();
-      micros[self.id]
-    };
-    let waveforma_index = self.synthstate.waveformis[self.id].load(Ordering::Relaxed);
-
-    let poli_moda = self.synthstate.poli_rezim.load(Ordering::Relaxed);
-
-    let nazatie_knopkii = {
-      let notas = self.synthstate.nazatie_knopki.lock().unwrap();
-      notas.clone()
-    };
+    // осциллятор считает кадры, во все каналы кадра идёт один и тот же сэмпл
+    let frames = output.len() / self.channels;
+    // громкость плавно меняется по блоку от прошлого значения к новому
+    self.level.update(&self.synthstate, frames);
+    let gromkost_start = self.level.current();
+    let gromkost_step = (self.level.skip(frames) - gromkost_start) / frames.max(1) as f32;
+
+    let sdvig_oktov = self.synthstate.value(OSC_OCTAVE[self.id]);
+    let nnno = self.synthstate.value(OSC_SEMITONE[self.id]);
+
+    self.fine.update(&self.synthstate, frames);
+    let micro_zdvig = self.fine.skip(frames);
+    // колесо, как и громкость, ведётся по кадрам от прошлого значения к новому
+    self.pitch_bend.set_target(self.synthstate.pitch_bend_semitones());
+    let bend_start = self.pitch_bend.current();
+    let bend_step = (self.pitch_bend.skip(frames) - bend_start) / frames.max(1) as f32;
+    let waveforma_index = self.synthstate.choice(OSC_WAVEFORM[self.id]);
+    self.vibrato.set_target(modulation(&self.modulator, &self.synthstate), frames);
+    let vibrato_start = self.vibrato.current();
+    let vibrato_step = (self.vibrato.skip(frames) - vibrato_start) / frames.max(1) as f32;
+
+    let poli_moda = self.synthstate.flag(ParamId::PolyMode);
+
+    // ноты меняет только аудиопоток между блоками, поэтому держим их до конца блока без копии
+    let synthstate = self.synthstate.clone();
+    let nazatie_knopkii = synthstate.nazatie_knopki.lock().unwrap();
 
     if nazatie_knopkii.is_empty() {
       return;
@@ -98,11 +160,29 @@ impl AudioModule for Oscillator {
 
     if poli_moda {
       for (osc_i, nota) in nazatie_knopkii.iter().take(8).enumerate() {
-        let basa_nota = *nota as f32 + sdvig_oktov * 12.0 + nnno + micro_zdvig;
-        self.mini_osilators[osc_i].frequenchy = midi_note_to_freq(basa_nota);
-
-        let phase_increment = self.mini_osilators[osc_i].frequenchy / self.sample_rate;
-        for sample in output.iter_mut() {
+        let voice = VoiceSources::from_held(nota);
+        let note_bend = self.synthstate.note_bend_semitones(voice.bend);
+        let basa_nota = nota.note as f32 + sdvig_oktov * 12.0 + nnno + micro_zdvig;
+        let skorost = self.velocity_gain(nota.velocity);
+        let (pitch_mod, level_mod) = (self.pitch_offset(&voice), self.level_offset(&voice));
+        self.mini_osilators[osc_i].set_modulation((nota.channel, nota.note), pitch_mod, level_mod, note_bend, frames);
+        let note_freq = midi_note_to_freq(basa_nota);
+
+        let mut last_pitch = f32::NAN;
+        let mut phase_increment = 0.0;
+        for (i, frame) in output.chunks_mut(self.channels).enumerate() {
+          let gromkost = gromkost_start + gromkost_step * i as f32;
+          // колёса, вибрато и матрица меняются по кадрам, частоту пересчитываем, только когда они двигаются
+          let pitch = bend_start + bend_step * i as f32
+            + vibrato_start + vibrato_step * i as f32
+            + self.mini_osilators[osc_i].note_bend.next()
+            + self.mini_osilators[osc_i].pitch_mod.next();
+          let level_mod = self.mini_osilators[osc_i].level_mod.next();
+          if pitch != last_pitch {
+            last_pitch = pitch;
+            self.mini_osilators[osc_i].frequenchy = note_freq * 2.0_f32.powf(pitch / 12.0);
+            phase_increment = self.mini_osilators[osc_i].frequenchy / self.sample_rate;
+          }
           self.mini_osilators[osc_i].phase += phase_increment;
           if self.mini_osilators[osc_i].phase > 1.0 {
             self.mini_osilators[osc_i].phase -= 1.0;
@@ -122,22 +202,34 @@ impl AudioModule for Oscillator {
             _ => 0.0,
           };
 
-          *sample += v * gromkost / nazatie_knopkii.len() as f32;
+          for sample in frame.iter_mut() {
+            *sample += v * gromkost * skorost * level_mod / nazatie_knopkii.len() as f32;
+          }
         }
       }
     } else {
       let midinota = self.synthstate.last_key.load(Ordering::Relaxed);
+      let voice = VoiceSources::last_of(&self.synthstate, &nazatie_knopkii);
+      let skorost = self.velocity_gain(voice.velocity);
       let basa_nota = midinota as f32 + sdvig_oktov * 12.0 + nnno + micro_zdvig;
+      let (pitch_mod, level_mod) = (self.pitch_offset(&voice), self.level_offset(&voice));
+      let note_bend = self.synthstate.note_bend_semitones(voice.bend);
+      self.mono.set_modulation((0, 0), pitch_mod, level_mod, note_bend, frames);
       let frequency_for_glide = midi_note_to_freq(basa_nota);
 
       // let vrema_glida = self.synthstate.glide_time.load(Ordering::Relaxed) as f32 / 127.0 * 0.5;
       // self.glide.set_glide_time(vrema_glida);
       self.glide.set_target(frequency_for_glide);
 
-      let phase_increment = self.frequency / self.sample_rate;
-      for sample in output.iter_mut() {
-        self.frequency = self.glide.next() + modulation(&mut self.modulator);
-        self.phase += phase_increment;
+      for (i, frame) in output.chunks_mut(self.channels).enumerate() {
+        let gromkost = gromkost_start + gromkost_step * i as f32;
+        let pitch_bend = bend_start + bend_step * i as f32;
+        let vibrato = vibrato_start + vibrato_step * i as f32;
+        let level_mod = self.mono.level_mod.next();
+        // вибрато, колесо и матрица после глайда, чтобы глайд их не сглаживал
+        let pitch = vibrato + pitch_bend + self.mono.note_bend.next() + self.mono.pitch_mod.next();
+        self.frequency = self.glide.next() * 2.0_f32.powf(pitch / 12.0);
+        self.phase += self.frequency / self.sample_rate;
         if self.phase > 1.0 {
           self.phase -= 1.0;
         }
@@ -154,7 +246,9 @@ impl AudioModule for Oscillator {
           3 => 4.0 * (self.phase - 0.5).abs() - 1.0,
           _ => 0.0,
         };
-        *sample += v * gromkost;
+        for sample in frame.iter_mut() {
+          *sample += v * gromkost * skorost * level_mod;
+        }
       }
     }
   }
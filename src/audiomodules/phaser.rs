@@ -0,0 +1,132 @@
+use crate::audiomodules::smoother::SmoothedParam;
+use crate::audiomodules::AudioModule;
+use crate::params::ParamId;
+use crate::synth_state::SynthState;
+use std::f32::consts::{PI, TAU};
+use std::sync::Arc;
+
+const MIN_STAGES: usize = 4;
+const MAX_STAGES: usize = 12;
+// диапазон, по которому LFO двигает точку поворота фазы
+const MIN_SWEEP_FREQ: f32 = 200.0;
+const MAX_SWEEP_FREQ: f32 = 6000.0;
+
+/// Первого порядка all-pass: y[n] = a*x[n] + x[n-1] - a*y[n-1]
+#[derive(Clone, Copy, Default)]
+struct AllPassStage {
+  x1: f32,
+  y1: f32,
+}
+
+impl AllPassStage {
+  #[inline]
+  fn process_sample(&mut self, input: f32, a: f32) -> f32 {
+    let output = a * input + self.x1 - a * self.y1;
+    self.x1 = input;
+    self.y1 = output;
+    output
+  }
+}
+
+/// Цепочка all-pass фильтров для одного канала со своим LFO
+struct PhaserChannel {
+  stages: [AllPassStage; MAX_STAGES],
+  last_output: f32,
+}
+
+impl PhaserChannel {
+  fn new() -> Self {
+    Self {
+      stages: [AllPassStage::default(); MAX_STAGES],
+      last_output: 0.0,
+    }
+  }
+
+  fn process_sample(&mut self, input: f32, a: f32, stages: usize, feedback: f32) -> f32 {
+    let mut x = input + self.last_output * feedback;
+    for stage in self.stages.iter_mut().take(stages) {
+      x = stage.process_sample(x, a);
+    }
+    self.last_output = x;
+    x
+  }
+}
+
+pub struct Phaser {
+  sample_rate: f32,
+  channels: Vec<PhaserChannel>,
+  lfo_phase: f32, // 0..1
+
+  depth: SmoothedParam,
+  feedback: SmoothedParam,
+  stereo_offset: SmoothedParam,
+  mix: SmoothedParam,
+  synthstate: Arc<SynthState>,
+}
+
+impl Phaser {
+  /// `channels` -- количество перемежающихся каналов в буфере (для стерео сдвига LFO)
+  pub fn new(sample_rate: f32, channels: usize, synthstate: Arc<SynthState>) -> Self {
+    Self {
+      sample_rate,
+      channels: (0..channels.max(1)).map(|_| PhaserChannel::new()).collect(),
+      lfo_phase: 0.0,
+      depth: SmoothedParam::new(ParamId::PhaserDepth, sample_rate, &synthstate),
+      feedback: SmoothedParam::new(ParamId::PhaserFeedback, sample_rate, &synthstate),
+      stereo_offset: SmoothedParam::new(ParamId::PhaserStereoOffset, sample_rate, &synthstate),
+      mix: SmoothedParam::new(ParamId::PhaserMix, sample_rate, &synthstate),
+      synthstate,
+    }
+  }
+
+  /// Коэффициент all-pass для заданной частоты поворота фазы
+  #[inline]
+  fn coefficient(&self, freq: f32) -> f32 {
+    let t = (PI * freq.clamp(1.0, 0.49 * self.sample_rate) / self.sample_rate).tan();
+    (t - 1.0) / (t + 1.0)
+  }
+}
+
+impl AudioModule for Phaser {
+  fn process(&mut self, output: &mut [f32]) {
+    let s = &self.synthstate;
+    let stages = (s.params.value(ParamId::PhaserStages) as usize).clamp(MIN_STAGES, MAX_STAGES);
+    let lfo_freq = s.value(ParamId::PhaserLfoRate);
+    let frames = output.len() / self.channels.len();
+    self.depth.update(s, frames);
+    self.feedback.update(s, frames);
+    self.stereo_offset.update(s, frames);
+    self.mix.update(s, frames);
+
+    if self.mix.is_settled() && self.mix.current() == 0.0 {
+      return;
+    }
+
+    let channel_count = self.channels.len();
+    let sweep_ratio = MAX_SWEEP_FREQ / MIN_SWEEP_FREQ;
+
+    for frame in output.chunks_mut(channel_count) {
+      let depth = self.depth.next();
+      let feedback = self.feedback.next();
+      // сдвиг фазы LFO между соседними каналами, в долях периода
+      let stereo_offset = self.stereo_offset.next() / 360.0;
+      let mix = self.mix.next();
+      for (ch, sample) in frame.iter_mut().enumerate() {
+        let phase = self.lfo_phase + stereo_offset * ch as f32;
+        let lfo = 0.5 + 0.5 * (phase * TAU).sin(); // 0..1
+        // экспоненциальный свип, чтобы на слух движение было равномерным
+        let freq = MIN_SWEEP_FREQ * sweep_ratio.powf(lfo * depth);
+        let a = self.coefficient(freq);
+
+        let dry = *sample;
+        let wet = self.channels[ch].process_sample(dry, a, stages, feedback);
+        *sample = dry * (1.0 - mix) + wet * mix;
+      }
+
+      self.lfo_phase += lfo_freq / self.sample_rate;
+      if self.lfo_phase >= 1.0 {
+        self.lfo_phase -= 1.0;
+      }
+    }
+  }
+}
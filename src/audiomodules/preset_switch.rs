@@ -0,0 +1,76 @@
+use crate::audiomodules::AudioModule;
+use crate::synth_state::SynthState;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+// за это время звук уходит в ноль перед сменой пресета и возвращается после
+const FADE_MS: f32 = 5.0;
+
+/// Смена пресета без щелчков. Стоит последней в цепочке: увидев ждущий пресет,
+/// уводит звук в тишину, в тишине подменяет все параметры и плавно возвращает громкость.
+/// Переключатели вроде формы волны при этом меняются, когда их не слышно, а сглаженные
+/// параметры по `SynthState::preset_counter` сразу встают на новые значения.
+pub struct PresetSwitch {
+  gain: f32,
+  step: f32,
+  channels: usize,
+  switching: bool,
+  synthstate: Arc<SynthState>,
+}
+
+impl PresetSwitch {
+  pub fn new(sample_rate: f32, channels: usize, synthstate: Arc<SynthState>) -> Self {
+    Self {
+      gain: 1.0,
+      step: 1.0 / (FADE_MS * 0.001 * sample_rate).max(1.0),
+      channels: channels.max(1),
+      switching: false,
+      synthstate,
+    }
+  }
+
+  fn has_pending(&self) -> bool {
+    // аудиопоток не ждёт MIDI-поток: занято -- проверим в следующем блоке
+    self.synthstate.pending_preset.try_lock().is_ok_and(|pending| pending.ready)
+  }
+
+  fn apply_pending(&self) -> bool {
+    let Ok(mut pending) = self.synthstate.pending_preset.try_lock() else {
+      return false;
+    };
+    if pending.ready {
+      // пресет копируется, буферы остаются на месте: аудиопоток не освобождает память
+      self.synthstate.params.restore(&pending.raw);
+      if pending.has_shapes {
+        for (slot, shape) in self.synthstate.mod_env_breakpoints.iter().zip(&pending.shapes) {
+          slot.lock().unwrap().copy_from(shape);
+        }
+      }
+      pending.ready = false;
+      self.synthstate.preset_counter.fetch_add(1, Ordering::Relaxed);
+    }
+    true
+  }
+}
+
+impl AudioModule for PresetSwitch {
+  fn process(&mut self, output: &mut [f32]) {
+    if !self.switching && self.gain >= 1.0 {
+      if !self.has_pending() {
+        return;
+      }
+      self.switching = true;
+    }
+
+    for frame in output.chunks_mut(self.channels) {
+      self.gain = if self.switching { (self.gain - self.step).max(0.0) } else { (self.gain + self.step).min(1.0) };
+      for sample in frame.iter_mut() {
+        *sample *= self.gain;
+      }
+    }
+
+    if self.switching && self.gain == 0.0 && self.apply_pending() {
+      self.switching = false;
+    }
+  }
+}
@@ -1,8 +1,16 @@
+use crate::params::ParamId;
+use crate::audiomodules::smoother::SmoothedParam;
 use crate::audiomodules::AudioModule;
+use crate::synth_state::SynthState;
+use std::sync::Arc;
 
 pub struct ReverbEffect {
-  dry_wet_mix: f32,
+  sample_rate: usize,
+  channels: usize,
   decay_time: f32,
+  decay: SmoothedParam,
+  mix: SmoothedParam,
+  synthstate: Arc<SynthState>,
   pre_delay: DelayLine,
   early_reflections: EarlyReflections,
   late_reflections: LateReflections,
@@ -54,6 +62,11 @@ struct CombFilter {
 }
 
 impl CombFilter {
+  fn feedback_for(delay: usize, decay_time: f32, sample_rate: usize) -> f32 {
+    let delay_seconds = delay as f32 / sample_rate as f32;
+    (10.0_f32).powf((-3.0 * delay_seconds) / decay_time.max(0.01))
+  }
+
   fn process_sample(&mut self, input: f32) -> f32 {
     let delayed = self.delay_line.read_at_offset(self.delay_samples);
     let new_value = input + delayed * self.feedback;
@@ -91,8 +104,7 @@ impl LateReflections {
     let comb_filters = comb_delays
       .iter()
       .map(|&delay| {
-        let delay_seconds = delay as f32 / sample_rate as f32;
-        let feedback = (10.0_f32).powf((-3.0 * delay_seconds) / decay_time);
+        let feedback = CombFilter::feedback_for(delay, decay_time, sample_rate);
         CombFilter {
           delay_line: DelayLine {
             buffer: vec![0.0; delay],
@@ -122,6 +134,12 @@ impl LateReflections {
     }
   }
 
+  fn set_decay_time(&mut self, decay_time: f32, sample_rate: usize) {
+    for filter in &mut self.comb_filters {
+      filter.feedback = CombFilter::feedback_for(filter.delay_samples, decay_time, sample_rate);
+    }
+  }
+
   fn process_sample(&mut self, input: f32) -> f32 {
     let mut comb_output = 0.0;
     for filter in &mut self.comb_filters {
@@ -137,29 +155,49 @@ impl LateReflections {
 
 impl AudioModule for ReverbEffect {
   fn process(&mut self, output: &mut [f32]) {
-    for sample in output.iter_mut() {
-      let dry_sample = *sample;
+    let s = &self.synthstate;
+    let frames = output.len() / self.channels;
+    self.mix.update(s, frames);
+    self.decay.update(s, frames);
+    // обратная связь гребёнок пересчитывается раз в блок, этого хватает
+    let decay_time = self.decay.skip(frames);
+    if decay_time != self.decay_time {
+      self.decay_time = decay_time;
+      self.late_reflections.set_decay_time(decay_time, self.sample_rate);
+    }
+
+    for frame in output.chunks_mut(self.channels) {
+      // баланс сглаживается по кадрам
+      let dry_wet_mix = self.mix.next();
+      for sample in frame.iter_mut() {
+        let dry_sample = *sample;
 
-      let delayed_sample = self.pre_delay.process_sample(dry_sample);
+        let delayed_sample = self.pre_delay.process_sample(dry_sample);
 
-      let early = self.early_reflections.process_sample(delayed_sample);
-      let late = self.late_reflections.process_sample(delayed_sample);
+        let early = self.early_reflections.process_sample(delayed_sample);
+        let late = self.late_reflections.process_sample(delayed_sample);
 
-      let wet_sample = early + late;
+        let wet_sample = early + late;
 
-      *sample = (dry_sample * (1.0 - self.dry_wet_mix)) + (wet_sample * self.dry_wet_mix);
+        *sample = (dry_sample * (1.0 - dry_wet_mix)) + (wet_sample * dry_wet_mix);
+      }
     }
   }
 }
 
 impl ReverbEffect {
-  pub fn new(dry_wet_mix: f32, decay_time: f32, sample_rate: usize) -> Self {
+  pub fn new(sample_rate: usize, channels: usize, synthstate: Arc<SynthState>) -> Self {
+    let decay_time = synthstate.value(ParamId::ReverbDecayTime);
     let pre_delay_samples = (0.05 * sample_rate as f32) as usize;
     let early_reflections_buffer_len = 6000;
 
     Self {
-      dry_wet_mix,
+      sample_rate,
+      channels: channels.max(1),
       decay_time,
+      decay: SmoothedParam::new(ParamId::ReverbDecayTime, sample_rate as f32, &synthstate),
+      mix: SmoothedParam::new(ParamId::ReverbMix, sample_rate as f32, &synthstate),
+      synthstate,
       pre_delay: DelayLine {
         buffer: vec![0.0; pre_delay_samples],
         write_head: 0,
@@ -0,0 +1,395 @@
+use crate::params::{ParamId, Smoothing};
+use crate::synth_state::SynthState;
+use std::sync::atomic::Ordering;
+
+// ближе этого к цели считаем, что переход закончился
+const SETTLE_EPSILON: f32 = 1e-5;
+// ln(100): за время сглаживания экспонента проходит 99% пути
+const ONE_POLE_TIME_CONSTANTS: f32 = 4.605;
+
+/// Плавный переход к новому значению вместо скачка
+pub struct Smoother {
+  smoothing: Smoothing,
+  sample_rate: f32,
+  current: f32,
+  target: f32,
+  // экспонента: current = target + (current - target) * coef
+  coef: f32,
+  // линейный переход: шаг и сколько сэмплов осталось
+  step: f32,
+  remaining: usize,
+}
+
+impl Smoother {
+  pub fn new(smoothing: Smoothing, sample_rate: f32, value: f32) -> Self {
+    let mut smoother = Self {
+      smoothing,
+      sample_rate,
+      current: value,
+      target: value,
+      coef: 0.0,
+      step: 0.0,
+      remaining: 0,
+    };
+    smoother.set_smoothing(smoothing);
+    smoother
+  }
+
+  /// Меняет вид и время сглаживания, текущий переход продолжается с новой скоростью
+  pub fn set_smoothing(&mut self, smoothing: Smoothing) {
+    self.smoothing = smoothing;
+    if let Smoothing::OnePole(ms) = smoothing {
+      let samples = ms * 0.001 * self.sample_rate;
+      self.coef = if samples < 1.0 { 0.0 } else { (-ONE_POLE_TIME_CONSTANTS / samples).exp() };
+    }
+    let target = self.target;
+    self.target = f32::NAN;
+    self.set_target(target);
+  }
+
+  pub fn set_target(&mut self, target: f32) {
+    if target == self.target {
+      return;
+    }
+    self.target = target;
+    match self.smoothing {
+      Smoothing::None => self.current = target,
+      Smoothing::OnePole(_) => {},
+      Smoothing::Linear(ms) => {
+        self.remaining = (ms * 0.001 * self.sample_rate).round() as usize;
+        if self.remaining == 0 {
+          self.current = target;
+        } else {
+          self.step = (target - self.current) / self.remaining as f32;
+        }
+      },
+    }
+  }
+
+  /// Сразу встаёт на значение, без перехода
+  pub fn reset(&mut self, value: f32) {
+    self.current = value;
+    self.target = value;
+    self.remaining = 0;
+  }
+
+  pub fn is_settled(&self) -> bool {
+    self.current == self.target
+  }
+
+  pub fn current(&self) -> f32 {
+    self.current
+  }
+
+  /// Следующий сэмпл перехода
+  #[inline]
+  pub fn next(&mut self) -> f32 {
+    if self.is_settled() {
+      return self.current;
+    }
+    match self.smoothing {
+      Smoothing::None => self.current = self.target,
+      Smoothing::OnePole(_) => {
+        self.current = self.target + (self.current - self.target) * self.coef;
+        if (self.current - self.target).abs() < SETTLE_EPSILON {
+          self.current = self.target;
+        }
+      },
+      Smoothing::Linear(_) => {
+        self.remaining = self.remaining.saturating_sub(1);
+        self.current = if self.remaining == 0 { self.target } else { self.current + self.step };
+      },
+    }
+    self.current
+  }
+
+  /// Проматывает переход на `samples` сэмплов, для модулей, которые читают параметры раз в блок
+  pub fn skip(&mut self, samples: usize) -> f32 {
+    if self.is_settled() {
+      return self.current;
+    }
+    match self.smoothing {
+      Smoothing::None => self.current = self.target,
+      Smoothing::OnePole(_) => {
+        self.current = self.target + (self.current - self.target) * self.coef.powi(samples as i32);
+        if (self.current - self.target).abs() < SETTLE_EPSILON {
+          self.current = self.target;
+        }
+      },
+      Smoothing::Linear(_) => {
+        if samples >= self.remaining {
+          self.remaining = 0;
+          self.current = self.target;
+        } else {
+          self.remaining -= samples;
+          self.current += self.step * samples as f32;
+        }
+      },
+    }
+    self.current
+  }
+}
+
+/// Линейный переход за блок. Источники модуляции (LFO, огибающие) считаются раз в блок
+/// и отдают значение на его конец; переход от прошлого значения к новому убирает ступеньки.
+pub struct Ramp {
+  current: f32,
+  target: f32,
+  step: f32,
+  remaining: usize,
+}
+
+impl Ramp {
+  pub fn new(value: f32) -> Self {
+    Self {
+      current: value,
+      target: value,
+      step: 0.0,
+      remaining: 0,
+    }
+  }
+
+  /// Новое значение, к которому нужно прийти за `frames` кадров
+  pub fn set_target(&mut self, target: f32, frames: usize) {
+    self.target = target;
+    if frames == 0 || target == self.current {
+      self.reset(target);
+      return;
+    }
+    self.step = (target - self.current) / frames as f32;
+    self.remaining = frames;
+  }
+
+  /// Сразу встаёт на значение, без перехода
+  pub fn reset(&mut self, value: f32) {
+    self.current = value;
+    self.target = value;
+    self.remaining = 0;
+  }
+
+  pub fn is_settled(&self) -> bool {
+    self.remaining == 0
+  }
+
+  pub fn current(&self) -> f32 {
+    self.current
+  }
+
+  #[inline]
+  pub fn next(&mut self) -> f32 {
+    if self.remaining > 0 {
+      self.remaining -= 1;
+      self.current = if self.remaining == 0 { self.target } else { self.current + self.step };
+    }
+    self.current
+  }
+
+  pub fn skip(&mut self, frames: usize) -> f32 {
+    if frames >= self.remaining {
+      self.reset(self.target);
+    } else {
+      self.remaining -= frames;
+      self.current += self.step * frames as f32;
+    }
+    self.current
+  }
+}
+
+/// Сглаженный параметр из реестра. Сглаживает положение ручки (0..1),
+/// так что экспоненциальные параметры вроде частоты среза меняются равномерно на слух.
+/// Смещение от матрицы модуляции не сглаживается, а ведётся по блоку линейно.
+pub struct SmoothedParam {
+  id: ParamId,
+  smoother: Smoother,
+  modulation: Ramp,
+  normalized: f32,
+  value: f32,
+  last_preset: u32,
+}
+
+impl SmoothedParam {
+  pub fn new(id: ParamId, sample_rate: f32, synthstate: &SynthState) -> Self {
+    let knob = synthstate.params.normalized(id);
+    let offset = synthstate.mod_offset(id);
+    let normalized = (knob + offset).clamp(0.0, 1.0);
+    Self {
+      id,
+      smoother: Smoother::new(id.smoothing(), sample_rate, knob),
+      modulation: Ramp::new(offset),
+      normalized,
+      value: id.info().denormalize(normalized),
+      last_preset: synthstate.preset_counter.load(Ordering::Relaxed),
+    }
+  }
+
+  /// Берёт новую цель из `SynthState` раз в блок длиной `frames` кадров.
+  /// После смены пресета сразу встаёт на новое значение.
+  pub fn update(&mut self, synthstate: &SynthState, frames: usize) {
+    let knob = synthstate.params.normalized(self.id);
+    let offset = synthstate.mod_offset(self.id);
+    let preset = synthstate.preset_counter.load(Ordering::Relaxed);
+    if preset != self.last_preset {
+      self.last_preset = preset;
+      self.smoother.reset(knob);
+      self.modulation.reset(offset);
+      self.set_normalized(knob + offset);
+      return;
+    }
+    self.smoother.set_target(knob);
+    self.modulation.set_target(offset, frames);
+  }
+
+  pub fn is_settled(&self) -> bool {
+    self.smoother.is_settled() && self.modulation.is_settled()
+  }
+
+  /// Значение на текущем сэмпле
+  pub fn current(&self) -> f32 {
+    self.value
+  }
+
+  /// Положение ручки (0..1) на текущем сэмпле, с модуляцией
+  pub fn normalized(&self) -> f32 {
+    self.normalized
+  }
+
+  fn set_normalized(&mut self, normalized: f32) {
+    self.normalized = normalized.clamp(0.0, 1.0);
+    self.value = self.id.info().denormalize(self.normalized);
+  }
+
+  /// Значение на следующем сэмпле
+  #[inline]
+  pub fn next(&mut self) -> f32 {
+    if !self.is_settled() {
+      let normalized = self.smoother.next() + self.modulation.next();
+      self.set_normalized(normalized);
+    }
+    self.value
+  }
+
+  /// Значение через `samples` сэмплов
+  pub fn skip(&mut self, samples: usize) -> f32 {
+    if !self.is_settled() {
+      let normalized = self.smoother.skip(samples) + self.modulation.skip(samples);
+      self.set_normalized(normalized);
+    }
+    self.value
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  // 1 сэмпл = 1 мс
+  const SR: f32 = 1000.0;
+
+  #[test]
+  fn linear_reaches_target_in_configured_time() {
+    let mut smoother = Smoother::new(Smoothing::Linear(10.0), SR, 0.0);
+    smoother.set_target(1.0);
+    for n in 1..10 {
+      let value = smoother.next();
+      assert!((value - n as f32 / 10.0).abs() < 1e-5, "sample {}: {}", n, value);
+      assert!(!smoother.is_settled());
+    }
+    assert_eq!(smoother.next(), 1.0);
+    assert!(smoother.is_settled());
+    assert_eq!(smoother.next(), 1.0);
+  }
+
+  #[test]
+  fn linear_retarget_starts_from_current_value() {
+    let mut smoother = Smoother::new(Smoothing::Linear(10.0), SR, 0.0);
+    smoother.set_target(1.0);
+    smoother.skip(5);
+    smoother.set_target(0.0);
+    assert!((smoother.next() - 0.45).abs() < 1e-5);
+    assert_eq!(smoother.skip(9), 0.0);
+    assert!(smoother.is_settled());
+  }
+
+  #[test]
+  fn one_pole_covers_99_percent_in_configured_time() {
+    let mut smoother = Smoother::new(Smoothing::OnePole(20.0), SR, 0.0);
+    smoother.set_target(1.0);
+    let mut value = 0.0;
+    for _ in 0..19 {
+      value = smoother.next();
+    }
+    assert!(value < 0.99, "{}", value);
+    value = smoother.next();
+    assert!((value - 0.99).abs() < 1e-3, "{}", value);
+
+    // дальше экспонента дотягивается до цели и останавливается
+    for _ in 0..200 {
+      smoother.next();
+    }
+    assert!(smoother.is_settled());
+    assert_eq!(smoother.current(), 1.0);
+  }
+
+  #[test]
+  fn skip_matches_per_sample_steps() {
+    for smoothing in [Smoothing::OnePole(20.0), Smoothing::Linear(20.0)] {
+      let mut stepped = Smoother::new(smoothing, SR, 0.2);
+      let mut skipped = Smoother::new(smoothing, SR, 0.2);
+      stepped.set_target(0.8);
+      skipped.set_target(0.8);
+      for _ in 0..7 {
+        stepped.next();
+      }
+      assert!((skipped.skip(7) - stepped.current()).abs() < 1e-5);
+    }
+  }
+
+  #[test]
+  fn no_smoothing_and_short_times_jump() {
+    let mut smoother = Smoother::new(Smoothing::None, SR, 0.0);
+    smoother.set_target(1.0);
+    assert!(smoother.is_settled());
+    assert_eq!(smoother.current(), 1.0);
+
+    for smoothing in [Smoothing::OnePole(0.5), Smoothing::Linear(0.4)] {
+      let mut smoother = Smoother::new(smoothing, SR, 0.0);
+      smoother.set_target(1.0);
+      assert_eq!(smoother.next(), 1.0);
+    }
+  }
+
+  #[test]
+  fn ramp_reaches_target_at_block_end() {
+    let mut ramp = Ramp::new(0.0);
+    ramp.set_target(-1.0, 4);
+    let values: Vec<f32> = (0..5).map(|_| ramp.next()).collect();
+    assert_eq!(values, [-0.25, -0.5, -0.75, -1.0, -1.0]);
+    assert!(ramp.is_settled());
+
+    ramp.set_target(1.0, 8);
+    assert!((ramp.skip(2) + 0.5).abs() < 1e-6);
+    assert_eq!(ramp.skip(100), 1.0);
+
+    ramp.set_target(0.5, 0);
+    assert_eq!(ramp.current(), 0.5);
+  }
+
+  #[test]
+  fn smoothed_param_jumps_on_preset_change() {
+    let state = SynthState::new();
+    let id = ParamId::LpfCutoff;
+    let mut param = SmoothedParam::new(id, SR, &state);
+
+    state.params.set_normalized(id, 0.0);
+    param.update(&state, 16);
+    assert!(!param.is_settled());
+    assert!(param.next() > 0.0);
+
+    state.params.set_normalized(id, 1.0);
+    state.preset_counter.fetch_add(1, Ordering::Relaxed);
+    param.update(&state, 16);
+    assert!(param.is_settled());
+    assert_eq!(param.normalized(), 1.0);
+    assert_eq!(param.current(), id.info().denormalize(1.0));
+  }
+}
@@ -1,16 +1,34 @@
 use crate::synth_state::SynthState;
 use std::sync::Arc;
+use crate::audiomodules::smoother::SmoothedParam;
 use crate::audiomodules::AudioModule;
+use crate::params::ParamId;
 
 
 pub struct Volume {
+    volume: SmoothedParam,
+    channels: usize,
     synthstate: Arc<SynthState>,
 }
+
+impl Volume {
+    pub fn new(sample_rate: f32, channels: usize, synthstate: Arc<SynthState>) -> Self {
+        Self {
+            volume: SmoothedParam::new(ParamId::Volume, sample_rate, &synthstate),
+            channels: channels.max(1),
+            synthstate,
+        }
+    }
+}
+
 impl AudioModule for Volume {
     fn process(&mut self, output: &mut [f32]) {
-        let volume = self.synthstate.volume_volume.load(std::sync::atomic::Ordering::Relaxed) as f32 / 127.0;
-        for sample in output.iter_mut() {
-            *sample *= volume;
+        self.volume.update(&self.synthstate, output.len() / self.channels);
+        for frame in output.chunks_mut(self.channels) {
+            let volume = self.volume.next();
+            for sample in frame.iter_mut() {
+                *sample *= volume;
+            }
         }
     }
 }
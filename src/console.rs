@@ -0,0 +1,74 @@
+//! Команды с клавиатуры, пока синтезатор играет.
+
+use std::io::{stdin, BufRead};
+
+use crate::params::{ParamId, PARAMS};
+use crate::synth_state::SynthState;
+use crate::sysex::{self, SysexMessage};
+
+/// Сохраняет дамп первой части в файл, как его хранят SysEx-библиотекари
+fn save_dump(synth_state: &SynthState, path: &str) -> Result<(), Box<dyn std::error::Error>> {
+  std::fs::write(path, sysex::dump(synth_state, 0)).map_err(|e| format!("{}: {}", path, e))?;
+  Ok(())
+}
+
+fn load_dump(synth_state: &SynthState, path: &str) -> Result<(), Box<dyn std::error::Error>> {
+  let data = std::fs::read(path).map_err(|e| format!("{}: {}", path, e))?;
+  match sysex::parse(&data)? {
+    Some((_, SysexMessage::Dump { raw })) => {
+      sysex::apply_dump(synth_state, &raw);
+      Ok(())
+    },
+    _ => Err(format!("{}: not a synth dump", path).into()),
+  }
+}
+
+const HELP: &str = "commands:
+  params        list parameters and their values
+  learn <key>   bind the next moved MIDI knob to a parameter
+  cancel        stop waiting for MIDI learn
+  dump <file>   save all parameters as a SysEx dump (.syx)
+  load <file>   load a SysEx dump
+  quit          exit";
+
+/// Читает команды из stdin до `quit`. Если stdin закрыт, просто ждёт вечно,
+/// чтобы синтезатор можно было запускать в фоне.
+pub fn run(synth_state: &SynthState) {
+  println!("{}", HELP);
+  for line in stdin().lock().lines() {
+    let Ok(line) = line else { break };
+    let mut words = line.split_whitespace();
+    match (words.next(), words.next()) {
+      (None, _) => {},
+      (Some("params"), _) => {
+        for info in PARAMS {
+          println!("{:24} {}", info.key, info.format(synth_state.params.value(info.id)));
+        }
+      },
+      (Some("learn"), Some(key)) => match ParamId::from_key(key) {
+        Some(id) => {
+          synth_state.arm_midi_learn(id);
+          println!("MIDI learn: move a knob to bind {}", id.info().name);
+        },
+        None => println!("unknown parameter '{}', see `params`", key),
+      },
+      (Some("dump"), Some(path)) => match save_dump(synth_state, path) {
+        Ok(()) => println!("saved {}", path),
+        Err(e) => println!("{}", e),
+      },
+      (Some("load"), Some(path)) => match load_dump(synth_state, path) {
+        Ok(()) => println!("loaded {}", path),
+        Err(e) => println!("{}", e),
+      },
+      (Some("cancel"), _) => {
+        synth_state.cancel_midi_learn();
+        println!("MIDI learn cancelled");
+      },
+      (Some("quit" | "exit"), _) => return,
+      _ => println!("{}", HELP),
+    }
+  }
+  loop {
+    std::thread::park();
+  }
+}
@@ -6,10 +6,19 @@ use std::sync::{Arc, Mutex, atomic::{Ordering},};
 
 use anyhow::Result;
 
+mod params;
+mod presets;
+mod render;
 mod synth_state;
+mod sysex;
+mod console;
+mod midi_events;
+mod midi_file;
+mod midi_mapping;
+mod midi_output;
 mod midi_service;
 
-use crate::{audiomodules::{advanced_gate::{AdvGate, GateState}, reverb::ReverbEffect}, synth_state::SynthState};
+use crate::{audiomodules::{advanced_gate::AdvGate, delay::Delay, event_scheduler::EventScheduler, lfo::LfoModule, low_pass_filter::LowPassFilter, mixer::PartMixer, mod_envelope::ModEnvelope, mod_matrix::ModMatrix, phaser::Phaser, preset_switch::PresetSwitch, reverb::ReverbEffect}, midi_mapping::{MappingConfig, MappingProfile}, midi_output::{MidiOut, MidiOutputConfig}, midi_events::{MidiEvent, Timeline, EVENT_QUEUE_SIZE}, midi_file::{MidiFile, TimedMessage}, render::RenderConfig, midi_service::{MidiInputConfig, MidiSender}, presets::PatchBank, synth_state::{Part, SynthState, MPE_MAX_MEMBERS}};
 use cpal::traits::{DeviceTrait, HostTrait};
 use cpal::{Device, SupportedStreamConfig};
 
@@ -20,6 +29,88 @@ use cpal::traits::StreamTrait;
 
 
 
+/// Значение ключа командной строки вида `--name value`
+fn arg_value(name: &str) -> Option<String> {
+  let mut args = std::env::args().skip(1);
+  while let Some(arg) = args.next() {
+    if arg == name {
+      return args.next();
+    }
+  }
+  None
+}
+
+/// Все значения ключа, который можно указать несколько раз
+fn arg_values(name: &str) -> Vec<String> {
+  let mut values = Vec::new();
+  let mut args = std::env::args().skip(1);
+  while let Some(arg) = args.next() {
+    if arg == name {
+      values.extend(args.next());
+    }
+  }
+  values
+}
+
+/// Есть ли в командной строке ключ-флаг вида `--name`
+fn has_arg(name: &str) -> bool {
+  std::env::args().skip(1).any(|arg| arg == name)
+}
+
+// куда MIDI learn сохраняет привязки, если файл не указан
+const DEFAULT_MAPPING_PATH: &str = "midi_map.toml";
+
+/// Профиль MIDI-привязок: `--midi-map файл.toml [--midi-profile имя]`.
+/// Без ключа читается `midi_map.toml`, если он есть, иначе берётся встроенный профиль.
+fn load_mapping_profile() -> Result<MappingConfig, Box<dyn std::error::Error>> {
+  let path = std::path::PathBuf::from(arg_value("--midi-map").unwrap_or_else(|| DEFAULT_MAPPING_PATH.to_string()));
+  let name = arg_value("--midi-profile");
+  let profile = if path.exists() {
+    MappingProfile::load(&path, name.as_deref())?
+  } else if arg_value("--midi-map").is_some() {
+    return Err(format!("{}: file not found", path.display()).into());
+  } else {
+    let mut profile = MappingProfile::default();
+    if let Some(name) = name {
+      profile.name = name;
+    }
+    profile
+  };
+  Ok(MappingConfig { profile, path })
+}
+
+/// Части синтезатора: `--parts N` даёт N частей на каналах 1..N,
+/// иначе одна часть на канале `--channel 1..16` или на всех (`omni`, по умолчанию).
+fn build_parts() -> Result<Vec<Part>, Box<dyn std::error::Error>> {
+  if let Some(count) = arg_value("--parts") {
+    let count: u8 = count.parse().map_err(|_| format!("--parts: '{}' is not a number", count))?;
+    if !(1..=16).contains(&count) {
+      return Err(format!("--parts: expected 1..16, got {}", count).into());
+    }
+    return Ok((0..count).map(|channel| Part { channel: Some(channel), state: SynthState::new() }).collect());
+  }
+  let channel = match arg_value("--channel").as_deref() {
+    None | Some("omni") => None,
+    Some(text) => match text.parse::<u8>() {
+      Ok(channel @ 1..=16) => Some(channel - 1),
+      _ => return Err(format!("--channel: expected 1..16 or omni, got '{}'", text).into()),
+    },
+  };
+  Ok(vec![Part { channel, state: SynthState::new() }])
+}
+
+// банк пресетов для Program Change, если файл не указан
+const DEFAULT_PATCHES_PATH: &str = "patches.toml";
+
+/// Банк пресетов: `--patches файл.toml`, без ключа -- `patches.toml`, если он есть
+fn load_patch_bank() -> Result<PatchBank, Box<dyn std::error::Error>> {
+  match arg_value("--patches") {
+    Some(path) => PatchBank::load(std::path::Path::new(&path)),
+    None if std::path::Path::new(DEFAULT_PATCHES_PATH).exists() => PatchBank::load(std::path::Path::new(DEFAULT_PATCHES_PATH)),
+    None => Ok(PatchBank::default()),
+  }
+}
+
 /// Инициализация аудиоустройства и конфигурации
 fn init_audio_device() -> Option<(Device, SupportedStreamConfig)> {
   let host = cpal::default_host();
@@ -57,43 +148,162 @@ fn start_audio_stream(
     stream
 }
 
-fn build_audio_modules(synthstate: Arc<SynthState>) -> Vec<Arc<Mutex<dyn AudioModule>>> {
-  let osc = Oscillator::new(0, 440.0, 44100.0,  synthstate.clone());
-  let osc1 = Oscillator::new(1, 660.0, 44100.0,  synthstate.clone());
-  let osc2 = Oscillator::new(2, 880.0, 44100.0,  synthstate.clone());
-  let osc3 = Oscillator::new(3, 1320.0, 44100.0,  synthstate.clone());
-  let gate = AdvGate::new(7.0,GateState::Idle,synthstate.clone());
-  let reverbeffect = ReverbEffect::new(0.5, 5.0, 44100);
+fn build_audio_modules(synthstate: Arc<SynthState>, sample_rate: f32, channels: usize) -> Vec<Arc<Mutex<dyn AudioModule>>> {
+  let lfos = LfoModule::new(sample_rate, channels, synthstate.clone());
+  let mod_env = ModEnvelope::new(sample_rate, channels, synthstate.clone());
+  let mod_matrix = ModMatrix::new(synthstate.clone());
+  let osc = Oscillator::new(0, 440.0, sample_rate, channels, synthstate.clone());
+  let osc1 = Oscillator::new(1, 660.0, sample_rate, channels, synthstate.clone());
+  let osc2 = Oscillator::new(2, 880.0, sample_rate, channels, synthstate.clone());
+  let osc3 = Oscillator::new(3, 1320.0, sample_rate, channels, synthstate.clone());
+  let lpf = LowPassFilter::new(synthstate.clone(), sample_rate, channels);
+  let gate = AdvGate::new(sample_rate, channels, synthstate.clone());
+  let phaser = Phaser::new(sample_rate, channels, synthstate.clone());
+  let delay = Delay::new(sample_rate, channels, synthstate.clone());
+  let reverbeffect = ReverbEffect::new(sample_rate as usize, channels, synthstate.clone());
+  let preset_switch = PresetSwitch::new(sample_rate, channels, synthstate.clone());
 
 
   vec![
+    // источники модуляции идут первыми, остальные модули читают их значения
+    Arc::new(Mutex::new(lfos)),
+    Arc::new(Mutex::new(mod_env)),
+    Arc::new(Mutex::new(mod_matrix)),
     Arc::new(Mutex::new(osc)), // Квадрат
     Arc::new(Mutex::new(osc1)), // син
     Arc::new(Mutex::new(osc2)), // пила
     Arc::new(Mutex::new(osc3)), // Триугольни
+    Arc::new(Mutex::new(lpf)),
     Arc::new(Mutex::new(gate)),
+    Arc::new(Mutex::new(phaser)),
+    Arc::new(Mutex::new(delay)),
     Arc::new(Mutex::new(reverbeffect)),
+    // пресеты меняются в тишине, поэтому после всех модулей
+    Arc::new(Mutex::new(preset_switch)),
     
   ]
 }
 
 
+/// Цепочки модулей всех частей, каждая со своей очередью нот. Несколько частей
+/// складываются микшером.
+fn build_engine(
+  part_states: Vec<Arc<SynthState>>,
+  consumers: Vec<rtrb::Consumer<MidiEvent>>,
+  timeline: &Timeline,
+  patches: &Arc<PatchBank>,
+  sample_rate: f32,
+  channels: usize,
+) -> Vec<Arc<Mutex<dyn AudioModule>>> {
+  let mut chains: Vec<Vec<Arc<Mutex<dyn AudioModule>>>> = part_states
+    .into_iter()
+    .zip(consumers)
+    .map(|(state, events)| {
+      let modules = build_audio_modules(state.clone(), sample_rate, channels);
+      let scheduler = EventScheduler::new(modules, events, timeline.clone(), sample_rate, channels, patches.clone(), state);
+      vec![Arc::new(Mutex::new(scheduler)) as Arc<Mutex<dyn AudioModule>>]
+    })
+    .collect();
+  if chains.len() == 1 {
+    chains.remove(0)
+  } else {
+    vec![Arc::new(Mutex::new(PartMixer::new(chains)))]
+  }
+}
+
+// рендер в файл по умолчанию
+const DEFAULT_RENDER_SAMPLE_RATE: u32 = 48000;
+const DEFAULT_RENDER_BITS: u16 = 24;
+const DEFAULT_RENDER_TAIL_S: f32 = 2.0;
+
+/// Ключи рендера: `--render файл.wav [--sample-rate 48000] [--bits 16|24|32] [--tail 2.0]`
+fn render_config(path: String) -> Result<RenderConfig, Box<dyn std::error::Error>> {
+  fn parse<T: std::str::FromStr>(name: &str, default: T) -> Result<T, String> {
+    match arg_value(name) {
+      Some(text) => text.parse().map_err(|_| format!("{}: '{}' is not a number", name, text)),
+      None => Ok(default),
+    }
+  }
+  Ok(RenderConfig {
+    path: path.into(),
+    sample_rate: parse("--sample-rate", DEFAULT_RENDER_SAMPLE_RATE)?,
+    bits: parse("--bits", DEFAULT_RENDER_BITS)?,
+    channels: 2,
+    tail_s: parse("--tail", DEFAULT_RENDER_TAIL_S)?,
+  })
+}
+
+/// Что рендерить: MIDI-файл из `--play` или сценарий из `--script`
+fn render_source() -> Result<Vec<TimedMessage>, Box<dyn std::error::Error>> {
+  if let Some(path) = arg_value("--play") {
+    return Ok(MidiFile::load(std::path::Path::new(&path))?.into_messages());
+  }
+  if let Some(path) = arg_value("--script") {
+    return render::load_event_script(std::path::Path::new(&path));
+  }
+  Err("--render needs a source: --play file.mid or --script events.txt".into())
+}
+
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-  let synth_state = SynthState::new(4);
-  let midi_con = midi_service::initiate_midi_connection(synth_state.clone());
+  let parts = build_parts()?;
+  if has_arg("--mpe") {
+    // контроллер может сам перенастроить зону через MPE Configuration Message
+    parts[0].state.mpe_members.store(MPE_MAX_MEMBERS, Ordering::Relaxed);
+  }
+  // MIDI learn и консоль работают с первой частью
+  let synth_state = parts[0].state.clone();
+  let part_states: Vec<Arc<SynthState>> = parts.iter().map(|part| part.state.clone()).collect();
+  let mapping = load_mapping_profile()?;
+  let patches = Arc::new(load_patch_bank()?);
+  println!("Patch bank: {} presets", patches.len());
+  // ноты идут в аудиопоток через очередь без блокировок, по одной на часть
+  let (producers, consumers): (Vec<_>, Vec<_>) =
+    part_states.iter().map(|_| rtrb::RingBuffer::<MidiEvent>::new(EVENT_QUEUE_SIZE)).unzip();
+
+  if let Some(path) = arg_value("--render") {
+    let config = render_config(path)?;
+    let messages = render_source()?;
+    let timeline = Timeline::offline();
+    let sender = MidiSender::new(parts, producers, timeline.clone(), mapping, patches.clone(), None)?;
+    sender.set_verbose(has_arg("--verbose"));
+    let modules = build_engine(part_states, consumers, &timeline, &patches, config.sample_rate as f32, config.channels as usize);
+    return render::render(&config, &sender, &timeline, modules, &messages);
+  }
+
+  let output = match arg_value("--midi-out") {
+    Some(port) => Some(MidiOut::connect(&MidiOutputConfig { port, thru: has_arg("--midi-thru") })?),
+    None => None,
+  };
+  // без MIDI синтезатор всё равно запускается: консоль и пресеты работают
+  let inputs = MidiInputConfig { ports: arg_values("--midi-in"), virtual_port: has_arg("--virtual") };
+  let timeline = Timeline::new();
+  let sender = MidiSender::new(parts, producers, timeline.clone(), mapping, patches.clone(), output)?;
+  sender.set_verbose(has_arg("--verbose"));
+  if let Some(path) = arg_value("--play") {
+    MidiFile::load(std::path::Path::new(&path))?.play(sender.clone());
+  }
+  let midi_con = match midi_service::initiate_midi_connection(sender, inputs) {
+    Ok(connection) => Some(connection),
+    Err(e) => {
+      eprintln!("MIDI input is not available: {}", e);
+      None
+    },
+  };
     println!("SynthState готов");
 
-  let modules = build_audio_modules(synth_state.clone());
      let (device, supported_config) = match init_audio_device() {
         Some(val) => val,
         None => return Ok(()), // Если не получилось, просто завершаемся молча
     };
     let config = supported_config.config();
+  let sample_rate = config.sample_rate.0 as f32;
+  let channels = config.channels as usize;
+  let modules = build_engine(part_states, consumers, &timeline, &patches, sample_rate, channels);
 
     let stream = start_audio_stream(device, config, modules);
     stream.play().expect("Не удалось запустить поток");
 
-    std::thread::park(); // Чтобы поток не завершился сразу
+    console::run(&synth_state); // Чтобы поток не завершился сразу
     drop(midi_con);
 
     Ok(())
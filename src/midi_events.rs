@@ -0,0 +1,555 @@
+//! MIDI-события между MIDI-потоком и аудиопотоком.
+//!
+//! MIDI-поток не меняет `SynthState` сам: ноты, педали, колесо высоты, ручки,
+//! NRPN/RPN, Program Change и SysEx-дампы уходят в очередь без блокировок вместе со временем
+//! прихода, а аудиопоток применяет их с точностью до сэмпла (см. `EventScheduler`).
+//! Так «CC, потом нота» звучит в том же порядке, что и пришло. Все буферы, которые
+//! нужны аудиопотоку для событий (ноты, пресеты, дампы), выделены заранее.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+
+use crate::params::{ParamId, PARAM_COUNT, RAW_MAX};
+use crate::presets::PatchBank;
+use crate::synth_state::{HeldNote, PendingPreset, SynthState, NOT_FROM_CONTROLLER, PITCH_BEND_CENTER, TIMBRE_CENTER};
+
+pub const CC_MOD_WHEEL: u8 = 1;
+pub const CC_BREATH: u8 = 2;
+pub const CC_SUSTAIN: u8 = 64;
+pub const CC_SOSTENUTO: u8 = 66;
+/// Тембр ноты MPE (слайд по клавише)
+pub const CC_TIMBRE: u8 = 74;
+
+// Статусы того, что MIDI-поток уже разобрал из нескольких сообщений.
+// Они меньше 0x80, поэтому не пересекаются со статусами сообщений канала.
+/// Параметр с номером `data1` встаёт в 14-битное положение `value`,
+/// `data2` -- `FROM_CONTROLLER`, если положение пришло с привязанного CC
+pub const PARAM_CHANGE: u8 = 0x01;
+pub const FROM_CONTROLLER: u8 = 1;
+/// MPE Configuration Message: число каналов нот в `data1`
+pub const MPE_CONFIGURATION: u8 = 0x02;
+/// Параметр `data1` из SysEx-дампа в положении `value`. Дамп приходит подряд такими
+/// событиями и заканчивается `DUMP_END`, тогда он и становится пресетом.
+pub const DUMP_PARAM: u8 = 0x03;
+pub const DUMP_END: u8 = 0x04;
+
+/// Сколько событий помещается в очередь одной части
+pub const EVENT_QUEUE_SIZE: usize = 1024;
+
+/// Сообщение канала с временем прихода по `Timeline`
+#[derive(Clone, Copy)]
+pub struct MidiEvent {
+  pub time_us: u64,
+  pub status: u8,
+  pub channel: u8,
+  pub data1: u8,
+  pub data2: u8,
+  /// 14 бит для событий, которым мало байтов данных: положение параметра,
+  /// банк у Program Change
+  pub value: u16,
+}
+
+/// Общая шкала времени MIDI- и аудиопотока: микросекунды от запуска
+#[derive(Clone)]
+pub struct Timeline {
+  started: Instant,
+  /// При рендере в файл время идёт по посчитанным сэмплам, а не по часам
+  offline_us: Option<Arc<AtomicU64>>,
+}
+
+impl Timeline {
+  pub fn new() -> Self {
+    Self {
+      started: Instant::now(),
+      offline_us: None,
+    }
+  }
+
+  /// Шкала, которую двигает `set_offline_time`
+  pub fn offline() -> Self {
+    Self {
+      started: Instant::now(),
+      offline_us: Some(Arc::new(AtomicU64::new(0))),
+    }
+  }
+
+  pub fn now_us(&self) -> u64 {
+    match &self.offline_us {
+      Some(offline_us) => offline_us.load(Ordering::Relaxed),
+      None => self.started.elapsed().as_micros() as u64,
+    }
+  }
+
+  pub fn set_offline_time(&self, time_us: u64) {
+    if let Some(offline_us) = &self.offline_us {
+      offline_us.store(time_us, Ordering::Relaxed);
+    }
+  }
+}
+
+/// Последние жесты канала нот MPE. Контроллер присылает их перед Note On,
+/// новая нота на канале начинает с них.
+#[derive(Clone, Copy)]
+struct MemberExpression {
+  bend: u16,
+  pressure: u8,
+  timbre: u8,
+}
+
+/// Применяет события к нажатым нотам части. Живёт в аудиопотоке, поэтому
+/// `nazatie_knopki` больше никто не держит, пока аудиопоток их ждёт.
+pub struct NotePlayer {
+  expression: [MemberExpression; 16],
+  /// Program Change выбирает пресет здесь, в момент события
+  patches: Arc<PatchBank>,
+  /// Пресет для `pending_preset`. Если он `ready`, его ещё не удалось передать: мьютекс
+  /// держал MIDI-поток или консоль, аудиопоток его не ждёт и пробует в следующем блоке
+  preset: PendingPreset,
+  /// SysEx-дамп, который собирается из `DUMP_PARAM`, и начат ли он
+  dump: Vec<u16>,
+  dumping: bool,
+}
+
+impl NotePlayer {
+  pub fn new(patches: Arc<PatchBank>) -> Self {
+    Self {
+      patches,
+      preset: PendingPreset::new(),
+      dump: vec![0; PARAM_COUNT],
+      dumping: false,
+      expression: [MemberExpression {
+        bend: PITCH_BEND_CENTER,
+        pressure: 0,
+        timbre: TIMBRE_CENTER,
+      }; 16],
+    }
+  }
+
+  /// Передаёт выбранный пресет в `PresetSwitch`, если мьютекс свободен. Буферы
+  /// меняются местами: старый вернётся сюда и пойдёт под следующий пресет.
+  pub fn flush_preset(&mut self, state: &SynthState) {
+    if !self.preset.ready {
+      return;
+    }
+    if let Ok(mut pending) = state.pending_preset.try_lock() {
+      std::mem::swap(&mut *pending, &mut self.preset);
+      self.preset.ready = false;
+    }
+  }
+
+  /// На каналах нот MPE колесо, послекасание и CC 74 относятся только к нотам своего канала
+  pub fn apply(&mut self, state: &SynthState, event: &MidiEvent) {
+    let MidiEvent { status, channel, data1: note, data2: velocity, .. } = *event;
+    let mut knopki = state.nazatie_knopki.lock().unwrap();
+    let member = state.is_mpe_member(channel);
+    let expression = &mut self.expression[channel as usize & 0x0F];
+
+    match status {
+      0xB0 if member && note == CC_TIMBRE => {
+        expression.timbre = velocity;
+        for held in knopki.iter_mut().filter(|held| held.channel == channel) {
+          held.timbre = velocity;
+        }
+      },
+      0xB0 if note == CC_MOD_WHEEL => state.mod_wheel.store(velocity, Ordering::Relaxed),
+      0xB0 if note == CC_BREATH => state.breath.store(velocity, Ordering::Relaxed),
+      0xD0 if member => {
+        expression.pressure = note;
+        for held in knopki.iter_mut().filter(|held| held.channel == channel) {
+          held.pressure = note;
+        }
+      },
+      0xE0 if member => {
+        let bend = ((velocity as u16) << 7) | note as u16;
+        expression.bend = bend;
+        for held in knopki.iter_mut().filter(|held| held.channel == channel) {
+          held.bend = bend;
+        }
+      },
+      0xB0 if note == CC_SUSTAIN || note == CC_SOSTENUTO => {
+        set_pedal(state, &mut knopki, note, velocity >= 64);
+      },
+      0x90 if velocity > 0 => { // Note On
+        if let Some(i) = knopki.iter().position(|nazataya| nazataya.note == note && nazataya.channel == channel) {
+          knopki.remove(i);
+        }
+        let mut nazataya = HeldNote::new(channel, note, velocity);
+        if member {
+          nazataya.bend = expression.bend;
+          nazataya.pressure = expression.pressure;
+          nazataya.timbre = expression.timbre;
+        }
+        knopki.push(nazataya);
+        state.last_key.store(note, Ordering::Relaxed);
+        state.last_velocity.store(velocity, Ordering::Relaxed);
+        state.has_key_pressed.store(true, Ordering::Relaxed);
+        state.note_on_counter.fetch_add(1, Ordering::Relaxed);
+      },
+      0x80 | 0x90 => { // Note Off или Note On с vel=0
+        release_note(state, &mut knopki, channel, note);
+      },
+      0xA0 => { // Polyphonic Aftertouch
+        if let Some(nazataya) = knopki.iter_mut().find(|nazataya| nazataya.note == note && nazataya.channel == channel) {
+          nazataya.pressure = velocity;
+        }
+      },
+      0xD0 => { // Channel Pressure, сила в первом байте
+        state.aftertouch.store(note, Ordering::Relaxed);
+      },
+      0xE0 => { // Pitch Bend, младшие 7 бит идут первыми
+        let bend = ((velocity as u16) << 7) | note as u16;
+        state.pitch_bend.store(bend, Ordering::Relaxed);
+      },
+      0xC0 => { // Program Change, банк в `value`
+        if let Some(preset) = self.patches.find(event.value, note) {
+          self.preset.set(&preset.raw, Some(&preset.shapes));
+          self.flush_preset(state);
+        }
+      },
+      PARAM_CHANGE => {
+        if let Some(id) = ParamId::from_index(note as usize) {
+          state.params.set_raw(id, event.value);
+          let controller = if velocity == FROM_CONTROLLER { state.params.raw(id) } else { NOT_FROM_CONTROLLER };
+          state.controller_raw[id as usize].store(controller, Ordering::Relaxed);
+        }
+      },
+      DUMP_PARAM => {
+        // параметры, которых нет в дампе, остаются такими, какими их застал дамп,
+        // вместе с изменениями, пришедшими перед ним
+        if !self.dumping {
+          state.params.snapshot_into(&mut self.dump);
+          self.dumping = true;
+        }
+        if let Some(raw) = self.dump.get_mut(note as usize) {
+          *raw = event.value.min(RAW_MAX);
+        }
+      },
+      DUMP_END => {
+        if !self.dumping {
+          state.params.snapshot_into(&mut self.dump);
+        }
+        self.dumping = false;
+        self.preset.set(&self.dump, None);
+        self.flush_preset(state);
+      },
+      MPE_CONFIGURATION => state.mpe_members.store(note, Ordering::Relaxed),
+      _ => {},
+    }
+  }
+}
+
+/// Последняя звучащая нота становится текущей, без нот гейт закрывается
+fn update_last_key(state: &SynthState, knopki: &[HeldNote]) {
+  if let Some(last) = knopki.last() {
+    state.last_key.store(last.note, Ordering::Relaxed);
+    state.last_velocity.store(last.velocity, Ordering::Relaxed);
+    state.has_key_pressed.store(true, Ordering::Relaxed);
+  } else {
+    state.last_key.store(0, Ordering::Relaxed);
+    state.has_key_pressed.store(false, Ordering::Relaxed);
+  }
+}
+
+/// Держит ли ноту какая-нибудь педаль
+fn held_by_pedal(state: &SynthState, held: &HeldNote) -> bool {
+  state.sustain_pedal.load(Ordering::Relaxed) || (held.sostenuto && state.sostenuto_pedal.load(Ordering::Relaxed))
+}
+
+/// Клавишу отпустили: нота уходит, если её не держит педаль
+fn release_note(state: &SynthState, knopki: &mut Vec<HeldNote>, channel: u8, note: u8) {
+  if let Some(held) = knopki.iter_mut().find(|held| held.note == note && held.channel == channel) {
+    if held_by_pedal(state, held) {
+      held.released = true;
+      return;
+    }
+  }
+  knopki.retain(|held| held.note != note || held.channel != channel);
+  update_last_key(state, knopki);
+}
+
+/// Sustain держит все отпущенные ноты, sostenuto -- только те, что были нажаты в момент нажатия педали
+fn set_pedal(state: &SynthState, knopki: &mut Vec<HeldNote>, cc: u8, down: bool) {
+  if cc == CC_SUSTAIN {
+    state.sustain_pedal.store(down, Ordering::Relaxed);
+  } else {
+    // педаль шлёт CC 66 и пока её держат, защёлка меняется только при нажатии и отпускании
+    let was_down = state.sostenuto_pedal.swap(down, Ordering::Relaxed);
+    if was_down != down {
+      for held in knopki.iter_mut() {
+        held.sostenuto = down && !held.released;
+      }
+    }
+  }
+  if !down {
+    knopki.retain(|held| !held.released || held_by_pedal(state, held));
+    update_last_key(state, knopki);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::alloc::{GlobalAlloc, Layout, System};
+  use std::cell::Cell;
+
+  use super::*;
+  use crate::audiomodules::preset_switch::PresetSwitch;
+  use crate::audiomodules::AudioModule;
+  use crate::synth_state::MAX_HELD_NOTES;
+
+  struct Part {
+    state: Arc<SynthState>,
+    player: NotePlayer,
+  }
+
+  impl Part {
+    fn new() -> Self {
+      Self {
+        state: SynthState::new(),
+        player: NotePlayer::new(Arc::new(PatchBank::default())),
+      }
+    }
+
+    fn send(&mut self, status: u8, data1: u8, data2: u8) {
+      let event = MidiEvent { time_us: 0, status, channel: 0, data1, data2, value: 0 };
+      self.player.apply(&self.state, &event);
+    }
+
+    fn press(&mut self, note: u8) {
+      self.send(0x90, note, 100);
+    }
+
+    fn release(&mut self, note: u8) {
+      self.send(0x80, note, 0);
+    }
+
+    fn pedal(&mut self, cc: u8, down: bool) {
+      self.send(0xB0, cc, if down { 127 } else { 0 });
+    }
+
+    /// Звучащие ноты и отпущены ли их клавиши
+    fn held(&self) -> Vec<(u8, bool)> {
+      self.state.nazatie_knopki.lock().unwrap().iter().map(|held| (held.note, held.released)).collect()
+    }
+
+    fn gate(&self) -> bool {
+      self.state.has_key_pressed.load(Ordering::Relaxed)
+    }
+  }
+
+  #[test]
+  fn release_without_pedal_drops_note() {
+    let mut part = Part::new();
+    part.press(60);
+    part.press(64);
+    part.release(64);
+    assert_eq!(part.held(), vec![(60, false)]);
+    assert_eq!(part.state.last_key.load(Ordering::Relaxed), 60);
+    part.release(60);
+    assert!(part.held().is_empty());
+    assert!(!part.gate());
+  }
+
+  #[test]
+  fn sustain_holds_released_notes_until_pedal_up() {
+    let mut part = Part::new();
+    part.pedal(CC_SUSTAIN, true);
+    part.press(60);
+    part.release(60);
+    assert_eq!(part.held(), vec![(60, true)]);
+    assert!(part.gate());
+
+    part.pedal(CC_SUSTAIN, false);
+    assert!(part.held().is_empty());
+    assert!(!part.gate());
+  }
+
+  #[test]
+  fn sustain_up_keeps_pressed_keys() {
+    let mut part = Part::new();
+    part.pedal(CC_SUSTAIN, true);
+    part.press(60);
+    part.press(64);
+    part.release(64);
+    part.pedal(CC_SUSTAIN, false);
+    assert_eq!(part.held(), vec![(60, false)]);
+    assert!(part.gate());
+  }
+
+  #[test]
+  fn repress_of_sustained_note_replaces_it() {
+    let mut part = Part::new();
+    part.pedal(CC_SUSTAIN, true);
+    part.press(60);
+    part.release(60);
+    part.press(60);
+    assert_eq!(part.held(), vec![(60, false)]);
+
+    // клавиша нажата снова, педаль её больше не держит
+    part.pedal(CC_SUSTAIN, false);
+    assert_eq!(part.held(), vec![(60, false)]);
+    part.release(60);
+    assert!(part.held().is_empty());
+    assert!(!part.gate());
+  }
+
+  #[test]
+  fn sostenuto_holds_only_notes_pressed_at_pedal_down() {
+    let mut part = Part::new();
+    part.press(60);
+    part.press(64);
+    part.pedal(CC_SOSTENUTO, true);
+    part.press(67);
+    part.release(60);
+    part.release(64);
+    part.release(67);
+    assert_eq!(part.held(), vec![(60, true), (64, true)]);
+    assert!(part.gate());
+
+    part.pedal(CC_SOSTENUTO, false);
+    assert!(part.held().is_empty());
+    assert!(!part.gate());
+  }
+
+  #[test]
+  fn sostenuto_ignores_keys_already_released() {
+    let mut part = Part::new();
+    part.pedal(CC_SUSTAIN, true);
+    part.press(60);
+    part.release(60);
+    part.press(64);
+    // 60 звучит только из-за sustain, sostenuto берёт лишь нажатую 64
+    part.pedal(CC_SOSTENUTO, true);
+    part.pedal(CC_SUSTAIN, false);
+    assert_eq!(part.held(), vec![(64, false)]);
+    part.release(64);
+    assert_eq!(part.held(), vec![(64, true)]);
+    part.pedal(CC_SOSTENUTO, false);
+    assert!(part.held().is_empty());
+    assert!(!part.gate());
+  }
+
+  #[test]
+  fn program_change_waits_for_a_free_mutex() {
+    let mut part = Part::new();
+    let state = part.state.clone();
+    let guard = state.pending_preset.lock().unwrap();
+    part.send(0xC0, 0, 0);
+    drop(guard);
+    assert!(!part.state.pending_preset.lock().unwrap().ready);
+    part.player.flush_preset(&part.state);
+    assert!(part.state.pending_preset.lock().unwrap().ready);
+  }
+
+  #[test]
+  fn repeated_sostenuto_down_does_not_relatch() {
+    let mut part = Part::new();
+    part.press(60);
+    part.pedal(CC_SOSTENUTO, true);
+    part.release(60);
+    part.press(64);
+    part.pedal(CC_SOSTENUTO, true);
+    part.release(64);
+    assert_eq!(part.held(), vec![(60, true)]);
+    part.pedal(CC_SOSTENUTO, false);
+    assert!(part.held().is_empty());
+  }
+
+  #[test]
+  fn note_off_under_both_pedals_waits_for_both() {
+    let mut part = Part::new();
+    part.press(60);
+    part.pedal(CC_SOSTENUTO, true);
+    part.pedal(CC_SUSTAIN, true);
+    part.release(60);
+    part.pedal(CC_SUSTAIN, false);
+    assert_eq!(part.held(), vec![(60, true)]);
+    part.pedal(CC_SOSTENUTO, false);
+    assert!(part.held().is_empty());
+    assert!(!part.gate());
+  }
+
+  /// Считает выделения и освобождения памяти в своём потоке, чтобы проверить,
+  /// что путь событий в аудиопотоке обходится без них
+  struct CountingAlloc;
+
+  thread_local! {
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+  }
+
+  fn count_allocation() {
+    let _ = ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+  }
+
+  unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+      count_allocation();
+      System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+      count_allocation();
+      System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+      count_allocation();
+      System.realloc(ptr, layout, new_size)
+    }
+  }
+
+  #[global_allocator]
+  static ALLOCATOR: CountingAlloc = CountingAlloc;
+
+  fn allocations(f: impl FnOnce()) -> usize {
+    let before = ALLOCATIONS.with(Cell::get);
+    f();
+    ALLOCATIONS.with(Cell::get) - before
+  }
+
+  #[test]
+  fn notes_do_not_allocate() {
+    let mut part = Part::new();
+    let count = allocations(|| {
+      // все ноты на всех каналах сразу, потом отпустить
+      for channel in 0..16 {
+        for note in 0..128 {
+          part.player.apply(&part.state, &MidiEvent { time_us: 0, status: 0x90, channel, data1: note, data2: 100, value: 0 });
+        }
+      }
+      assert_eq!(part.state.nazatie_knopki.lock().unwrap().len(), MAX_HELD_NOTES);
+      for channel in 0..16 {
+        for note in 0..128 {
+          part.player.apply(&part.state, &MidiEvent { time_us: 0, status: 0x80, channel, data1: note, data2: 0, value: 0 });
+        }
+      }
+    });
+    assert_eq!(count, 0);
+    assert!(part.held().is_empty());
+  }
+
+  #[test]
+  fn program_change_and_dump_do_not_allocate() {
+    let mut part = Part::new();
+    let mut switch = PresetSwitch::new(48_000.0, 1, part.state.clone());
+    let mut block = vec![0.0; 512];
+    let dump = |index: u8, status: u8| MidiEvent { time_us: 0, status, channel: 0, data1: index, data2: 0, value: 1000 };
+
+    let count = allocations(|| {
+      for _ in 0..2 {
+        part.send(0xC0, 0, 0);
+        for _ in 0..4 {
+          switch.process(&mut block);
+        }
+        part.player.apply(&part.state, &dump(ParamId::LpfCutoff as u8, DUMP_PARAM));
+        part.player.apply(&part.state, &dump(0, DUMP_END));
+        for _ in 0..4 {
+          switch.process(&mut block);
+        }
+      }
+    });
+    assert_eq!(count, 0);
+    assert_eq!(part.state.preset_counter.load(Ordering::Relaxed), 4);
+    assert_eq!(part.state.params.raw(ParamId::LpfCutoff), 1000);
+  }
+}
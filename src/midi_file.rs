@@ -0,0 +1,202 @@
+//! Воспроизведение Standard MIDI File (форматы 0 и 1).
+//!
+//! Все дорожки сводятся в один список сообщений со временем в микросекундах
+//! по карте темпа файла. Проигрыватель отдаёт их в тот же `MidiSender`, что и
+//! MIDI-порты, так что файл звучит так же, как живая игра.
+
+use std::error::Error;
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+
+use midly::{Format, MetaMessage, Smf, Timing, TrackEventKind};
+
+use crate::midi_service::MidiSender;
+
+// темп по умолчанию, пока в файле не встретился свой: 120 BPM
+const DEFAULT_TEMPO_US_PER_BEAT: u64 = 500_000;
+// проигрыватель начинает чуть позже, чтобы успеть разослать первые события
+const START_DELAY_US: u64 = 50_000;
+
+/// Сообщение файла и его время от начала, мкс
+pub struct TimedMessage {
+  pub time_us: u64,
+  pub bytes: Vec<u8>,
+}
+
+pub struct MidiFile {
+  messages: Vec<TimedMessage>,
+  /// Длина файла до последнего события, включая мета-события, мкс
+  length_us: f64,
+}
+
+impl MidiFile {
+  pub fn load(path: &Path) -> Result<Self, Box<dyn Error>> {
+    let data = std::fs::read(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    let file = Self::parse(&data).map_err(|e| format!("{}: {}", path.display(), e))?;
+    println!("{}: {} events, {:.1} s", path.display(), file.messages.len(), file.length_us / 1_000_000.0);
+    Ok(file)
+  }
+
+  /// Разбирает содержимое файла
+  fn parse(data: &[u8]) -> Result<Self, Box<dyn Error>> {
+    let smf = Smf::parse(data)?;
+    if smf.header.format == Format::Sequential {
+      return Err("format 2 MIDI files are not supported".into());
+    }
+
+    // все события всех дорожек в абсолютных тиках; при равных тиках порядок дорожек
+    // сохраняется, поэтому темп с нулевой дорожки применяется раньше нот
+    let mut events = Vec::new();
+    for track in &smf.tracks {
+      let mut tick = 0u64;
+      for event in track {
+        tick += event.delta.as_int() as u64;
+        events.push((tick, event.kind));
+      }
+    }
+    events.sort_by_key(|(tick, _)| *tick);
+
+    let mut messages = Vec::new();
+    let mut tempo = DEFAULT_TEMPO_US_PER_BEAT;
+    let (mut last_tick, mut time_us) = (0u64, 0f64);
+    for (tick, kind) in events {
+      time_us += (tick - last_tick) as f64 * Self::tick_us(smf.header.timing, tempo);
+      last_tick = tick;
+      if let TrackEventKind::Meta(MetaMessage::Tempo(us_per_beat)) = kind {
+        tempo = us_per_beat.as_int() as u64;
+        continue;
+      }
+      if let Some(live) = kind.as_live_event() {
+        let mut bytes = Vec::new();
+        live.write_std(&mut bytes)?;
+        messages.push(TimedMessage { time_us: time_us as u64, bytes });
+      }
+    }
+    Ok(Self { messages, length_us: time_us })
+  }
+
+  /// Длина одного тика при текущем темпе
+  fn tick_us(timing: Timing, tempo: u64) -> f64 {
+    match timing {
+      Timing::Metrical(ticks_per_beat) => tempo as f64 / ticks_per_beat.as_int().max(1) as f64,
+      // SMPTE: темп не важен, тик -- доля кадра
+      Timing::Timecode(fps, subframes) => 1_000_000.0 / (fps.as_f32() as f64 * subframes.max(1) as f64),
+    }
+  }
+
+  pub fn into_messages(self) -> Vec<TimedMessage> {
+    self.messages
+  }
+
+  /// Играет файл в реальном времени в фоновом потоке
+  pub fn play(self, sender: MidiSender) {
+    thread::spawn(move || {
+      let timeline = sender.timeline();
+      let start = timeline.now_us() + START_DELAY_US;
+      for message in &self.messages {
+        let at = start + message.time_us;
+        let now = timeline.now_us();
+        if at > now {
+          thread::sleep(Duration::from_micros(at - now));
+        }
+        // время события, а не время отправки: планировщик в аудиопотоке поставит его точно
+        sender.send(at, &message.bytes);
+      }
+      println!("MIDI file finished");
+    });
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const END_OF_TRACK: [u8; 4] = [0x00, 0xFF, 0x2F, 0x00];
+
+  /// Standard MIDI File из заголовка и дорожек; в конец каждой дорожки дописывается End of Track
+  fn smf(format: u16, division: [u8; 2], tracks: &[&[u8]]) -> Vec<u8> {
+    let mut data = b"MThd".to_vec();
+    data.extend(6u32.to_be_bytes());
+    data.extend(format.to_be_bytes());
+    data.extend((tracks.len() as u16).to_be_bytes());
+    data.extend(division);
+    for track in tracks {
+      data.extend(b"MTrk");
+      data.extend(((track.len() + END_OF_TRACK.len()) as u32).to_be_bytes());
+      data.extend(*track);
+      data.extend(END_OF_TRACK);
+    }
+    data
+  }
+
+  fn times_and_bytes(file: MidiFile) -> Vec<(u64, Vec<u8>)> {
+    file.into_messages().into_iter().map(|message| (message.time_us, message.bytes)).collect()
+  }
+
+  #[test]
+  fn default_tempo_is_120_bpm() {
+    // 96 тиков на четверть, нота длиной в четверть
+    let track = [0x00, 0x90, 60, 100, 0x60, 0x80, 60, 0];
+    let file = MidiFile::parse(&smf(0, [0, 96], &[&track])).unwrap();
+    assert_eq!(times_and_bytes(file), vec![(0, vec![0x90, 60, 100]), (500_000, vec![0x80, 60, 0])]);
+  }
+
+  #[test]
+  fn tempo_changes_apply_from_their_tick() {
+    // четверть при 120 BPM, смена на 240 BPM, ещё четверть; 192 тика -- 0x81 0x40
+    let track = [
+      0x00, 0x90, 60, 100,
+      0x60, 0xFF, 0x51, 0x03, 0x03, 0xD0, 0x90,
+      0x60, 0x80, 60, 0,
+      0x81, 0x40, 0x90, 62, 100,
+    ];
+    let file = MidiFile::parse(&smf(0, [0, 96], &[&track])).unwrap();
+    assert_eq!(file.length_us as u64, 1_250_000);
+    let times: Vec<u64> = times_and_bytes(file).iter().map(|(time, _)| *time).collect();
+    assert_eq!(times, vec![0, 750_000, 1_250_000]);
+  }
+
+  #[test]
+  fn smpte_timing_ignores_tempo() {
+    // 25 кадров в секунду по 40 тиков: тик -- миллисекунда
+    let track = [
+      0x00, 0xFF, 0x51, 0x03, 0x03, 0xD0, 0x90,
+      0x64, 0x90, 60, 100,
+      0x81, 0x48, 0x80, 60, 0,
+    ];
+    let file = MidiFile::parse(&smf(0, [(-25i8) as u8, 40], &[&track])).unwrap();
+    let times: Vec<u64> = times_and_bytes(file).iter().map(|(time, _)| *time).collect();
+    assert_eq!(times, vec![100_000, 300_000]);
+  }
+
+  #[test]
+  fn tracks_merge_by_time_then_track_order() {
+    // 60 BPM с нулевой дорожки действует на ноты в тот же тик на остальных
+    let tempo = [0x00, 0xFF, 0x51, 0x03, 0x0F, 0x42, 0x40];
+    let first = [0x00, 0x90, 60, 100, 0x60, 0x80, 60, 0];
+    let second = [0x00, 0x91, 64, 100, 0x30, 0x81, 64, 0];
+    let file = MidiFile::parse(&smf(1, [0, 96], &[&tempo, &first, &second])).unwrap();
+    assert_eq!(
+      times_and_bytes(file),
+      vec![
+        (0, vec![0x90, 60, 100]),
+        (0, vec![0x91, 64, 100]),
+        (500_000, vec![0x81, 64, 0]),
+        (1_000_000, vec![0x80, 60, 0]),
+      ],
+    );
+  }
+
+  #[test]
+  fn format_2_is_rejected() {
+    let track = [0x00, 0x90, 60, 100];
+    let error = MidiFile::parse(&smf(2, [0, 96], &[&track, &track])).err().unwrap();
+    assert!(error.to_string().contains("format 2"));
+  }
+
+  #[test]
+  fn garbage_is_rejected() {
+    assert!(MidiFile::parse(b"not a midi file").is_err());
+  }
+}
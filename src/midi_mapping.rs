@@ -0,0 +1,443 @@
+//! Профили привязки MIDI CC к параметрам.
+//!
+//! Профиль описывает, какой CC двигает какую ручку, в каком диапазоне и с какой
+//! кривой. Профили лежат в TOML-файле, нужный выбирается при запуске, так что
+//! под свой контроллер ничего перекомпилировать не нужно. Без файла работает
+//! встроенный профиль `default`. Привязки из MIDI learn дописываются в тот же файл.
+//!
+//! ```toml
+//! [[profile]]
+//! name = "minilab"
+//!
+//! [[profile.cc]]
+//! cc = 74
+//! param = "lpf_cutoff"
+//! lsb = 106        # необязательно: младший байт 14-битной пары
+//! min = 200.0      # в единицах параметра, по умолчанию весь диапазон
+//! max = 8000.0
+//! invert = false
+//! curve = "exponential"
+//! ```
+
+use std::error::Error;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::params::{ParamId, ParamStore, RAW_MAX};
+use crate::synth_state::SynthState;
+
+/// Как положение контроллера раскладывается по диапазону привязки
+#[derive(Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MappingCurve {
+  #[default]
+  Linear,
+  /// Точнее в начале хода: t^2
+  Exponential,
+  /// Точнее в конце хода: sqrt(t)
+  Logarithmic,
+}
+
+impl MappingCurve {
+  fn apply(self, t: f32) -> f32 {
+    match self {
+      MappingCurve::Linear => t,
+      MappingCurve::Exponential => t * t,
+      MappingCurve::Logarithmic => t.sqrt(),
+    }
+  }
+
+  fn inverse(self, t: f32) -> f32 {
+    match self {
+      MappingCurve::Linear => t,
+      MappingCurve::Exponential => t.sqrt(),
+      MappingCurve::Logarithmic => t * t,
+    }
+  }
+}
+
+/// Одна привязка в файле. Параметр указывается по ключу из `params::PARAMS`.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct CcMapping {
+  pub cc: u8,
+  pub param: String,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub lsb: Option<u8>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub min: Option<f32>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub max: Option<f32>,
+  #[serde(default, skip_serializing_if = "is_false")]
+  pub invert: bool,
+  #[serde(default, skip_serializing_if = "is_linear")]
+  pub curve: MappingCurve,
+}
+
+fn is_false(value: &bool) -> bool {
+  !*value
+}
+
+fn is_linear(curve: &MappingCurve) -> bool {
+  *curve == MappingCurve::Linear
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct MappingProfile {
+  pub name: String,
+  #[serde(default)]
+  pub cc: Vec<CcMapping>,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct MappingFile {
+  #[serde(default)]
+  profile: Vec<MappingProfile>,
+}
+
+/// Привязки встроенного профиля: CC и ключ параметра
+const DEFAULT_CC: &[(u8, ParamId)] = &[
+  (5, ParamId::ChorusLfoFreq),
+  (33, ParamId::Volume),
+  (34, ParamId::LpfResonance),
+  (35, ParamId::LpfCutoff),
+  (36, ParamId::DelayMix),
+  (37, ParamId::DelayFeedback),
+  (39, ParamId::ReverbDecayTime),
+  (40, ParamId::ReverbMix),
+  (41, ParamId::GlideTime),
+  (44, ParamId::GateAttack),
+  (45, ParamId::GateDecay),
+  (46, ParamId::GateRelease),
+  (47, ParamId::GateSustain),
+  (48, ParamId::PhaserStages),
+  (49, ParamId::PhaserLfoRate),
+  (50, ParamId::PhaserDepth),
+  (51, ParamId::PhaserFeedback),
+  (52, ParamId::PhaserStereoOffset),
+  (53, ParamId::PhaserMix),
+  (54, ParamId::VelocityCurve),
+  (55, ParamId::VelocityToAmp),
+  (56, ParamId::VelocityToCutoff),
+  (57, ParamId::VelocityToEnv),
+  (58, ParamId::Env1Delay),
+  (59, ParamId::Env1Attack),
+  (60, ParamId::Env1Hold),
+  (61, ParamId::Env1Decay),
+  (62, ParamId::Env1Sustain),
+  (63, ParamId::Env1Release),
+  (75, ParamId::Mod1Amount),
+  (76, ParamId::Mod2Amount),
+  (77, ParamId::Mod3Amount),
+  (78, ParamId::Env1Mode),
+  (102, ParamId::Lfo1Shape),
+  (103, ParamId::Lfo1Rate),
+  (104, ParamId::Lfo1Division),
+  (105, ParamId::Lfo1Fade),
+  (106, ParamId::Lfo1Phase),
+  (107, ParamId::Lfo1Retrigger),
+  (108, ParamId::Lfo1Depth),
+  (109, ParamId::VibratoDepth),
+  // CC 38 -- младший байт Data Entry, его шлёт каждая точная правка RPN/NRPN
+  (110, ParamId::DelayTime),
+];
+
+impl CcMapping {
+  pub fn new(cc: u8, id: ParamId) -> Self {
+    Self {
+      cc,
+      param: id.info().key.to_string(),
+      lsb: None,
+      min: None,
+      max: None,
+      invert: false,
+      curve: MappingCurve::Linear,
+    }
+  }
+}
+
+impl Default for MappingProfile {
+  fn default() -> Self {
+    // 14-битных пар здесь нет: свободных CC 0..31 с незанятым младшим байтом не осталось,
+    // пары задаются в файле профиля (`lsb`)
+    Self {
+      name: "default".to_string(),
+      cc: DEFAULT_CC.iter().map(|&(cc, id)| CcMapping::new(cc, id)).collect(),
+    }
+  }
+}
+
+impl MappingProfile {
+  /// Читает профиль `name` из файла, без имени берёт первый
+  pub fn load(path: &Path, name: Option<&str>) -> Result<Self, Box<dyn Error>> {
+    let text = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    let file: MappingFile = toml::from_str(&text).map_err(|e| format!("{}: {}", path.display(), e))?;
+    let profile = match name {
+      Some(name) => file.profile.into_iter().find(|p| p.name == name),
+      None => file.profile.into_iter().next(),
+    };
+    let profile = profile.ok_or_else(|| match name {
+      Some(name) => format!("{}: no profile named '{}'", path.display(), name),
+      None => format!("{}: no profiles", path.display()),
+    })?;
+    profile.resolve()?;
+    Ok(profile)
+  }
+
+  /// Привязывает `cc` к параметру: старые привязки этого CC и этого параметра убираются
+  pub fn bind(&mut self, cc: u8, id: ParamId) {
+    let key = id.info().key;
+    self.cc.retain(|m| m.cc != cc && m.lsb != Some(cc) && m.param != key);
+    self.cc.push(CcMapping::new(cc, id));
+  }
+
+  /// Записывает профиль в файл: профиль с тем же именем заменяется, остальные
+  /// остаются как были. Комментарии в файле при этом теряются.
+  pub fn save(&self, path: &Path) -> Result<(), Box<dyn Error>> {
+    let mut file = if path.exists() {
+      let text = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+      toml::from_str(&text).map_err(|e| format!("{}: {}", path.display(), e))?
+    } else {
+      MappingFile::default()
+    };
+    match file.profile.iter_mut().find(|p| p.name == self.name) {
+      Some(profile) => *profile = self.clone(),
+      None => file.profile.push(self.clone()),
+    }
+    let text = toml::to_string_pretty(&file)?;
+    std::fs::write(path, text).map_err(|e| format!("{}: {}", path.display(), e))?;
+    Ok(())
+  }
+
+  /// Проверяет ключи параметров и диапазоны, возвращает готовые к работе привязки
+  pub fn resolve(&self) -> Result<Vec<ResolvedMapping>, Box<dyn Error>> {
+    self
+      .cc
+      .iter()
+      .map(|m| {
+        let id = ParamId::from_key(&m.param)
+          .ok_or_else(|| format!("profile '{}': unknown parameter '{}' on CC {}", self.name, m.param, m.cc))?;
+        if m.cc > 127 || m.lsb.is_some_and(|lsb| lsb > 127) {
+          return Err(format!("profile '{}': CC number out of range for '{}'", self.name, m.param).into());
+        }
+        let info = id.info();
+        Ok(ResolvedMapping {
+          cc: m.cc,
+          lsb: m.lsb,
+          id,
+          from: info.normalize(m.min.unwrap_or(info.min)),
+          to: info.normalize(m.max.unwrap_or(info.max)),
+          invert: m.invert,
+          curve: m.curve,
+        })
+      })
+      .collect()
+  }
+}
+
+/// Профиль вместе с файлом, куда сохранять привязки из MIDI learn
+pub struct MappingConfig {
+  pub profile: MappingProfile,
+  pub path: PathBuf,
+}
+
+/// Привязка с найденным параметром и диапазоном в положениях ручки (0..1)
+pub struct ResolvedMapping {
+  pub cc: u8,
+  pub lsb: Option<u8>,
+  pub id: ParamId,
+  from: f32,
+  to: f32,
+  invert: bool,
+  curve: MappingCurve,
+}
+
+impl ResolvedMapping {
+  /// Положение параметра по 14-битному положению контроллера
+  pub fn param_raw(&self, raw: u16) -> u16 {
+    let mut t = raw.min(RAW_MAX) as f32 / RAW_MAX as f32;
+    if self.invert {
+      t = 1.0 - t;
+    }
+    let t = self.curve.apply(t);
+    ParamStore::to_raw(self.from + (self.to - self.from) * t)
+  }
+
+  /// Обратно к `param_raw`: 14-битное положение контроллера для текущего значения параметра
+  pub fn position(&self, state: &SynthState) -> u16 {
+    let span = self.to - self.from;
+    let t = if span == 0.0 { 0.0 } else { ((state.params.normalized(self.id) - self.from) / span).clamp(0.0, 1.0) };
+    let mut t = self.curve.inverse(t);
+    if self.invert {
+      t = 1.0 - t;
+    }
+    (t * RAW_MAX as f32).round() as u16
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// Файл во временном каталоге, свой для каждого теста
+  fn temp_file(name: &str, text: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("delta-synth-{}-{}.toml", name, std::process::id()));
+    std::fs::write(&path, text).unwrap();
+    path
+  }
+
+  fn profile(cc: Vec<CcMapping>) -> MappingProfile {
+    MappingProfile { name: "test".to_string(), cc }
+  }
+
+  fn resolve_err(profile: &MappingProfile) -> String {
+    match profile.resolve() {
+      Err(e) => e.to_string(),
+      Ok(_) => panic!("profile resolved"),
+    }
+  }
+
+  const TWO_PROFILES: &str = r#"
+[[profile]]
+name = "first"
+
+[[profile.cc]]
+cc = 20
+param = "lpf_cutoff"
+
+[[profile]]
+name = "minilab"
+
+[[profile.cc]]
+cc = 74
+param = "lpf_cutoff"
+lsb = 106
+min = 200.0
+max = 8000.0
+invert = true
+curve = "exponential"
+"#;
+
+  #[test]
+  fn load_picks_profile_by_name_or_first() {
+    let path = temp_file("load", TWO_PROFILES);
+    let minilab = MappingProfile::load(&path, Some("minilab")).unwrap();
+    let first = MappingProfile::load(&path, None).unwrap();
+    let missing = MappingProfile::load(&path, Some("nope")).err().map(|e| e.to_string());
+    std::fs::remove_file(&path).unwrap();
+
+    assert_eq!(minilab.name, "minilab");
+    let [mapping] = &minilab.cc[..] else { panic!("expected one mapping") };
+    assert_eq!((mapping.cc, mapping.lsb, mapping.min, mapping.max), (74, Some(106), Some(200.0), Some(8000.0)));
+    assert!(mapping.invert);
+    assert!(mapping.curve == MappingCurve::Exponential);
+    assert_eq!(first.name, "first");
+    assert!(missing.unwrap().contains("no profile named 'nope'"));
+  }
+
+  #[test]
+  fn load_rejects_bad_profile() {
+    let path = temp_file("bad", "[[profile]]\nname = \"x\"\n[[profile.cc]]\ncc = 1\nparam = \"no_such_param\"\n");
+    let error = MappingProfile::load(&path, None).err().map(|e| e.to_string());
+    std::fs::remove_file(&path).unwrap();
+    assert!(error.unwrap().contains("unknown parameter 'no_such_param'"));
+  }
+
+  #[test]
+  fn resolve_rejects_unknown_key_and_cc_out_of_range() {
+    let mut unknown = CcMapping::new(1, ParamId::LpfCutoff);
+    unknown.param = "cutoff".to_string();
+    assert!(resolve_err(&profile(vec![unknown])).contains("unknown parameter 'cutoff' on CC 1"));
+
+    assert!(resolve_err(&profile(vec![CcMapping::new(128, ParamId::LpfCutoff)])).contains("out of range"));
+    let lsb = CcMapping { lsb: Some(200), ..CcMapping::new(10, ParamId::LpfCutoff) };
+    assert!(resolve_err(&profile(vec![lsb])).contains("out of range"));
+  }
+
+  #[test]
+  fn default_profile_resolves() {
+    let resolved = MappingProfile::default().resolve().unwrap();
+    assert!(resolved.iter().any(|m| m.cc == 35 && m.id == ParamId::LpfCutoff));
+    // Pan и Expression шлёт любая DAW при сбросе контроллеров, они не должны трогать звук
+    assert!(resolved.iter().all(|m| m.cc != 10 && m.cc != 11 && m.lsb.is_none()));
+    // Data Entry идёт с каждой правкой RPN/NRPN
+    assert!(resolved.iter().all(|m| m.cc != 6 && m.cc != 38));
+  }
+
+  #[test]
+  fn position_inverts_param_raw() {
+    let state = SynthState::new();
+    let info = ParamId::LpfCutoff.info();
+    let variants = [
+      CcMapping::new(74, ParamId::LpfCutoff),
+      CcMapping { invert: true, ..CcMapping::new(74, ParamId::LpfCutoff) },
+      CcMapping { min: Some(200.0), max: Some(8000.0), ..CcMapping::new(74, ParamId::LpfCutoff) },
+      CcMapping { min: Some(info.max), max: Some(info.min), ..CcMapping::new(74, ParamId::LpfCutoff) },
+      CcMapping { curve: MappingCurve::Exponential, ..CcMapping::new(74, ParamId::LpfCutoff) },
+      CcMapping { curve: MappingCurve::Logarithmic, invert: true, ..CcMapping::new(74, ParamId::LpfCutoff) },
+    ];
+    for mapping in variants {
+      let [resolved] = &profile(vec![mapping]).resolve().unwrap()[..] else { panic!() };
+      for raw in [0, 1000, RAW_MAX / 2, 12000, RAW_MAX] {
+        state.params.set_raw(ParamId::LpfCutoff, resolved.param_raw(raw));
+        let back = resolved.position(&state) as i32;
+        // параметр тоже хранится в 14 битах, после кривой положение может уйти на шаг
+        assert!((back - raw as i32).abs() <= 2, "{} -> {}", raw, back);
+      }
+    }
+  }
+
+  #[test]
+  fn param_raw_follows_range_invert_and_curve() {
+    let info = ParamId::LpfCutoff.info();
+    let resolve = |mapping: CcMapping| profile(vec![mapping]).resolve().unwrap().remove(0);
+    let value = |raw: u16| info.denormalize(raw as f32 / RAW_MAX as f32);
+
+    let ranged = resolve(CcMapping { min: Some(200.0), max: Some(8000.0), ..CcMapping::new(1, ParamId::LpfCutoff) });
+    assert!((value(ranged.param_raw(0)) - 200.0).abs() < 1.0);
+    assert!((value(ranged.param_raw(RAW_MAX)) - 8000.0).abs() < 5.0);
+
+    let inverted = resolve(CcMapping { invert: true, ..CcMapping::new(1, ParamId::LpfCutoff) });
+    assert_eq!(inverted.param_raw(0), RAW_MAX);
+    assert_eq!(inverted.param_raw(RAW_MAX), 0);
+
+    let exponential = resolve(CcMapping { curve: MappingCurve::Exponential, ..CcMapping::new(1, ParamId::LpfCutoff) });
+    let logarithmic = resolve(CcMapping { curve: MappingCurve::Logarithmic, ..CcMapping::new(1, ParamId::LpfCutoff) });
+    // середина хода: t^2 -- четверть диапазона, sqrt(t) от четверти -- половина
+    assert!(exponential.param_raw(RAW_MAX / 2).abs_diff(RAW_MAX / 4) <= 1);
+    assert!(logarithmic.param_raw(RAW_MAX / 4).abs_diff(RAW_MAX / 2) <= 1);
+    assert_eq!(exponential.param_raw(RAW_MAX), RAW_MAX);
+  }
+
+  #[test]
+  fn bind_replaces_old_bindings() {
+    let mut profile = profile(vec![
+      CcMapping::new(20, ParamId::LpfCutoff),
+      CcMapping::new(21, ParamId::LpfResonance),
+      CcMapping { lsb: Some(42), ..CcMapping::new(10, ParamId::Volume) },
+      CcMapping::new(22, ParamId::ReverbMix),
+    ]);
+    // CC 21 занят резонансом, срез привязан к CC 20: обе старые привязки уходят
+    profile.bind(21, ParamId::LpfCutoff);
+    // младший байт пары тоже считается занятым
+    profile.bind(42, ParamId::DelayMix);
+    let bound: Vec<(u8, &str)> = profile.cc.iter().map(|m| (m.cc, m.param.as_str())).collect();
+    assert_eq!(bound, vec![(22, "reverb_mix"), (21, "lpf_cutoff"), (42, "delay_mix")]);
+  }
+
+  #[test]
+  fn save_replaces_only_its_profile() {
+    let path = temp_file("save", TWO_PROFILES);
+    let mut minilab = MappingProfile::load(&path, Some("minilab")).unwrap();
+    minilab.bind(75, ParamId::ReverbMix);
+    minilab.save(&path).unwrap();
+    let saved = MappingProfile::load(&path, Some("minilab")).unwrap();
+    let first = MappingProfile::load(&path, Some("first")).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    let bound: Vec<(u8, &str)> = saved.cc.iter().map(|m| (m.cc, m.param.as_str())).collect();
+    assert_eq!(bound, vec![(74, "lpf_cutoff"), (75, "reverb_mix")]);
+    assert!(saved.cc[0].invert && saved.cc[0].curve == MappingCurve::Exponential);
+    assert_eq!(first.cc.len(), 1);
+  }
+}
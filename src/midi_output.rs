@@ -0,0 +1,138 @@
+//! MIDI-выход: MIDI thru, ответы на SysEx и обратная связь CC.
+//!
+//! Обратная связь нужна контроллерам с моторными фейдерами и светящимися кольцами:
+//! когда параметры меняются не с самого контроллера (пресет, SysEx-дамп, NRPN),
+//! синтезатор отправляет текущие положения привязанных ручек. Движения самих
+//! привязанных CC обратно не отправляются, иначе фейдер спорил бы с рукой.
+
+use std::error::Error;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use midir::{MidiOutput, MidiOutputConnection};
+
+use crate::midi_mapping::ResolvedMapping;
+use crate::midi_service::find_port;
+use crate::synth_state::Part;
+
+// как часто проверяем, не поменялись ли параметры
+const FEEDBACK_INTERVAL: Duration = Duration::from_millis(40);
+/// Имена клиента и порта MIDI-выхода в ALSA
+pub const OUTPUT_CLIENT_NAME: &str = "delta-synth output";
+pub const OUTPUT_PORT_NAME: &str = "delta-synth-out";
+
+/// Куда и что отправлять: `--midi-out <порт> [--midi-thru]`
+pub struct MidiOutputConfig {
+  /// Номер порта или часть его имени
+  pub port: String,
+  pub thru: bool,
+}
+
+/// Соединение с выходом, общее для MIDI-потока и потока обратной связи
+#[derive(Clone)]
+pub struct MidiOut {
+  connection: Arc<Mutex<MidiOutputConnection>>,
+  pub thru: bool,
+}
+
+impl MidiOut {
+  pub fn connect(config: &MidiOutputConfig) -> Result<Self, Box<dyn Error>> {
+    let midi_out = MidiOutput::new(OUTPUT_CLIENT_NAME)?;
+    let ports = midi_out.ports();
+    let names: Vec<String> = ports.iter().map(|p| midi_out.port_name(p).unwrap_or_default()).collect();
+    let index = find_port(&names, &config.port).ok_or_else(|| format!("no MIDI output port matching '{}'", config.port))?;
+    println!("MIDI output: {}", names[index]);
+    let connection = midi_out.connect(&ports[index], OUTPUT_PORT_NAME).map_err(|e| e.to_string())?;
+    Ok(Self {
+      connection: Arc::new(Mutex::new(connection)),
+      thru: config.thru,
+    })
+  }
+
+  pub fn send(&self, message: &[u8]) {
+    if let Err(e) = self.connection.lock().unwrap().send(message) {
+      println!("MIDI output: {}", e);
+    }
+  }
+
+  /// Раз в `FEEDBACK_INTERVAL` отправляет CC привязанных параметров, которые изменились.
+  /// Часть в omni отвечает на первом канале.
+  pub fn spawn_feedback(&self, parts: Vec<Part>, mappings: Arc<Mutex<Vec<ResolvedMapping>>>) {
+    let out = self.clone();
+    thread::spawn(move || {
+      // последнее отправленное положение по части и номеру CC
+      let mut sent = vec![[None::<u16>; 128]; parts.len()];
+      loop {
+        thread::sleep(FEEDBACK_INTERVAL);
+        let mappings = mappings.lock().unwrap();
+        for (part, sent) in parts.iter().zip(sent.iter_mut()) {
+          for message in feedback_messages(part, &mappings, sent) {
+            out.send(&message);
+          }
+        }
+      }
+    });
+  }
+}
+
+/// CC для привязанных параметров части, которые изменились с прошлой отправки
+fn feedback_messages(part: &Part, mappings: &[ResolvedMapping], sent: &mut [Option<u16>; 128]) -> Vec<[u8; 3]> {
+  let status = 0xB0 | part.channel.unwrap_or(0);
+  let mut messages = Vec::new();
+  for mapping in mappings {
+    if part.state.set_by_controller(mapping.id) {
+      // контроллер сам стоит там, где его оставили; что он показывает, мы не знаем
+      sent[mapping.cc as usize] = None;
+      continue;
+    }
+    let position = mapping.position(&part.state);
+    // 7-битная ручка видит только старший байт, лишние сообщения ей ни к чему
+    let shown = if mapping.lsb.is_some() { position } else { position >> 7 };
+    if sent[mapping.cc as usize] == Some(shown) {
+      continue;
+    }
+    sent[mapping.cc as usize] = Some(shown);
+    messages.push([status, mapping.cc, (position >> 7) as u8]);
+    if let Some(lsb) = mapping.lsb {
+      messages.push([status, lsb, (position & 0x7F) as u8]);
+    }
+  }
+  messages
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::midi_events::{MidiEvent, NotePlayer, FROM_CONTROLLER, PARAM_CHANGE};
+  use crate::midi_mapping::{CcMapping, MappingProfile};
+  use crate::params::ParamId;
+  use crate::presets::PatchBank;
+  use crate::synth_state::SynthState;
+
+  fn set_param(part: &Part, id: ParamId, raw: u16, source: u8) {
+    let event = MidiEvent { time_us: 0, status: PARAM_CHANGE, channel: 0, data1: id as u8, data2: source, value: raw };
+    NotePlayer::new(Arc::new(PatchBank::default())).apply(&part.state, &event);
+  }
+
+  #[test]
+  fn controller_moves_are_not_echoed() {
+    let part = Part { channel: Some(2), state: SynthState::new() };
+    let cc = 35;
+    let profile = MappingProfile { name: "test".to_string(), cc: vec![CcMapping::new(cc, ParamId::LpfCutoff)] };
+    let mappings = profile.resolve().unwrap();
+    let mut sent = [None; 128];
+    feedback_messages(&part, &mappings, &mut sent);
+
+    set_param(&part, ParamId::LpfCutoff, 5000, FROM_CONTROLLER);
+    assert!(feedback_messages(&part, &mappings, &mut sent).is_empty());
+
+    // тот же параметр с NRPN или из пресета уходит на контроллер
+    set_param(&part, ParamId::LpfCutoff, 9000, 0);
+    assert_eq!(feedback_messages(&part, &mappings, &mut sent), vec![[0xB2, cc, (9000 >> 7) as u8]]);
+    assert!(feedback_messages(&part, &mappings, &mut sent).is_empty());
+
+    part.state.params.set_raw(ParamId::LpfCutoff, 100);
+    assert_eq!(feedback_messages(&part, &mappings, &mut sent), vec![[0xB2, cc, 0]]);
+  }
+}
@@ -1,146 +1,1124 @@
 use std::error::Error;
-use std::io::{stdin, stdout, Write};
+use std::thread;
+use std::time::Duration;
 
-use std::sync::Arc;
-use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
 
 use midir::{Ignore, MidiInput, MidiInputConnection};
+use rtrb::Producer;
+#[cfg(unix)]
+use midir::os::unix::VirtualInput;
 
-use crate::synth_state::SynthState;
+use crate::midi_mapping::{MappingConfig, ResolvedMapping};
+use crate::midi_output::{MidiOut, OUTPUT_CLIENT_NAME, OUTPUT_PORT_NAME};
+use crate::params::{cc_to_raw, ParamId, ParamStore, PARAM_COUNT, RAW_MAX};
+use crate::presets::PatchBank;
+use crate::sysex::{self, ALL_DEVICES, SYSEX_START};
+use crate::midi_events::{MidiEvent, Timeline, CC_BREATH, CC_MOD_WHEEL, CC_SOSTENUTO, CC_SUSTAIN, CC_TIMBRE, DUMP_END, DUMP_PARAM, FROM_CONTROLLER, MPE_CONFIGURATION, PARAM_CHANGE};
+use crate::synth_state::{Part, SynthState, CLOCK_PPQN, MAX_TEMPO_BPM, MIN_TEMPO_BPM, MPE_MAX_MEMBERS};
 
-pub fn initiate_midi_connection(synth_state: Arc<SynthState>) -> Result<MidiInputConnection<()>, Box<dyn Error>> {
-  let mut input = String::new();
+/// Имя виртуального входа в ALSA, оно же имя клиента входов
+const VIRTUAL_PORT_NAME: &str = "delta-synth";
+/// Имя порта, через который клиент входов подключается к контроллеру
+const INPUT_PORT_NAME: &str = "delta-synth-in";
+/// Как часто проверяем, не отключили ли и не подключили ли контроллер
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(1);
 
-  let mut midi_in = MidiInput::new("midir reading input")?;
-  midi_in.ignore(Ignore::None);
+const CC_BANK_SELECT: u8 = 0;
+const CC_BANK_SELECT_LSB: u8 = 32;
+const CC_DATA_ENTRY: u8 = 6;
+const CC_DATA_ENTRY_LSB: u8 = 38;
+const CC_DATA_INCREMENT: u8 = 96;
+const CC_DATA_DECREMENT: u8 = 97;
+const CC_NRPN_LSB: u8 = 98;
+const CC_NRPN_MSB: u8 = 99;
+const CC_RPN_LSB: u8 = 100;
+const CC_RPN_MSB: u8 = 101;
+/// RPN 127/127 снимает выбор, после него CC 6/38 снова обычные
+const RPN_NULL: u16 = 0x3FFF;
+/// RPN 0: диапазон колеса высоты тона, MSB -- полутоны, LSB -- центы
+const RPN_PITCH_BEND_RANGE: u16 = 0;
+/// RPN 6 на первом канале: MPE Configuration Message, MSB -- число каналов нот
+const RPN_MPE_CONFIGURATION: u16 = 6;
 
-  // Get an input port (read from console if multiple are available)
-  let in_ports = midi_in.ports();
-  let in_port = match in_ports.len() {
-    0 => return Err("no input port found".into()),
-    1 => {
-      println!(
-        "Choosing the only available input port: {}",
-        midi_in.port_name(&in_ports[0]).unwrap()
-      );
-      &in_ports[0]
-    },
-    _ => {
-      println!("\nAvailable input ports:");
-      for (i, p) in in_ports.iter().enumerate() {
-        println!("{}: {}", i, midi_in.port_name(p).unwrap());
+/// Какой NRPN/RPN сейчас выбран для Data Entry
+#[derive(Clone, Copy, PartialEq)]
+enum ParamNumber {
+  None,
+  Rpn(u16),
+  /// Номер NRPN -- номер параметра в `params::PARAMS`
+  Nrpn(u16),
+}
+
+/// Привязки CC, общие для всех частей, вместе с MIDI learn
+struct MidiMappings {
+  config: MappingConfig,
+  /// Общие с потоком обратной связи CC
+  resolved: Arc<Mutex<Vec<ResolvedMapping>>>,
+  /// Чей `SynthState` держит ждущий MIDI learn: консоль взводит его у первой части
+  learn_state: Arc<SynthState>,
+}
+
+impl MidiMappings {
+  /// Привязывает CC к параметру, который ждал MIDI learn, и сохраняет профиль
+  fn learn(&mut self, cc: u8, id: ParamId) {
+    self.config.profile.bind(cc, id);
+    if let Ok(resolved) = self.config.profile.resolve() {
+      *self.resolved.lock().unwrap() = resolved;
+    }
+    println!("MIDI learn: CC {} -> {}", cc, id.info().name);
+    match self.config.profile.save(&self.config.path) {
+      Ok(()) => println!("MIDI learn: saved to {}", self.config.path.display()),
+      Err(e) => println!("MIDI learn: could not save mapping: {}", e),
+    }
+  }
+}
+
+/// Очередь части в аудиопоток. Если аудиопоток не успевает её разбирать, события
+/// теряются; об этом пишем один раз, а не на каждое событие.
+pub struct PartEvents {
+  producer: Producer<MidiEvent>,
+  dropped: u32,
+}
+
+impl PartEvents {
+  pub fn new(producer: Producer<MidiEvent>) -> Self {
+    Self { producer, dropped: 0 }
+  }
+
+  fn push(&mut self, event: MidiEvent) {
+    if self.producer.push(event).is_err() {
+      self.drop_message();
+    } else {
+      self.report_dropped();
+    }
+  }
+
+  /// Кладёт события одним куском: если все не помещаются, не кладёт ни одного
+  fn push_all(&mut self, events: &[MidiEvent]) {
+    if self.producer.slots() < events.len() {
+      self.drop_message();
+      return;
+    }
+    for &event in events {
+      let _ = self.producer.push(event);
+    }
+    self.report_dropped();
+  }
+
+  fn drop_message(&mut self) {
+    if self.dropped == 0 {
+      println!("MIDI event queue is full, dropping messages");
+    }
+    self.dropped += 1;
+  }
+
+  fn report_dropped(&mut self) {
+    if self.dropped > 0 {
+      println!("MIDI event queue: {} messages dropped", self.dropped);
+      self.dropped = 0;
+    }
+  }
+}
+
+/// Очередь части и время сообщения, которое сейчас разбирается
+pub struct EventQueue<'a> {
+  events: &'a mut PartEvents,
+  time_us: u64,
+  channel: u8,
+  /// Печатать новые значения параметров, режим MPE и пропущенные RPN (`--verbose`)
+  verbose: bool,
+}
+
+impl<'a> EventQueue<'a> {
+  pub fn new(events: &'a mut PartEvents, time_us: u64, channel: u8, verbose: bool) -> Self {
+    Self { events, time_us, channel, verbose }
+  }
+
+  fn event(&self, status: u8, data1: u8, data2: u8, value: u16) -> MidiEvent {
+    MidiEvent { time_us: self.time_us, status, channel: self.channel, data1, data2, value }
+  }
+
+  fn push(&mut self, status: u8, data1: u8, data2: u8, value: u16) {
+    self.events.push(self.event(status, data1, data2, value));
+  }
+
+  /// SysEx-дамп: положения параметров по номерам. Уходит целиком, чтобы не смешать
+  /// половину дампа со старыми значениями, и встаёт по порядку с Program Change и ручками.
+  pub fn dump(&mut self, raw: &[u16]) {
+    let mut events: Vec<MidiEvent> =
+      raw.iter().take(PARAM_COUNT).enumerate().map(|(i, &value)| self.event(DUMP_PARAM, i as u8, 0, value)).collect();
+    events.push(self.event(DUMP_END, 0, 0, 0));
+    self.events.push_all(&events);
+  }
+
+  pub fn set_param(&mut self, id: ParamId, raw: u16) {
+    self.push(PARAM_CHANGE, id as u8, 0, raw);
+    self.print_param(id, raw);
+  }
+
+  /// Положение с привязанного CC: обратная связь не вернёт его контроллеру
+  fn set_param_from_cc(&mut self, id: ParamId, raw: u16) {
+    self.push(PARAM_CHANGE, id as u8, FROM_CONTROLLER, raw);
+    self.print_param(id, raw);
+  }
+
+  fn print_param(&self, id: ParamId, raw: u16) {
+    if self.verbose {
+      let info = id.info();
+      println!("{}: {}", info.name, info.format(info.denormalize(raw as f32 / RAW_MAX as f32)));
+    }
+  }
+}
+
+/// Разбор CC одного канала одной части, которому нужно помнить предыдущие сообщения:
+/// старшие байты 14-битных пар и выбранный NRPN/RPN.
+struct ControllerDecoder {
+  channel: u8,
+  msb: [u8; 128],
+  nrpn: [u8; 2],
+  rpn: [u8; 2],
+  selected: ParamNumber,
+  data: u16,
+}
+
+impl ControllerDecoder {
+  fn new(channel: u8) -> Self {
+    Self {
+      channel,
+      msb: [0; 128],
+      nrpn: [0; 2],
+      rpn: [0x7F; 2],
+      selected: ParamNumber::None,
+      data: 0,
+    }
+  }
+
+  fn control_change(&mut self, state: &SynthState, mappings: &mut MidiMappings, queue: &mut EventQueue, cc: u8, value: u8) {
+    match cc {
+      CC_NRPN_MSB | CC_NRPN_LSB => {
+        self.nrpn[(cc - CC_NRPN_LSB) as usize] = value;
+        self.selected = ParamNumber::Nrpn(((self.nrpn[1] as u16) << 7) | self.nrpn[0] as u16);
+      },
+      CC_RPN_MSB | CC_RPN_LSB => {
+        self.rpn[(cc - CC_RPN_LSB) as usize] = value;
+        let number = ((self.rpn[1] as u16) << 7) | self.rpn[0] as u16;
+        self.selected = if number == RPN_NULL { ParamNumber::None } else { ParamNumber::Rpn(number) };
+      },
+      CC_DATA_ENTRY if self.selected != ParamNumber::None => {
+        // новый старший байт сбрасывает младший
+        self.data = (value as u16) << 7;
+        self.apply_data(state, queue);
+      },
+      CC_DATA_ENTRY_LSB if self.selected != ParamNumber::None => {
+        self.data = (self.data & !0x7F) | value as u16;
+        self.apply_data(state, queue);
+      },
+      CC_DATA_INCREMENT | CC_DATA_DECREMENT if self.selected != ParamNumber::None => {
+        // шаг -- одна ступень старшего байта, как у 7-битной ручки
+        if let ParamNumber::Nrpn(number) = self.selected {
+          if let Some(id) = ParamId::from_index(number as usize) {
+            self.data = state.params.raw(id);
+          }
+        }
+        self.data = if cc == CC_DATA_INCREMENT {
+          (self.data + 0x80).min(RAW_MAX)
+        } else {
+          self.data.saturating_sub(0x80)
+        };
+        self.apply_data(state, queue);
+      },
+      _ => {
+        if cc == CC_MOD_WHEEL || cc == CC_BREATH {
+          queue.push(0xB0, cc, value, 0);
+        }
+        if let Some(id) = mappings.learn_state.take_midi_learn() {
+          mappings.learn(cc, id);
+        }
+        for mapping in mappings.resolved.lock().unwrap().iter() {
+          if mapping.cc == cc {
+            // у 14-битной пары новый старший байт сбрасывает младший
+            let raw = if mapping.lsb.is_some() { (value as u16) << 7 } else { cc_to_raw(value) };
+            queue.set_param_from_cc(mapping.id, mapping.param_raw(raw));
+          } else if mapping.lsb == Some(cc) {
+            let raw = ((self.msb[mapping.cc as usize] as u16) << 7) | value as u16;
+            queue.set_param_from_cc(mapping.id, mapping.param_raw(raw));
+          }
+        }
+        self.msb[cc as usize] = value;
+      },
+    }
+  }
+
+  fn apply_data(&self, state: &SynthState, queue: &mut EventQueue) {
+    match self.selected {
+      ParamNumber::Nrpn(number) => {
+        if let Some(id) = ParamId::from_index(number as usize) {
+          queue.set_param(id, self.data.min(RAW_MAX));
+        }
+      },
+      // в MPE диапазон, присланный на канал нот, действует на все ноты
+      ParamNumber::Rpn(RPN_PITCH_BEND_RANGE) if state.is_mpe_member(self.channel) => {
+        queue.set_param(ParamId::MpeBendRange, self.bend_range_raw(ParamId::MpeBendRange));
+      },
+      ParamNumber::Rpn(RPN_PITCH_BEND_RANGE) => {
+        queue.set_param(ParamId::BendRangeUp, self.bend_range_raw(ParamId::BendRangeUp));
+        queue.set_param(ParamId::BendRangeDown, self.bend_range_raw(ParamId::BendRangeDown));
+      },
+      ParamNumber::Rpn(RPN_MPE_CONFIGURATION) if self.channel == 0 => {
+        let members = ((self.data >> 7) as u8).min(MPE_MAX_MEMBERS);
+        queue.push(MPE_CONFIGURATION, members, 0, 0);
+        // приходит на каждый байт Data Entry, печатаем только с --verbose
+        if queue.verbose {
+          if members == 0 {
+            println!("MPE: off");
+          } else {
+            println!("MPE: lower zone, {} note channels", members);
+          }
+        }
+      },
+      // RPN настройки строя и прочие DAW шлют на все каналы при запуске
+      ParamNumber::Rpn(number) if queue.verbose => println!("RPN {}: {} ignored", number, self.data),
+      ParamNumber::Rpn(_) => {},
+      ParamNumber::None => {},
+    }
+  }
+
+  /// Диапазон колеса из RPN 0: MSB -- полутоны, LSB -- центы
+  fn bend_range_raw(&self, id: ParamId) -> u16 {
+    let semitones = (self.data >> 7) as f32 + (self.data & 0x7F).min(99) as f32 / 100.0;
+    ParamStore::to_raw(id.info().normalize(semitones))
+  }
+}
+
+const SONG_POSITION: u8 = 0xF2;
+const CLOCK: u8 = 0xF8;
+const START: u8 = 0xFA;
+const CONTINUE: u8 = 0xFB;
+const STOP: u8 = 0xFC;
+
+/// Доля нового интервала в сглаженном: тики приходят с дрожанием в доли миллисекунды,
+/// темп успокаивается примерно за полторы четверти
+const CLOCK_SMOOTHING: f64 = 0.04;
+/// Интервал дальше этого от текущей оценки -- не дрожание, а новый темп или пауза в clock
+const CLOCK_JUMP: f64 = 2.0;
+
+/// Темп и транспорт по MIDI clock, общие для всех частей
+struct MidiClock {
+  last_stamp: Option<u64>,
+  /// Сглаженный интервал между тиками, мкс
+  interval_us: f64,
+  /// Неожиданные интервалы подряд: после нескольких таких темп переустанавливается
+  jumps: u8,
+  position: u32,
+}
+
+impl MidiClock {
+  fn new() -> Self {
+    Self {
+      last_stamp: None,
+      interval_us: 0.0,
+      jumps: 0,
+      position: 0,
+    }
+  }
+
+  /// Сообщения реального времени и Song Position Pointer, `stamp` -- время от midir в мкс
+  fn system_message(&mut self, parts: &[Part], stamp: u64, message: &[u8]) {
+    match message[0] {
+      CLOCK => {
+        if let Some(bpm) = self.tick(stamp) {
+          for part in parts {
+            part.state.tempo_bpm.store(bpm as f32, Ordering::Relaxed);
+          }
+        }
+        let running = parts.first().is_some_and(|part| part.state.transport_running.load(Ordering::Relaxed));
+        if running {
+          self.position += 1;
+          for part in parts {
+            part.state.song_position.store(self.position, Ordering::Relaxed);
+          }
+        }
+      },
+      START => {
+        self.position = 0;
+        self.relocate(parts, true);
+      },
+      CONTINUE => self.relocate(parts, true),
+      STOP => {
+        for part in parts {
+          part.state.transport_running.store(false, Ordering::Relaxed);
+        }
+      },
+      SONG_POSITION if message.len() >= 3 => {
+        // позиция в шестнадцатых, шестнадцатая -- шесть тиков
+        let sixteenths = ((message[2] as u32) << 7) | message[1] as u32;
+        self.position = sixteenths * CLOCK_PPQN / 4;
+        let running = parts.first().is_some_and(|part| part.state.transport_running.load(Ordering::Relaxed));
+        self.relocate(parts, running);
+      },
+      _ => {},
+    }
+  }
+
+  fn relocate(&self, parts: &[Part], running: bool) {
+    for part in parts {
+      let state = &part.state;
+      state.song_position.store(self.position, Ordering::Relaxed);
+      state.transport_running.store(running, Ordering::Relaxed);
+      state.transport_counter.fetch_add(1, Ordering::Relaxed);
+    }
+  }
+
+  /// Новый тик: возвращает сглаженный темп, когда он известен
+  fn tick(&mut self, stamp: u64) -> Option<f64> {
+    let last = self.last_stamp.replace(stamp)?;
+    let interval = stamp.saturating_sub(last) as f64;
+    let min_interval = 60_000_000.0 / (MAX_TEMPO_BPM as f64 * CLOCK_PPQN as f64);
+    let max_interval = 60_000_000.0 / (MIN_TEMPO_BPM as f64 * CLOCK_PPQN as f64);
+    if !(min_interval..=max_interval).contains(&interval) {
+      // clock прерывался или тики пришли пачкой
+      return None;
+    }
+    if self.interval_us == 0.0 {
+      self.interval_us = interval;
+    } else if interval > self.interval_us * CLOCK_JUMP || interval < self.interval_us / CLOCK_JUMP {
+      // одиночный выброс пропускаем, несколько подряд -- темп действительно сменился
+      self.jumps += 1;
+      if self.jumps < 3 {
+        return None;
       }
-      print!("Please select input port: ");
-      stdout().flush()?;
-      let mut input = String::new();
-      stdin().read_line(&mut input)?;
-      in_ports
-        .get(input.trim().parse::<usize>()?)
-        .ok_or("invalid input port selected")?
+      self.interval_us = interval;
+    } else {
+      self.interval_us += (interval - self.interval_us) * CLOCK_SMOOTHING;
+    }
+    self.jumps = 0;
+    Some(60_000_000.0 / (self.interval_us * CLOCK_PPQN as f64))
+  }
+}
+
+/// SysEx: номер устройства -- номер части. Ответы уходят на MIDI-выход, если он есть.
+fn sysex_message(parts: &[Part], events: &mut [PartEvents], output: Option<&MidiOut>, stamp: u64, verbose: bool, message: &[u8]) {
+  let (device, parsed) = match sysex::parse(message) {
+    Ok(Some(parsed)) => parsed,
+    Ok(None) => return,
+    Err(e) => {
+      println!("{}", e);
+      return;
     },
   };
+  for (i, (part, events)) in parts.iter().zip(events.iter_mut()).enumerate() {
+    if device == ALL_DEVICES || device as usize == i {
+      let mut queue = EventQueue::new(events, stamp, part.channel.unwrap_or(0), verbose);
+      if let Some(reply) = sysex::apply(&part.state, &mut queue, i as u8, &parsed) {
+        match output {
+          Some(output) => output.send(&reply),
+          None => println!("SysEx: no MIDI output for a {}-byte reply, see --midi-out", reply.len()),
+        }
+      }
+    }
+  }
+}
+
+/// Вход одного MIDI-канала одной части
+struct ChannelInput {
+  decoder: ControllerDecoder,
+  /// Банк для следующего Program Change: CC 0 -- старший байт, CC 32 -- младший
+  bank: [u8; 2],
+}
+
+impl ChannelInput {
+  fn new(channel: u8) -> Self {
+    Self {
+      decoder: ControllerDecoder::new(channel),
+      bank: [0; 2],
+    }
+  }
+}
 
-  println!("\nOpening connection");
-  let in_port_name = midi_in.port_name(in_port)?;
-
-  // _conn_in needs to be a named parameter, because it needs to be kept alive until the end of the scope
-  let synth_state_clone = Arc::clone(&synth_state);
-  let _conn_in = midi_in.connect(
-    in_port,
-    "midir-read-input",
-    move |stamp, message, _| {
-        if message.len() >= 3 {
-                let status = message[0] & 0xF0;
-                let note = message[1];
-                let velocity = message[2];
-
-                let mut knopki = synth_state_clone.nazatie_knopki.lock().unwrap();
-
-                match status {
-                    0xB0 => {
-                        if note==44 {
-                          synth_state_clone.gate_attack.store(velocity, Ordering::Relaxed);
-                        }
-                        else if note==45{
-                          synth_state_clone.gate_decay.store(velocity, Ordering::Relaxed);
-                        }
-                        else if note==46{
-                          synth_state_clone.gate_release.store(velocity, Ordering::Relaxed);
-                        }
-                        else if note==47{
-                          synth_state_clone.gate_sustain.store(velocity, Ordering::Relaxed);
-                        }
-                        else if note==33{
-                          synth_state_clone.volume_volume.store(velocity, Ordering::Relaxed);
-                        }
-                        else if note==35{
-                          synth_state_clone.lpf_cutoff.store(velocity, Ordering::Relaxed);
-                        }
-                        else if note==34{
-                          synth_state_clone.lpf_res_factor.store(velocity, Ordering::Relaxed);
-                        }
-                        else if note==36{
-                          synth_state_clone.delay_mix.store(velocity, Ordering::Relaxed);
-                        }
-                        else if note==37{
-                          synth_state_clone.delay_feedback.store(velocity, Ordering::Relaxed);
-                        }
-                        else if note==38{
-                          synth_state_clone.delay_delay_time.store(velocity, Ordering::Relaxed);
-                        }
-                        else if note==39{
-                          synth_state_clone.reverb_decay_time.store(velocity, Ordering::Relaxed);
-                        }
-                        else if note==40{
-                          synth_state_clone.reverb_dry_wet_mix.store(velocity, Ordering::Relaxed);
-                        }
-                        else if note==41{
-                          synth_state_clone.glide_time.store(velocity, Ordering::Relaxed);
-                        }
-                        else if note==5{
-                          synth_state_clone.chorus_lfo_freq.store(velocity, Ordering::Relaxed);
-                        }
-                        else if note==6{
-                          synth_state_clone.chorus_lfo_freq.store(velocity, Ordering::Relaxed);
-                        }
-
-                    }
-                    0x90 if velocity > 0 => { // Note On
-                      if let Some(i) = knopki.iter().position(|&nomer_nazato_knopki| nomer_nazato_knopki == note) {
-                        let nomer_nazato_knopki = knopki.remove(i);
-                        knopki.push(nomer_nazato_knopki);
-                      }else{
-                          knopki.push(note);
-                        }
-                        synth_state_clone.last_key.store(note, Ordering::Relaxed);
-                        synth_state_clone.has_key_pressed.store(true, Ordering::Relaxed);
-                    }
-                    0x80 | 0x90 => { // Note Off или Note On с vel=0
-                        knopki.retain(|&nomer_nazato_knopki| nomer_nazato_knopki != note);
-
-                        if let Some(&last) = knopki.last() {
-                        synth_state_clone.last_key.store(last, Ordering::Relaxed);
-                        synth_state_clone.has_key_pressed.store(true, Ordering::Relaxed);
-                    }else{ 
-                      synth_state_clone.last_key.store(0, Ordering::Relaxed);
-                      synth_state_clone.has_key_pressed.store(false, Ordering::Relaxed);
-                    }
-                }
-                    _ => {}
-                }
-            }
-            
-      println!("{}: {:?} (len = {})", stamp, message, message.len());     
+/// Сообщение канала для одной части. Всё, что меняет звук, уходит в очередь
+/// аудиопотока; здесь разбираются NRPN/RPN, привязки CC, банк и MIDI learn.
+fn channel_message(
+  state: &SynthState,
+  input: &mut ChannelInput,
+  mappings: &mut MidiMappings,
+  patches: &PatchBank,
+  queue: &mut EventQueue,
+  event: MidiEvent,
+) {
+  let MidiEvent { status, channel, data1, data2, .. } = event;
+  let performance = match status {
+    0xB0 => data1 == CC_SUSTAIN || data1 == CC_SOSTENUTO || (data1 == CC_TIMBRE && state.is_mpe_member(channel)),
+    0x80 | 0x90 | 0xA0 | 0xD0 | 0xE0 => true,
+    _ => false,
+  };
+  if performance {
+    queue.push(status, data1, data2, 0);
+    return;
+  }
+
+  match status {
+    0xB0 if data1 == CC_BANK_SELECT || data1 == CC_BANK_SELECT_LSB => {
+      input.bank[(data1 / CC_BANK_SELECT_LSB) as usize] = data2;
+    },
+    0xB0 => input.decoder.control_change(state, mappings, queue, data1, data2),
+    0xC0 => { // Program Change, номер программы в первом байте
+      let bank = ((input.bank[0] as u16) << 7) | input.bank[1] as u16;
+      match patches.find(bank, data1) {
+        Some(preset) => {
+          println!("Preset {}:{} {}", bank, data1, preset.name);
+          queue.push(0xC0, data1, 0, bank);
+        },
+        None => println!("No preset at bank {} program {}", bank, data1),
+      }
     },
-    (),
-  )?;
+    _ => {},
+  }
+}
+
+/// Всё, что нужно для разбора входящих сообщений. Общий для всех входов,
+/// поэтому сообщения с нескольких портов сливаются в один поток.
+struct MidiHandler {
+  parts: Vec<Part>,
+  mappings: MidiMappings,
+  patches: Arc<PatchBank>,
+  output: Option<MidiOut>,
+  clock: MidiClock,
+  inputs: Vec<Vec<ChannelInput>>,
+  /// Очереди нот в аудиопоток, по одной на часть
+  events: Vec<PartEvents>,
+  /// Печатать каждое сообщение и новые значения параметров (`--verbose`)
+  verbose: bool,
+}
+
+impl MidiHandler {
+  /// `stamp` -- время в мкс по `Timeline`, одно для всех портов
+  fn message(&mut self, stamp: u64, message: &[u8]) {
+    if let Some(output) = self.output.as_ref().filter(|output| output.thru) {
+      output.send(message);
+    }
+    if message.first() == Some(&SYSEX_START) {
+      sysex_message(&self.parts, &mut self.events, self.output.as_ref(), stamp, self.verbose, message);
+    } else if message.first().is_some_and(|&status| status >= 0xF0) {
+      self.clock.system_message(&self.parts, stamp, message);
+      // clock идёт 24 раза на четверть, в консоль его не пишем
+      if message[0] >= CLOCK {
+        return;
+      }
+    }
+    // у Channel Pressure и Program Change только один байт данных
+    if message.len() >= 2 && message[0] < 0xF0 {
+      let event = MidiEvent {
+        time_us: stamp,
+        status: message[0] & 0xF0,
+        channel: message[0] & 0x0F,
+        data1: message[1],
+        data2: message.get(2).copied().unwrap_or(0),
+        value: 0,
+      };
+
+      for ((part, inputs), events) in self.parts.iter().zip(self.inputs.iter_mut()).zip(self.events.iter_mut()) {
+        if part.listens(event.channel) {
+          let input = &mut inputs[event.channel as usize];
+          let mut queue = EventQueue::new(events, stamp, event.channel, self.verbose);
+          channel_message(&part.state, input, &mut self.mappings, &self.patches, &mut queue, event);
+        }
+      }
+    }
+
+    if self.verbose {
+      println!("{}: {:?} (len = {})", stamp, message, message.len());
+    }
+  }
+}
+
+/// Какие входы открывать: `--midi-in <номер или часть имени>` (можно несколько раз),
+/// без него -- все железные порты. `--virtual` -- ещё и виртуальный порт.
+pub struct MidiInputConfig {
+  pub ports: Vec<String>,
+  pub virtual_port: bool,
+}
+
+/// Открытые входы. Пока живёт, фоновый поток следит за портами: отключённый
+/// контроллер закрывается, а когда его снова подключат -- открывается заново.
+pub struct MidiInputs {
+  stop: Arc<AtomicBool>,
+}
+
+impl Drop for MidiInputs {
+  fn drop(&mut self) {
+    self.stop.store(true, Ordering::Relaxed);
+  }
+}
+
+/// Вход в разбор MIDI, общий для всех источников: портов и проигрывателя файлов
+#[derive(Clone)]
+pub struct MidiSender {
+  handler: Arc<Mutex<MidiHandler>>,
+  timeline: Timeline,
+}
+
+impl MidiSender {
+  /// Каждая часть получает сообщения своего канала, CC и MIDI learn работают
+  /// по общему профилю `mapping`, Program Change выбирает из `patches`.
+  /// Если задан `output`, на него идут MIDI thru, ответы SysEx и обратная связь CC.
+  /// Ноты уходят в `events` с временем по `timeline`.
+  pub fn new(
+    parts: Vec<Part>,
+    events: Vec<Producer<MidiEvent>>,
+    timeline: Timeline,
+    mapping: MappingConfig,
+    patches: Arc<PatchBank>,
+    output: Option<MidiOut>,
+  ) -> Result<Self, Box<dyn Error>> {
+    let resolved = Arc::new(Mutex::new(mapping.profile.resolve()?));
+    if let Some(output) = &output {
+      output.spawn_feedback(parts.clone(), resolved.clone());
+    }
+    println!("MIDI mapping profile: {}", mapping.profile.name);
+
+    let learn_state = Arc::clone(&parts[0].state);
+    let handler = MidiHandler {
+      mappings: MidiMappings { config: mapping, resolved, learn_state },
+      patches,
+      output,
+      clock: MidiClock::new(),
+      inputs: parts.iter().map(|_| (0..16).map(ChannelInput::new).collect()).collect(),
+      events: events.into_iter().map(PartEvents::new).collect(),
+      parts,
+      verbose: false,
+    };
+    Ok(Self {
+      handler: Arc::new(Mutex::new(handler)),
+      timeline,
+    })
+  }
+
+  /// Сообщение со временем `stamp_us` по общей шкале
+  pub fn send(&self, stamp_us: u64, message: &[u8]) {
+    self.handler.lock().unwrap().message(stamp_us, message);
+  }
+
+  pub fn timeline(&self) -> Timeline {
+    self.timeline.clone()
+  }
+
+  /// Включает печать каждого входящего сообщения
+  pub fn set_verbose(&self, verbose: bool) {
+    self.handler.lock().unwrap().verbose = verbose;
+  }
+}
+
+/// Подключается к MIDI-входам и передаёт их сообщения в `sender`
+pub fn initiate_midi_connection(sender: MidiSender, config: MidiInputConfig) -> Result<MidiInputs, Box<dyn Error>> {
+  // номера портов сдвигаются, когда что-то отключают, поэтому сразу переводим их в имена
+  let names = InputWatcher::port_names(&InputWatcher::new_input()?);
+  let mut wanted = Vec::new();
+  for port in config.ports {
+    if port.parse::<usize>().is_err() {
+      wanted.push(port);
+    } else if let Some(index) = find_port(&names, &port) {
+      if is_own_port(&names[index]) {
+        println!("MIDI input {}: '{}' is the synth's own port, skipped", port, names[index]);
+      } else {
+        // номер клиента ALSA меняется, когда USB-контроллер вставляют заново
+        wanted.push(without_port_id(&names[index]).to_string());
+      }
+    } else {
+      println!("MIDI input {}: no such port", port);
+    }
+  }
+  let mut watcher = InputWatcher {
+    sender,
+    wanted,
+    connections: Vec::new(),
+    virtual_connection: None,
+  };
+
+  let names = watcher.scan()?;
+  // без железных портов остаётся виртуальный, чтобы было куда слать ноты
+  if config.virtual_port || (names.is_empty() && watcher.wanted.is_empty()) {
+    watcher.virtual_connection = Some(watcher.open_virtual()?);
+  }
+  for wanted in &watcher.wanted {
+    if find_port(&names, wanted).is_none() {
+      println!("MIDI input '{}' is not connected, waiting for it", wanted);
+    }
+  }
+
+  let stop = Arc::new(AtomicBool::new(false));
+  let stop_watcher = stop.clone();
+  thread::spawn(move || {
+    while !stop_watcher.load(Ordering::Relaxed) {
+      thread::sleep(RECONNECT_INTERVAL);
+      if let Err(e) = watcher.scan() {
+        println!("MIDI input: {}", e);
+      }
+    }
+  });
+  Ok(MidiInputs { stop })
+}
+
+/// Держит соединения с входами и переоткрывает их, когда порты появляются и пропадают
+struct InputWatcher {
+  sender: MidiSender,
+  /// Части имён нужных портов, номера из командной строки уже заменены именами
+  /// без номера клиента и порта
+  wanted: Vec<String>,
+  /// Имя порта и соединение с ним
+  connections: Vec<(String, MidiInputConnection<()>)>,
+  virtual_connection: Option<MidiInputConnection<()>>,
+}
+
+impl InputWatcher {
+  fn new_input() -> Result<MidiInput, Box<dyn Error>> {
+    // имя клиента видно в `aconnect -l` рядом с именем порта
+    let mut midi_in = MidiInput::new(VIRTUAL_PORT_NAME)?;
+    midi_in.ignore(Ignore::None);
+    Ok(midi_in)
+  }
+
+  fn port_names(midi_in: &MidiInput) -> Vec<String> {
+    midi_in.ports().iter().map(|p| midi_in.port_name(p).unwrap_or_default()).collect()
+  }
+
+  /// Все сообщения идут в общий `MidiHandler`. У midir своя шкала времени
+  /// у каждого соединения, она переводится на общую.
+  fn callback(&self) -> impl FnMut(u64, &[u8], &mut ()) + Send + 'static {
+    let sender = self.sender.clone();
+    let offset = sender.timeline.now_us();
+    move |stamp, message, _| sender.send(offset + stamp, message)
+  }
+
+  /// Закрывает пропавшие порты и открывает нужные. Возвращает имена всех портов.
+  fn scan(&mut self) -> Result<Vec<String>, Box<dyn Error>> {
+    let names = Self::port_names(&Self::new_input()?);
+
+    self.connections.retain(|(name, _)| {
+      let present = names.contains(name);
+      if !present {
+        println!("MIDI input '{}' disconnected", name);
+      }
+      present
+    });
+
+    for index in wanted_ports(&names, &self.wanted) {
+      if self.connections.iter().any(|(name, _)| *name == names[index]) {
+        continue;
+      }
+      let midi_in = Self::new_input()?;
+      let Some(port) = midi_in.ports().into_iter().find(|p| midi_in.port_name(p).is_ok_and(|name| name == names[index])) else {
+        continue;
+      };
+      match midi_in.connect(&port, INPUT_PORT_NAME, self.callback(), ()) {
+        Ok(connection) => {
+          println!("MIDI input '{}' connected", names[index]);
+          self.connections.push((names[index].clone(), connection));
+        },
+        Err(e) => println!("MIDI input '{}': {}", names[index], e),
+      }
+    }
+    Ok(names)
+  }
+
+  /// Порт, в который DAW, `aconnect` и `aplaymidi` могут слать ноты без железного контроллера
+  #[cfg(unix)]
+  fn open_virtual(&self) -> Result<MidiInputConnection<()>, Box<dyn Error>> {
+    let connection = Self::new_input()?.create_virtual(VIRTUAL_PORT_NAME, self.callback(), ()).map_err(|e| e.to_string())?;
+    println!("Virtual MIDI input '{}' is open", VIRTUAL_PORT_NAME);
+    Ok(connection)
+  }
+
+  #[cfg(not(unix))]
+  fn open_virtual(&self) -> Result<MidiInputConnection<()>, Box<dyn Error>> {
+    Err("virtual MIDI ports are not supported on this system".into())
+  }
+}
+
+/// Номера нужных портов среди `names`: все чужие, если ничего не выбрано
+fn wanted_ports(names: &[String], wanted: &[String]) -> Vec<usize> {
+  // свои же порты (MIDI-выход синтезатора) не слушаем, иначе thru зациклится
+  let foreign: Vec<usize> = (0..names.len()).filter(|&i| !is_own_port(&names[i])).collect();
+  if wanted.is_empty() {
+    return foreign;
+  }
+  wanted
+    .iter()
+    .filter_map(|wanted| {
+      let wanted = wanted.to_lowercase();
+      foreign.iter().copied().find(|&i| names[i].to_lowercase().contains(&wanted))
+    })
+    .collect()
+}
+
+/// Имя порта без номера клиента и порта в конце: в ALSA midir называет порт
+/// "клиент:порт N:M", в других системах -- только именем порта
+fn without_port_id(name: &str) -> &str {
+  match name.rsplit_once(' ') {
+    Some((rest, id)) if id.split_once(':').is_some_and(|(client, port)| is_number(client) && is_number(port)) => rest,
+    _ => name,
+  }
+}
+
+fn is_number(text: &str) -> bool {
+  !text.is_empty() && text.bytes().all(|b| b.is_ascii_digit())
+}
+
+/// Порт создан самим синтезатором. Имя сравнивается целиком, а не по подстроке,
+/// чтобы не отбросить чужой контроллер, в имени которого встречается "delta-synth".
+fn is_own_port(name: &str) -> bool {
+  const OWN: [(&str, &str); 3] = [
+    (VIRTUAL_PORT_NAME, VIRTUAL_PORT_NAME),
+    (VIRTUAL_PORT_NAME, INPUT_PORT_NAME),
+    (OUTPUT_CLIENT_NAME, OUTPUT_PORT_NAME),
+  ];
+  let name = without_port_id(name);
+  match name.split_once(':') {
+    Some((client, port)) => OWN.contains(&(client, port)),
+    None => OWN.iter().any(|&(_, port)| port == name),
+  }
+}
+
+/// Порт по номеру или по части имени без учёта регистра
+pub fn find_port(names: &[String], wanted: &str) -> Option<usize> {
+  if let Ok(index) = wanted.parse::<usize>() {
+    return (index < names.len()).then_some(index);
+  }
+  let wanted = wanted.to_lowercase();
+  names.iter().position(|name| name.to_lowercase().contains(&wanted))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::midi_mapping::{CcMapping, MappingProfile};
+  use crate::params::PARAM_COUNT;
+  use rtrb::{Consumer, RingBuffer};
+
+  /// Декодер одного канала с профилем и очередью, из которой читает тест
+  struct Decoder {
+    state: Arc<SynthState>,
+    mappings: MidiMappings,
+    decoder: ControllerDecoder,
+    events: PartEvents,
+    consumer: Consumer<MidiEvent>,
+  }
+
+  impl Decoder {
+    fn new(channel: u8) -> Self {
+      Self::with_profile(channel, MappingProfile::default())
+    }
+
+    fn with_profile(channel: u8, profile: MappingProfile) -> Self {
+      let state = SynthState::new();
+      let resolved = Arc::new(Mutex::new(profile.resolve().unwrap()));
+      let (producer, consumer) = RingBuffer::new(16);
+      Self {
+        mappings: MidiMappings {
+          config: MappingConfig { profile, path: "unused.toml".into() },
+          resolved,
+          learn_state: state.clone(),
+        },
+        state,
+        decoder: ControllerDecoder::new(channel),
+        events: PartEvents::new(producer),
+        consumer,
+      }
+    }
+
+    /// Отправляет CC, возвращает события для аудиопотока: статус, номер параметра, значение
+    fn cc(&mut self, cc: u8, value: u8) -> Vec<(u8, u8, u16)> {
+      let mut queue = EventQueue::new(&mut self.events, 0, self.decoder.channel, false);
+      self.decoder.control_change(&self.state, &mut self.mappings, &mut queue, cc, value);
+      std::iter::from_fn(|| self.consumer.pop().ok()).map(|event| (event.status, event.data1, event.value)).collect()
+    }
+
+    fn nrpn(&mut self, number: u16) {
+      assert!(self.cc(CC_NRPN_MSB, (number >> 7) as u8).is_empty());
+      assert!(self.cc(CC_NRPN_LSB, (number & 0x7F) as u8).is_empty());
+    }
+
+    fn rpn(&mut self, number: u16) {
+      assert!(self.cc(CC_RPN_MSB, (number >> 7) as u8).is_empty());
+      assert!(self.cc(CC_RPN_LSB, (number & 0x7F) as u8).is_empty());
+    }
+  }
+
+  fn param(id: ParamId, raw: u16) -> Vec<(u8, u8, u16)> {
+    vec![(PARAM_CHANGE, id as u8, raw)]
+  }
+
+  fn semitones(id: ParamId, raw: u16) -> f32 {
+    id.info().denormalize(raw as f32 / RAW_MAX as f32)
+  }
+
+  #[test]
+  fn nrpn_data_entry_msb_then_lsb() {
+    let mut d = Decoder::new(0);
+    d.nrpn(ParamId::LpfCutoff as u16);
+    assert_eq!(d.cc(CC_DATA_ENTRY, 0x40), param(ParamId::LpfCutoff, 0x40 << 7));
+    assert_eq!(d.cc(CC_DATA_ENTRY_LSB, 0x05), param(ParamId::LpfCutoff, (0x40 << 7) | 0x05));
+    // новый старший байт сбрасывает младший
+    assert_eq!(d.cc(CC_DATA_ENTRY, 0x10), param(ParamId::LpfCutoff, 0x10 << 7));
+    // младший байт без старшего пишет поверх последнего старшего
+    assert_eq!(d.cc(CC_DATA_ENTRY_LSB, 0x7F), param(ParamId::LpfCutoff, (0x10 << 7) | 0x7F));
+  }
+
+  #[test]
+  fn nrpn_selection_follows_last_number() {
+    let mut d = Decoder::new(0);
+    d.nrpn(ParamId::LpfCutoff as u16);
+    // сменился только младший байт номера
+    assert!(d.cc(CC_NRPN_LSB, ParamId::LpfResonance as u8).is_empty());
+    assert_eq!(d.cc(CC_DATA_ENTRY, 1), param(ParamId::LpfResonance, 1 << 7));
+    // номер вне реестра: Data Entry ничего не меняет
+    d.nrpn(PARAM_COUNT as u16);
+    assert!(d.cc(CC_DATA_ENTRY, 1).is_empty());
+  }
+
+  #[test]
+  fn data_increment_steps_by_msb_and_clamps() {
+    let mut d = Decoder::new(0);
+    d.nrpn(ParamId::ReverbMix as u16);
+    d.state.params.set_raw(ParamId::ReverbMix, 1000);
+    assert_eq!(d.cc(CC_DATA_INCREMENT, 0), param(ParamId::ReverbMix, 1000 + 0x80));
+    d.state.params.set_raw(ParamId::ReverbMix, RAW_MAX - 10);
+    assert_eq!(d.cc(CC_DATA_INCREMENT, 0), param(ParamId::ReverbMix, RAW_MAX));
+    d.state.params.set_raw(ParamId::ReverbMix, 10);
+    assert_eq!(d.cc(CC_DATA_DECREMENT, 0), param(ParamId::ReverbMix, 0));
+  }
+
+  #[test]
+  fn data_entry_without_selection_is_a_plain_cc() {
+    let mut d = Decoder::new(0);
+    // в профиле по умолчанию CC 6 и 38 ни к чему не привязаны
+    assert!(d.cc(CC_DATA_ENTRY, 0x40).is_empty());
+    assert!(d.cc(CC_DATA_ENTRY_LSB, 0x40).is_empty());
+    d.nrpn(ParamId::LpfCutoff as u16);
+    d.rpn(RPN_NULL);
+    assert!(d.cc(CC_DATA_ENTRY, 0x40).is_empty());
+    assert!(d.cc(CC_DATA_INCREMENT, 0).is_empty());
+  }
+
+  #[test]
+  fn cc14_pair_msb_lsb() {
+    // CC 16 / 48 -- 14-битная пара среза, как в профиле `hires` из примера
+    let profile = MappingProfile {
+      name: "hires".to_string(),
+      cc: vec![CcMapping { lsb: Some(48), ..CcMapping::new(16, ParamId::LpfCutoff) }],
+    };
+    let mapping = profile.resolve().unwrap().into_iter().next().unwrap();
+    let mut d = Decoder::with_profile(0, profile);
+    let expect = |raw: u16| vec![(PARAM_CHANGE, mapping.id as u8, mapping.param_raw(raw))];
+
+    // младший байт до старшего: старший ещё ноль
+    assert_eq!(d.cc(48, 0x22), expect(0x22));
+    assert_eq!(d.cc(16, 0x30), expect(0x30 << 7));
+    assert_eq!(d.cc(48, 0x11), expect((0x30 << 7) | 0x11));
+    // старший байт заново без младшего сбрасывает младший
+    assert_eq!(d.cc(16, 0x31), expect(0x31 << 7));
+    assert_eq!(d.cc(16, 0x7F), expect(0x7F << 7));
+    assert_eq!(d.cc(48, 0x7F), expect(RAW_MAX));
+  }
+
+  #[test]
+  fn mapped_cc_is_tagged_as_controller() {
+    let mut d = Decoder::new(0);
+    let mut queue = EventQueue::new(&mut d.events, 0, 0, false);
+    d.decoder.control_change(&d.state, &mut d.mappings, &mut queue, 35, 127);
+    let event = d.consumer.pop().unwrap();
+    assert_eq!((event.status, event.data1, event.data2, event.value), (PARAM_CHANGE, ParamId::LpfCutoff as u8, FROM_CONTROLLER, RAW_MAX));
+  }
+
+  #[test]
+  fn rpn_0_sets_bend_range() {
+    let mut d = Decoder::new(0);
+    d.rpn(RPN_PITCH_BEND_RANGE);
+    let events = d.cc(CC_DATA_ENTRY, 12);
+    assert_eq!(events.len(), 2);
+    assert_eq!(events[0].1, ParamId::BendRangeUp as u8);
+    assert_eq!(events[1].1, ParamId::BendRangeDown as u8);
+    assert!((semitones(ParamId::BendRangeUp, events[0].2) - 12.0).abs() < 0.01);
+
+    // LSB -- центы
+    let events = d.cc(CC_DATA_ENTRY_LSB, 50);
+    assert!((semitones(ParamId::BendRangeDown, events[1].2) - 12.5).abs() < 0.01);
+  }
+
+  #[test]
+  fn rpn_0_on_mpe_member_sets_note_bend_range() {
+    let mut d = Decoder::new(3);
+    d.state.mpe_members.store(MPE_MAX_MEMBERS, Ordering::Relaxed);
+    d.rpn(RPN_PITCH_BEND_RANGE);
+    let events = d.cc(CC_DATA_ENTRY, 24);
+    assert_eq!(events.len(), 1);
+    assert_eq!(events[0].1, ParamId::MpeBendRange as u8);
+    assert!((semitones(ParamId::MpeBendRange, events[0].2) - 24.0).abs() < 0.01);
+  }
+
+  #[test]
+  fn rpn_6_configures_mpe_on_first_channel_only() {
+    let mut d = Decoder::new(0);
+    d.rpn(RPN_MPE_CONFIGURATION);
+    assert_eq!(d.cc(CC_DATA_ENTRY, 20), vec![(MPE_CONFIGURATION, MPE_MAX_MEMBERS, 0)]);
+
+    let mut d = Decoder::new(1);
+    d.rpn(RPN_MPE_CONFIGURATION);
+    assert!(d.cc(CC_DATA_ENTRY, 5).is_empty());
+  }
+
+  /// Гонит clock с постоянным интервалом, возвращает последний темп
+  fn clock_ticks(clock: &mut MidiClock, stamp: &mut u64, interval: f64, count: usize) -> Option<f64> {
+    let mut bpm = None;
+    let mut exact = *stamp as f64;
+    for _ in 0..count {
+      exact += interval;
+      *stamp = exact.round() as u64;
+      bpm = clock.tick(*stamp);
+    }
+    bpm
+  }
+
+  fn interval_us(bpm: f64) -> f64 {
+    60_000_000.0 / (bpm * CLOCK_PPQN as f64)
+  }
+
+  #[test]
+  fn clock_tempo_needs_two_ticks() {
+    let mut clock = MidiClock::new();
+    assert_eq!(clock.tick(1_000), None);
+    let bpm = clock.tick(1_000 + interval_us(120.0) as u64).unwrap();
+    assert!((bpm - 120.0).abs() < 0.01, "{}", bpm);
+  }
+
+  #[test]
+  fn clock_jitter_is_smoothed() {
+    let mut clock = MidiClock::new();
+    let mut stamp = 0;
+    clock_ticks(&mut clock, &mut stamp, interval_us(120.0), 2);
+    for i in 0..200 {
+      // дрожание до полумиллисекунды в обе стороны
+      let jitter = if i % 2 == 0 { 500 } else { 0 };
+      let bpm = clock.tick(stamp + interval_us(120.0) as u64 * (i + 1) + jitter).unwrap();
+      assert!((bpm - 120.0).abs() < 1.5, "tick {}: {}", i, bpm);
+    }
+  }
+
+  #[test]
+  fn clock_follows_gradual_tempo_change() {
+    let mut clock = MidiClock::new();
+    let mut stamp = 0;
+    clock_ticks(&mut clock, &mut stamp, interval_us(120.0), 10);
+    let bpm = clock_ticks(&mut clock, &mut stamp, interval_us(140.0), 6).unwrap();
+    assert!(bpm > 120.0 && bpm < 135.0, "{}", bpm);
+    let bpm = clock_ticks(&mut clock, &mut stamp, interval_us(140.0), 200).unwrap();
+    assert!((bpm - 140.0).abs() < 0.1, "{}", bpm);
+  }
+
+  #[test]
+  fn single_clock_outlier_is_ignored() {
+    let mut clock = MidiClock::new();
+    let mut stamp = 0;
+    clock_ticks(&mut clock, &mut stamp, interval_us(120.0), 10);
+    // один тик опоздал втрое
+    assert_eq!(clock_ticks(&mut clock, &mut stamp, interval_us(40.0), 1), None);
+    let bpm = clock_ticks(&mut clock, &mut stamp, interval_us(120.0), 1).unwrap();
+    assert!((bpm - 120.0).abs() < 0.01, "{}", bpm);
+  }
+
+  #[test]
+  fn clock_jumps_to_new_tempo_after_three_outliers() {
+    let mut clock = MidiClock::new();
+    let mut stamp = 0;
+    clock_ticks(&mut clock, &mut stamp, interval_us(120.0), 10);
+    assert_eq!(clock_ticks(&mut clock, &mut stamp, interval_us(50.0), 1), None);
+    assert_eq!(clock_ticks(&mut clock, &mut stamp, interval_us(50.0), 1), None);
+    let bpm = clock_ticks(&mut clock, &mut stamp, interval_us(50.0), 1).unwrap();
+    assert!((bpm - 50.0).abs() < 0.01, "{}", bpm);
+  }
+
+  #[test]
+  fn clock_gaps_and_bursts_are_skipped() {
+    let mut clock = MidiClock::new();
+    let mut stamp = 0;
+    clock_ticks(&mut clock, &mut stamp, interval_us(120.0), 10);
+    // clock стоял секунду, потом тики пришли пачкой
+    assert_eq!(clock_ticks(&mut clock, &mut stamp, 1_000_000.0, 1), None);
+    assert_eq!(clock_ticks(&mut clock, &mut stamp, 100.0, 1), None);
+    let bpm = clock_ticks(&mut clock, &mut stamp, interval_us(120.0), 1).unwrap();
+    assert!((bpm - 120.0).abs() < 0.01, "{}", bpm);
+    assert_eq!(clock.jumps, 0);
+  }
+
+  #[test]
+  fn clock_counts_position_while_running() {
+    let parts = [Part { channel: None, state: SynthState::new() }];
+    let state = &parts[0].state;
+    let mut clock = MidiClock::new();
+
+    clock.system_message(&parts, 0, &[CLOCK]);
+    assert_eq!(state.song_position.load(Ordering::Relaxed), 0);
+
+    // Song Position Pointer на второй такт: 16 шестнадцатых
+    clock.system_message(&parts, 0, &[SONG_POSITION, 16, 0]);
+    assert_eq!(state.song_position.load(Ordering::Relaxed), 16 * CLOCK_PPQN / 4);
+    assert!(!state.transport_running.load(Ordering::Relaxed));
+
+    clock.system_message(&parts, 0, &[CONTINUE]);
+    assert!(state.transport_running.load(Ordering::Relaxed));
+    for _ in 0..CLOCK_PPQN {
+      clock.system_message(&parts, 0, &[CLOCK]);
+    }
+    assert_eq!(state.song_position.load(Ordering::Relaxed), 5 * CLOCK_PPQN);
+
+    clock.system_message(&parts, 0, &[STOP]);
+    clock.system_message(&parts, 0, &[CLOCK]);
+    assert_eq!(state.song_position.load(Ordering::Relaxed), 5 * CLOCK_PPQN);
+
+    let relocations = state.transport_counter.load(Ordering::Relaxed);
+    clock.system_message(&parts, 0, &[START]);
+    assert_eq!(state.song_position.load(Ordering::Relaxed), 0);
+    assert_eq!(state.transport_counter.load(Ordering::Relaxed), relocations + 1);
+  }
+
+  #[test]
+  fn own_ports_match_by_exact_name() {
+    assert!(is_own_port("delta-synth:delta-synth 128:0"));
+    assert!(is_own_port("delta-synth output:delta-synth-out 130:0"));
+    assert!(is_own_port("delta-synth-out"));
+    assert!(is_own_port("delta-synth"));
+
+    assert!(!is_own_port("delta-synth-keys:delta-synth-keys MIDI 1 24:0"));
+    assert!(!is_own_port("My delta-synth controller:Port 1 20:0"));
+    assert!(!is_own_port("delta-synth output:MIDI 1 130:0"));
+    assert!(!is_own_port("Midi Through:Midi Through Port-0 14:0"));
+    assert!(!is_own_port("delta-synth controller"));
+  }
 
-  println!(
-    "Connection open, reading input from '{}' (press enter to exit) ...",
-    in_port_name
-  );
+  #[test]
+  fn port_id_is_stripped_only_when_it_is_alsa_client_and_port() {
+    assert_eq!(without_port_id("Arturia MiniLab mkII:Arturia MiniLab mkII MIDI 1 20:0"), "Arturia MiniLab mkII:Arturia MiniLab mkII MIDI 1");
+    assert_eq!(without_port_id("Keystation 49 MIDI 1"), "Keystation 49 MIDI 1");
+    assert_eq!(without_port_id("Port 1:"), "Port 1:");
+  }
 
+  #[test]
+  fn scan_finds_replugged_port_with_new_client_id() {
+    let names = |list: &[&str]| list.iter().map(|name| name.to_string()).collect::<Vec<_>>();
+    let before = names(&["Midi Through:Midi Through Port-0 14:0", "Arturia MiniLab mkII:Arturia MiniLab mkII MIDI 1 20:0"]);
+    // `--midi-in 1` запоминается без номера клиента
+    let wanted = vec![without_port_id(&before[1]).to_string()];
+    assert_eq!(wanted_ports(&before, &wanted), vec![1]);
 
-  println!("Closing connection");
-  Ok(_conn_in)
-}
\ No newline at end of file
+    // после того как контроллер выдернули и вставили, ALSA дала ему клиент 24
+    let after = names(&[
+      "Midi Through:Midi Through Port-0 14:0",
+      "delta-synth output:delta-synth-out 130:0",
+      "Arturia MiniLab mkII:Arturia MiniLab mkII MIDI 1 24:0",
+    ]);
+    assert_eq!(wanted_ports(&after, &wanted), vec![2]);
+    // без выбора слушаем все чужие порты
+    assert_eq!(wanted_ports(&after, &[]), vec![0, 2]);
+  }
+}
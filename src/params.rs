@@ -0,0 +1,472 @@
+//! Реестр параметров синтезатора.
+//!
+//! Каждый параметр описан один раз: идентификатор, имя, единицы, диапазон,
+//! значение по умолчанию и кривая, по которой положение ручки (0..1)
+//! переводится в реальное значение. Значения хранятся в `ParamStore` без блокировок,
+//! поэтому MIDI, пресеты и модули могут перебирать параметры, не зная о каждом отдельно.
+
+use std::sync::atomic::{AtomicU16, Ordering};
+
+use crate::audiomodules::envelope::{ms_to_norm, norm_to_ms};
+use crate::audiomodules::lfo::LFO_SINE;
+use crate::audiomodules::mod_matrix::{ModDestination, ModSource};
+use crate::synth_state::{MOD_ENV_DAHDSR, MOD_SLOT_COUNT, VELOCITY_LINEAR};
+
+/// Единицы измерения, нужны для отображения значения
+#[derive(Clone, Copy)]
+pub enum Unit {
+  None,
+  Percent,
+  Ms,
+  Seconds,
+  Hz,
+  Semitones,
+  Octaves,
+  Degrees,
+  Choice(&'static [&'static str]),
+  Toggle,
+  /// Номер приёмника матрицы модуляции, см. `ModDestination`
+  ModDestination,
+}
+
+/// Как положение ручки 0..1 переводится в значение min..max
+#[derive(Clone, Copy)]
+pub enum Curve {
+  Linear,
+  /// min * (max / min)^t, min > 0
+  Exponential,
+  /// Целые значения от min до max
+  Stepped,
+  /// Время стадии огибающей, см. `envelope::norm_to_ms`
+  EnvelopeTime,
+}
+
+/// Как модули сглаживают изменение параметра, время в миллисекундах
+#[derive(Clone, Copy, PartialEq)]
+pub enum Smoothing {
+  /// Значение применяется сразу (переключатели, времена огибающих)
+  None,
+  /// Экспонента, за указанное время проходит ~99% пути
+  OnePole(f32),
+  /// Линейный переход за указанное время. Для времени задержки:
+  /// при экспоненте высота «плывёт» неравномерно.
+  Linear(f32),
+}
+
+const DEFAULT_SMOOTHING_MS: f32 = 20.0;
+const DELAY_SMOOTHING_MS: f32 = 100.0;
+
+pub struct ParamInfo {
+  pub id: ParamId,
+  /// Имя для файлов и протоколов, например `lpf_cutoff`
+  pub key: &'static str,
+  /// Имя для людей
+  pub name: &'static str,
+  pub unit: Unit,
+  pub curve: Curve,
+  pub min: f32,
+  pub max: f32,
+  pub default: f32,
+}
+
+impl ParamInfo {
+  /// Положение ручки 0..1 -> значение
+  pub fn denormalize(&self, t: f32) -> f32 {
+    let t = t.clamp(0.0, 1.0);
+    match self.curve {
+      Curve::Linear => self.min + t * (self.max - self.min),
+      Curve::Exponential => self.min * (self.max / self.min).powf(t),
+      Curve::Stepped => (self.min + t * (self.max - self.min)).round(),
+      Curve::EnvelopeTime => norm_to_ms(t),
+    }
+  }
+
+  /// Значение -> положение ручки 0..1
+  pub fn normalize(&self, value: f32) -> f32 {
+    let value = value.clamp(self.min, self.max);
+    let t = match self.curve {
+      Curve::Linear | Curve::Stepped => (value - self.min) / (self.max - self.min),
+      Curve::Exponential => (value / self.min).ln() / (self.max / self.min).ln(),
+      Curve::EnvelopeTime => ms_to_norm(value),
+    };
+    t.clamp(0.0, 1.0)
+  }
+
+  /// Значение в виде текста с единицами
+  pub fn format(&self, value: f32) -> String {
+    match self.unit {
+      Unit::None => format!("{:.3}", value),
+      Unit::Percent => format!("{:.0} %", value * 100.0),
+      Unit::Ms if value >= 1000.0 => format!("{:.2} s", value / 1000.0),
+      Unit::Ms => format!("{:.1} ms", value),
+      Unit::Seconds if value < 1.0 => format!("{:.1} ms", value * 1000.0),
+      Unit::Seconds => format!("{:.2} s", value),
+      Unit::Hz if value >= 1000.0 => format!("{:.2} kHz", value / 1000.0),
+      Unit::Hz => format!("{:.2} Hz", value),
+      Unit::Semitones => format!("{:+.2} st", value),
+      Unit::Octaves => format!("{:+.0} oct", value),
+      Unit::Degrees => format!("{:.0}°", value),
+      Unit::Choice(names) => names.get(value as usize).copied().unwrap_or("?").to_string(),
+      Unit::Toggle => if value >= 0.5 { "on" } else { "off" }.to_string(),
+      Unit::ModDestination => ModDestination::from_u8(value as u8).name().to_string(),
+    }
+  }
+}
+
+const WAVEFORMS: &[&str] = &["sine", "square", "saw", "triangle"];
+const VELOCITY_CURVES: &[&str] = &["linear", "soft", "hard", "fixed"];
+const ENV_MODES: &[&str] = &["dahdsr", "breakpoint"];
+const LFO_SHAPES: &[&str] = &["sine", "triangle", "saw", "square", "sample & hold", "smooth random"];
+const MOD_SOURCES: &[&str] = &[
+  "none", "lfo 1", "lfo 2", "env 1", "env 2", "velocity", "mod wheel", "aftertouch", "key", "breath", "poly aftertouch", "timbre",
+];
+// Верх диапазона источника и приёмника не зависит от реестра: иначе новый параметр
+// в конце сдвигает то, во что превращаются сохранённые положения в дампах и на ручках.
+const MOD_SOURCE_MAX: f32 = 31.0;
+const MOD_DESTINATION_MAX: f32 = 255.0;
+const _: () = assert!(ModSource::ALL.len() <= MOD_SOURCE_MAX as usize + 1);
+const TEMPO_DIVISIONS: &[&str] = &[
+  "free", "4 bars", "2 bars", "1 bar", "1/2", "1/4", "1/4T", "1/8.", "1/8", "1/8T", "1/16.", "1/16", "1/16T", "1/32",
+];
+// у эха нет делений длиннее такта: они не влезают в буфер на медленном темпе
+const DELAY_DIVISIONS: &[&str] = &["free", "1 bar", "1/2", "1/4", "1/4T", "1/8.", "1/8", "1/8T", "1/16.", "1/16", "1/16T", "1/32"];
+
+// одна запись на параметр: id => key, имя, единицы, кривая, min, max, по умолчанию.
+// Номер параметра -- его номер NRPN, поэтому новые параметры добавляются в конец.
+macro_rules! params {
+  ($($id:ident => $key:literal, $name:literal, $unit:expr, $curve:ident, $min:expr, $max:expr, $default:expr;)*) => {
+    #[derive(Clone, Copy, PartialEq, Eq, Debug)]
+    #[repr(u8)]
+    pub enum ParamId {
+      $($id,)*
+    }
+
+    pub static PARAMS: &[ParamInfo] = &[
+      $(ParamInfo {
+        id: ParamId::$id,
+        key: $key,
+        name: $name,
+        unit: $unit,
+        curve: Curve::$curve,
+        min: $min,
+        max: $max,
+        default: $default,
+      },)*
+    ];
+
+    pub const PARAM_COUNT: usize = [$(ParamId::$id),*].len();
+  };
+}
+
+params! {
+  PolyMode => "poly_mode", "Poly mode", Unit::Toggle, Stepped, 0.0, 1.0, 0.0;
+
+  Osc1Waveform => "osc1_waveform", "Osc 1 waveform", Unit::Choice(WAVEFORMS), Stepped, 0.0, 3.0, 0.0;
+  Osc1Octave => "osc1_octave", "Osc 1 octave", Unit::Octaves, Stepped, -3.0, 3.0, 0.0;
+  Osc1Semitone => "osc1_semitone", "Osc 1 semitone", Unit::Semitones, Stepped, -12.0, 12.0, 0.0;
+  Osc1Fine => "osc1_fine", "Osc 1 fine tune", Unit::Semitones, Linear, -1.0, 1.0, 0.0;
+  Osc1Level => "osc1_level", "Osc 1 level", Unit::Percent, Linear, 0.0, 1.0, 0.25;
+  Osc1VelocityAmount => "osc1_velocity", "Osc 1 velocity amount", Unit::Percent, Linear, 0.0, 1.0, 0.0;
+  Osc2Waveform => "osc2_waveform", "Osc 2 waveform", Unit::Choice(WAVEFORMS), Stepped, 0.0, 3.0, 1.0;
+  Osc2Octave => "osc2_octave", "Osc 2 octave", Unit::Octaves, Stepped, -3.0, 3.0, 1.0;
+  Osc2Semitone => "osc2_semitone", "Osc 2 semitone", Unit::Semitones, Stepped, -12.0, 12.0, 0.0;
+  Osc2Fine => "osc2_fine", "Osc 2 fine tune", Unit::Semitones, Linear, -1.0, 1.0, 0.0;
+  Osc2Level => "osc2_level", "Osc 2 level", Unit::Percent, Linear, 0.0, 1.0, 0.25;
+  Osc2VelocityAmount => "osc2_velocity", "Osc 2 velocity amount", Unit::Percent, Linear, 0.0, 1.0, 0.0;
+  Osc3Waveform => "osc3_waveform", "Osc 3 waveform", Unit::Choice(WAVEFORMS), Stepped, 0.0, 3.0, 2.0;
+  Osc3Octave => "osc3_octave", "Osc 3 octave", Unit::Octaves, Stepped, -3.0, 3.0, 0.0;
+  Osc3Semitone => "osc3_semitone", "Osc 3 semitone", Unit::Semitones, Stepped, -12.0, 12.0, 7.0;
+  Osc3Fine => "osc3_fine", "Osc 3 fine tune", Unit::Semitones, Linear, -1.0, 1.0, 0.0;
+  Osc3Level => "osc3_level", "Osc 3 level", Unit::Percent, Linear, 0.0, 1.0, 0.25;
+  Osc3VelocityAmount => "osc3_velocity", "Osc 3 velocity amount", Unit::Percent, Linear, 0.0, 1.0, 0.0;
+  Osc4Waveform => "osc4_waveform", "Osc 4 waveform", Unit::Choice(WAVEFORMS), Stepped, 0.0, 3.0, 3.0;
+  Osc4Octave => "osc4_octave", "Osc 4 octave", Unit::Octaves, Stepped, -3.0, 3.0, 0.0;
+  Osc4Semitone => "osc4_semitone", "Osc 4 semitone", Unit::Semitones, Stepped, -12.0, 12.0, 0.0;
+  Osc4Fine => "osc4_fine", "Osc 4 fine tune", Unit::Semitones, Linear, -1.0, 1.0, 0.1;
+  Osc4Level => "osc4_level", "Osc 4 level", Unit::Percent, Linear, 0.0, 1.0, 0.25;
+  Osc4VelocityAmount => "osc4_velocity", "Osc 4 velocity amount", Unit::Percent, Linear, 0.0, 1.0, 0.0;
+
+  GlideTime => "glide_time", "Glide time", Unit::Seconds, Linear, 0.0, 0.4, 0.02;
+  VibratoDepth => "vibrato_depth", "Vibrato depth", Unit::Semitones, Linear, 0.0, 2.0, 0.0;
+
+  VelocityCurve => "velocity_curve", "Velocity curve", Unit::Choice(VELOCITY_CURVES), Stepped, 0.0, 3.0, VELOCITY_LINEAR as f32;
+  VelocityToAmp => "velocity_to_amp", "Velocity to amp", Unit::Percent, Linear, 0.0, 1.0, 0.8;
+  VelocityToCutoff => "velocity_to_cutoff", "Velocity to cutoff", Unit::Percent, Linear, 0.0, 1.0, 0.0;
+  VelocityToEnv => "velocity_to_env", "Velocity to envelope", Unit::Percent, Linear, 0.0, 1.0, 0.0;
+
+  LpfCutoff => "lpf_cutoff", "LPF cutoff", Unit::Hz, Exponential, 20.0, 20000.0, 20000.0;
+  LpfResonance => "lpf_resonance", "LPF resonance", Unit::None, Linear, 0.05, 1.0, 0.25;
+
+  GateAttack => "gate_attack", "Amp attack", Unit::Ms, EnvelopeTime, 0.0, 10000.0, 10.0;
+  GateDecay => "gate_decay", "Amp decay", Unit::Ms, EnvelopeTime, 0.0, 10000.0, 320.0;
+  GateSustain => "gate_sustain", "Amp sustain", Unit::Percent, Linear, 0.0, 1.0, 0.8;
+  GateRelease => "gate_release", "Amp release", Unit::Ms, EnvelopeTime, 0.0, 10000.0, 320.0;
+  GateAttackCurve => "gate_attack_curve", "Amp attack curve", Unit::None, Exponential, 0.001, 100.0, 8.0;
+  GateDecayCurve => "gate_decay_curve", "Amp decay curve", Unit::None, Exponential, 0.001, 100.0, 0.04;
+  GateReleaseCurve => "gate_release_curve", "Amp release curve", Unit::None, Exponential, 0.001, 100.0, 0.04;
+
+  Env1Mode => "env1_mode", "Env 1 mode", Unit::Choice(ENV_MODES), Stepped, 0.0, 1.0, MOD_ENV_DAHDSR as f32;
+  Env1Delay => "env1_delay", "Env 1 delay", Unit::Ms, EnvelopeTime, 0.0, 10000.0, 0.0;
+  Env1Attack => "env1_attack", "Env 1 attack", Unit::Ms, EnvelopeTime, 0.0, 10000.0, 10.0;
+  Env1Hold => "env1_hold", "Env 1 hold", Unit::Ms, EnvelopeTime, 0.0, 10000.0, 0.0;
+  Env1Decay => "env1_decay", "Env 1 decay", Unit::Ms, EnvelopeTime, 0.0, 10000.0, 320.0;
+  Env1Sustain => "env1_sustain", "Env 1 sustain", Unit::Percent, Linear, 0.0, 1.0, 0.5;
+  Env1Release => "env1_release", "Env 1 release", Unit::Ms, EnvelopeTime, 0.0, 10000.0, 320.0;
+  Env2Mode => "env2_mode", "Env 2 mode", Unit::Choice(ENV_MODES), Stepped, 0.0, 1.0, MOD_ENV_DAHDSR as f32;
+  Env2Delay => "env2_delay", "Env 2 delay", Unit::Ms, EnvelopeTime, 0.0, 10000.0, 0.0;
+  Env2Attack => "env2_attack", "Env 2 attack", Unit::Ms, EnvelopeTime, 0.0, 10000.0, 10.0;
+  Env2Hold => "env2_hold", "Env 2 hold", Unit::Ms, EnvelopeTime, 0.0, 10000.0, 0.0;
+  Env2Decay => "env2_decay", "Env 2 decay", Unit::Ms, EnvelopeTime, 0.0, 10000.0, 320.0;
+  Env2Sustain => "env2_sustain", "Env 2 sustain", Unit::Percent, Linear, 0.0, 1.0, 0.5;
+  Env2Release => "env2_release", "Env 2 release", Unit::Ms, EnvelopeTime, 0.0, 10000.0, 320.0;
+
+  Lfo1Shape => "lfo1_shape", "LFO 1 shape", Unit::Choice(LFO_SHAPES), Stepped, 0.0, 5.0, LFO_SINE as f32;
+  Lfo1Rate => "lfo1_rate", "LFO 1 rate", Unit::Hz, Exponential, 0.05, 20.0, 3.5;
+  Lfo1Division => "lfo1_division", "LFO 1 tempo sync", Unit::Choice(TEMPO_DIVISIONS), Stepped, 0.0, 13.0, 0.0;
+  Lfo1Fade => "lfo1_fade", "LFO 1 fade in", Unit::Ms, EnvelopeTime, 0.0, 10000.0, 0.0;
+  Lfo1Phase => "lfo1_phase", "LFO 1 start phase", Unit::Degrees, Linear, 0.0, 360.0, 0.0;
+  Lfo1Retrigger => "lfo1_retrigger", "LFO 1 key retrigger", Unit::Toggle, Stepped, 0.0, 1.0, 0.0;
+  Lfo1Depth => "lfo1_depth", "LFO 1 depth", Unit::Percent, Linear, 0.0, 1.0, 1.0;
+  Lfo2Shape => "lfo2_shape", "LFO 2 shape", Unit::Choice(LFO_SHAPES), Stepped, 0.0, 5.0, LFO_SINE as f32;
+  Lfo2Rate => "lfo2_rate", "LFO 2 rate", Unit::Hz, Exponential, 0.05, 20.0, 3.5;
+  Lfo2Division => "lfo2_division", "LFO 2 tempo sync", Unit::Choice(TEMPO_DIVISIONS), Stepped, 0.0, 13.0, 0.0;
+  Lfo2Fade => "lfo2_fade", "LFO 2 fade in", Unit::Ms, EnvelopeTime, 0.0, 10000.0, 0.0;
+  Lfo2Phase => "lfo2_phase", "LFO 2 start phase", Unit::Degrees, Linear, 0.0, 360.0, 0.0;
+  Lfo2Retrigger => "lfo2_retrigger", "LFO 2 key retrigger", Unit::Toggle, Stepped, 0.0, 1.0, 0.0;
+  Lfo2Depth => "lfo2_depth", "LFO 2 depth", Unit::Percent, Linear, 0.0, 1.0, 1.0;
+
+  DelayTime => "delay_time", "Delay time", Unit::Seconds, Linear, 0.0, 1.5, 0.38;
+  DelayFeedback => "delay_feedback", "Delay feedback", Unit::Percent, Linear, 0.0, 1.0, 0.3;
+  DelayMix => "delay_mix", "Delay mix", Unit::Percent, Linear, 0.0, 1.0, 0.0;
+  GainMultiplyBy => "gain", "Gain", Unit::None, Linear, 0.0, 3.0, 1.5;
+  ReverbDecayTime => "reverb_decay", "Reverb decay", Unit::Seconds, Linear, 0.0, 10.0, 5.0;
+  ReverbMix => "reverb_mix", "Reverb mix", Unit::Percent, Linear, 0.0, 1.0, 0.5;
+  ChorusLfoFreq => "chorus_rate", "Chorus rate", Unit::Hz, Linear, 0.0, 5.0, 0.5;
+  ChorusBaseDelay => "chorus_delay", "Chorus delay", Unit::Seconds, Linear, 0.0, 0.05, 0.0025;
+  ChorusVariation => "chorus_depth", "Chorus depth", Unit::Seconds, Linear, 0.0, 0.01, 0.00025;
+  ChorusFeedback => "chorus_feedback", "Chorus feedback", Unit::Percent, Linear, 0.0, 1.0, 0.25;
+  ChorusMix => "chorus_mix", "Chorus mix", Unit::Percent, Linear, 0.0, 1.0, 0.25;
+  PhaserStages => "phaser_stages", "Phaser stages", Unit::None, Stepped, 4.0, 12.0, 4.0;
+  PhaserLfoRate => "phaser_rate", "Phaser rate", Unit::Hz, Linear, 0.0, 5.0, 0.4;
+  PhaserDepth => "phaser_depth", "Phaser depth", Unit::Percent, Linear, 0.0, 1.0, 0.8;
+  PhaserFeedback => "phaser_feedback", "Phaser feedback", Unit::Percent, Linear, 0.0, 0.95, 0.37;
+  PhaserStereoOffset => "phaser_stereo", "Phaser stereo offset", Unit::Degrees, Linear, 0.0, 180.0, 45.0;
+  PhaserMix => "phaser_mix", "Phaser mix", Unit::Percent, Linear, 0.0, 1.0, 0.0;
+  Volume => "volume", "Volume", Unit::Percent, Linear, 0.0, 1.0, 1.0;
+
+  BendRangeUp => "bend_up", "Pitch bend up", Unit::Semitones, Linear, 0.0, 48.0, 2.0;
+  BendRangeDown => "bend_down", "Pitch bend down", Unit::Semitones, Linear, 0.0, 48.0, 2.0;
+  MpeBendRange => "mpe_bend_range", "MPE note bend range", Unit::Semitones, Linear, 0.0, 96.0, 48.0;
+
+  // матрица модуляции: источник, приёмник, глубина и второй источник-множитель каждой ячейки.
+  // Контроллеры исполнителя в покое дают ноль, поэтому их ячейки включены сразу.
+  Mod1Source => "mod1_source", "Mod 1 source", Unit::Choice(MOD_SOURCES), Stepped, 0.0, MOD_SOURCE_MAX, ModSource::Env1 as u8 as f32;
+  Mod1Destination => "mod1_destination", "Mod 1 destination", Unit::ModDestination, Stepped, 0.0, MOD_DESTINATION_MAX, ModDestination::Pitch.to_u8() as f32;
+  Mod1Amount => "mod1_amount", "Mod 1 amount", Unit::Percent, Linear, -1.0, 1.0, 0.0;
+  Mod1Via => "mod1_via", "Mod 1 via", Unit::Choice(MOD_SOURCES), Stepped, 0.0, MOD_SOURCE_MAX, ModSource::None as u8 as f32;
+  Mod2Source => "mod2_source", "Mod 2 source", Unit::Choice(MOD_SOURCES), Stepped, 0.0, MOD_SOURCE_MAX, ModSource::Env1 as u8 as f32;
+  Mod2Destination => "mod2_destination", "Mod 2 destination", Unit::ModDestination, Stepped, 0.0, MOD_DESTINATION_MAX, ModDestination::Param(ParamId::LpfCutoff).to_u8() as f32;
+  Mod2Amount => "mod2_amount", "Mod 2 amount", Unit::Percent, Linear, -1.0, 1.0, 0.0;
+  Mod2Via => "mod2_via", "Mod 2 via", Unit::Choice(MOD_SOURCES), Stepped, 0.0, MOD_SOURCE_MAX, ModSource::None as u8 as f32;
+  Mod3Source => "mod3_source", "Mod 3 source", Unit::Choice(MOD_SOURCES), Stepped, 0.0, MOD_SOURCE_MAX, ModSource::Env1 as u8 as f32;
+  Mod3Destination => "mod3_destination", "Mod 3 destination", Unit::ModDestination, Stepped, 0.0, MOD_DESTINATION_MAX, ModDestination::Param(ParamId::PhaserMix).to_u8() as f32;
+  Mod3Amount => "mod3_amount", "Mod 3 amount", Unit::Percent, Linear, -1.0, 1.0, 0.0;
+  Mod3Via => "mod3_via", "Mod 3 via", Unit::Choice(MOD_SOURCES), Stepped, 0.0, MOD_SOURCE_MAX, ModSource::None as u8 as f32;
+  Mod4Source => "mod4_source", "Mod 4 source", Unit::Choice(MOD_SOURCES), Stepped, 0.0, MOD_SOURCE_MAX, ModSource::ModWheel as u8 as f32;
+  Mod4Destination => "mod4_destination", "Mod 4 destination", Unit::ModDestination, Stepped, 0.0, MOD_DESTINATION_MAX, ModDestination::Param(ParamId::VibratoDepth).to_u8() as f32;
+  Mod4Amount => "mod4_amount", "Mod 4 amount", Unit::Percent, Linear, -1.0, 1.0, 16.0 / 63.0;
+  Mod4Via => "mod4_via", "Mod 4 via", Unit::Choice(MOD_SOURCES), Stepped, 0.0, MOD_SOURCE_MAX, ModSource::None as u8 as f32;
+  Mod5Source => "mod5_source", "Mod 5 source", Unit::Choice(MOD_SOURCES), Stepped, 0.0, MOD_SOURCE_MAX, ModSource::Aftertouch as u8 as f32;
+  Mod5Destination => "mod5_destination", "Mod 5 destination", Unit::ModDestination, Stepped, 0.0, MOD_DESTINATION_MAX, ModDestination::Param(ParamId::LpfCutoff).to_u8() as f32;
+  Mod5Amount => "mod5_amount", "Mod 5 amount", Unit::Percent, Linear, -1.0, 1.0, 20.0 / 63.0;
+  Mod5Via => "mod5_via", "Mod 5 via", Unit::Choice(MOD_SOURCES), Stepped, 0.0, MOD_SOURCE_MAX, ModSource::None as u8 as f32;
+  Mod6Source => "mod6_source", "Mod 6 source", Unit::Choice(MOD_SOURCES), Stepped, 0.0, MOD_SOURCE_MAX, ModSource::PolyAftertouch as u8 as f32;
+  Mod6Destination => "mod6_destination", "Mod 6 destination", Unit::ModDestination, Stepped, 0.0, MOD_DESTINATION_MAX, ModDestination::OscLevel.to_u8() as f32;
+  Mod6Amount => "mod6_amount", "Mod 6 amount", Unit::Percent, Linear, -1.0, 1.0, 20.0 / 63.0;
+  Mod6Via => "mod6_via", "Mod 6 via", Unit::Choice(MOD_SOURCES), Stepped, 0.0, MOD_SOURCE_MAX, ModSource::None as u8 as f32;
+  Mod7Source => "mod7_source", "Mod 7 source", Unit::Choice(MOD_SOURCES), Stepped, 0.0, MOD_SOURCE_MAX, ModSource::Breath as u8 as f32;
+  Mod7Destination => "mod7_destination", "Mod 7 destination", Unit::ModDestination, Stepped, 0.0, MOD_DESTINATION_MAX, ModDestination::Param(ParamId::LpfCutoff).to_u8() as f32;
+  Mod7Amount => "mod7_amount", "Mod 7 amount", Unit::Percent, Linear, -1.0, 1.0, 32.0 / 63.0;
+  Mod7Via => "mod7_via", "Mod 7 via", Unit::Choice(MOD_SOURCES), Stepped, 0.0, MOD_SOURCE_MAX, ModSource::None as u8 as f32;
+  Mod8Source => "mod8_source", "Mod 8 source", Unit::Choice(MOD_SOURCES), Stepped, 0.0, MOD_SOURCE_MAX, ModSource::Timbre as u8 as f32;
+  Mod8Destination => "mod8_destination", "Mod 8 destination", Unit::ModDestination, Stepped, 0.0, MOD_DESTINATION_MAX, ModDestination::Param(ParamId::LpfCutoff).to_u8() as f32;
+  Mod8Amount => "mod8_amount", "Mod 8 amount", Unit::Percent, Linear, -1.0, 1.0, 32.0 / 63.0;
+  Mod8Via => "mod8_via", "Mod 8 via", Unit::Choice(MOD_SOURCES), Stepped, 0.0, MOD_SOURCE_MAX, ModSource::None as u8 as f32;
+  DelayDivision => "delay_division", "Delay tempo sync", Unit::Choice(DELAY_DIVISIONS), Stepped, 0.0, 11.0, 0.0;
+}
+
+// MIDI learn хранит номер параметра + 1 в AtomicU8
+const _: () = assert!(PARAM_COUNT < u8::MAX as usize);
+
+/// Параметры, которых по несколько штук, по номеру осциллятора / LFO / огибающей
+pub const OSC_WAVEFORM: [ParamId; 4] = [ParamId::Osc1Waveform, ParamId::Osc2Waveform, ParamId::Osc3Waveform, ParamId::Osc4Waveform];
+pub const OSC_OCTAVE: [ParamId; 4] = [ParamId::Osc1Octave, ParamId::Osc2Octave, ParamId::Osc3Octave, ParamId::Osc4Octave];
+pub const OSC_SEMITONE: [ParamId; 4] = [ParamId::Osc1Semitone, ParamId::Osc2Semitone, ParamId::Osc3Semitone, ParamId::Osc4Semitone];
+pub const OSC_FINE: [ParamId; 4] = [ParamId::Osc1Fine, ParamId::Osc2Fine, ParamId::Osc3Fine, ParamId::Osc4Fine];
+pub const OSC_LEVEL: [ParamId; 4] = [ParamId::Osc1Level, ParamId::Osc2Level, ParamId::Osc3Level, ParamId::Osc4Level];
+pub const OSC_VELOCITY: [ParamId; 4] =
+  [ParamId::Osc1VelocityAmount, ParamId::Osc2VelocityAmount, ParamId::Osc3VelocityAmount, ParamId::Osc4VelocityAmount];
+
+pub const ENV_MODE: [ParamId; 2] = [ParamId::Env1Mode, ParamId::Env2Mode];
+pub const ENV_DELAY: [ParamId; 2] = [ParamId::Env1Delay, ParamId::Env2Delay];
+pub const ENV_ATTACK: [ParamId; 2] = [ParamId::Env1Attack, ParamId::Env2Attack];
+pub const ENV_HOLD: [ParamId; 2] = [ParamId::Env1Hold, ParamId::Env2Hold];
+pub const ENV_DECAY: [ParamId; 2] = [ParamId::Env1Decay, ParamId::Env2Decay];
+pub const ENV_SUSTAIN: [ParamId; 2] = [ParamId::Env1Sustain, ParamId::Env2Sustain];
+pub const ENV_RELEASE: [ParamId; 2] = [ParamId::Env1Release, ParamId::Env2Release];
+
+pub const LFO_SHAPE: [ParamId; 2] = [ParamId::Lfo1Shape, ParamId::Lfo2Shape];
+pub const LFO_RATE: [ParamId; 2] = [ParamId::Lfo1Rate, ParamId::Lfo2Rate];
+pub const LFO_DIVISION: [ParamId; 2] = [ParamId::Lfo1Division, ParamId::Lfo2Division];
+pub const LFO_FADE: [ParamId; 2] = [ParamId::Lfo1Fade, ParamId::Lfo2Fade];
+pub const LFO_PHASE: [ParamId; 2] = [ParamId::Lfo1Phase, ParamId::Lfo2Phase];
+pub const LFO_RETRIGGER: [ParamId; 2] = [ParamId::Lfo1Retrigger, ParamId::Lfo2Retrigger];
+pub const LFO_DEPTH: [ParamId; 2] = [ParamId::Lfo1Depth, ParamId::Lfo2Depth];
+
+pub const MOD_SOURCE: [ParamId; MOD_SLOT_COUNT] = [
+  ParamId::Mod1Source,
+  ParamId::Mod2Source,
+  ParamId::Mod3Source,
+  ParamId::Mod4Source,
+  ParamId::Mod5Source,
+  ParamId::Mod6Source,
+  ParamId::Mod7Source,
+  ParamId::Mod8Source,
+];
+pub const MOD_DESTINATION: [ParamId; MOD_SLOT_COUNT] = [
+  ParamId::Mod1Destination,
+  ParamId::Mod2Destination,
+  ParamId::Mod3Destination,
+  ParamId::Mod4Destination,
+  ParamId::Mod5Destination,
+  ParamId::Mod6Destination,
+  ParamId::Mod7Destination,
+  ParamId::Mod8Destination,
+];
+pub const MOD_AMOUNT: [ParamId; MOD_SLOT_COUNT] = [
+  ParamId::Mod1Amount,
+  ParamId::Mod2Amount,
+  ParamId::Mod3Amount,
+  ParamId::Mod4Amount,
+  ParamId::Mod5Amount,
+  ParamId::Mod6Amount,
+  ParamId::Mod7Amount,
+  ParamId::Mod8Amount,
+];
+pub const MOD_VIA: [ParamId; MOD_SLOT_COUNT] = [
+  ParamId::Mod1Via,
+  ParamId::Mod2Via,
+  ParamId::Mod3Via,
+  ParamId::Mod4Via,
+  ParamId::Mod5Via,
+  ParamId::Mod6Via,
+  ParamId::Mod7Via,
+  ParamId::Mod8Via,
+];
+
+impl ParamId {
+  pub fn info(self) -> &'static ParamInfo {
+    &PARAMS[self as usize]
+  }
+
+  pub fn from_index(index: usize) -> Option<Self> {
+    PARAMS.get(index).map(|info| info.id)
+  }
+
+  pub fn from_key(key: &str) -> Option<Self> {
+    PARAMS.iter().find(|info| info.key == key).map(|info| info.id)
+  }
+
+  /// Сглаживание параметра в модулях
+  pub fn smoothing(self) -> Smoothing {
+    match self {
+      ParamId::DelayTime | ParamId::ChorusBaseDelay => Smoothing::Linear(DELAY_SMOOTHING_MS),
+      _ => match self.info().curve {
+        Curve::Stepped | Curve::EnvelopeTime => Smoothing::None,
+        Curve::Linear | Curve::Exponential => Smoothing::OnePole(DEFAULT_SMOOTHING_MS),
+      },
+    }
+  }
+}
+
+/// Наибольшее 14-битное положение ручки
+pub const RAW_MAX: u16 = 0x3FFF;
+
+/// 7-битное значение CC 0..127 в 14-битное положение. Младшие биты повторяют старшие,
+/// чтобы 127 попадало точно в конец диапазона.
+pub fn cc_to_raw(value: u8) -> u16 {
+  let value = value.min(127) as u16;
+  (value << 7) | value
+}
+
+/// Хранилище значений всех параметров. Значение хранится как положение ручки
+/// в 14-битном виде (0..16383), как его присылают пары MSB/LSB и NRPN.
+/// Обычный 7-битный CC растягивается на весь диапазон.
+pub struct ParamStore {
+  raw: Vec<AtomicU16>,
+}
+
+impl Default for ParamStore {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+impl ParamStore {
+  pub fn new() -> Self {
+    Self {
+      raw: PARAMS.iter().map(|info| AtomicU16::new(Self::to_raw(info.normalize(info.default)))).collect(),
+    }
+  }
+
+  /// Положение ручки 0..1 в 14 бит
+  pub fn to_raw(t: f32) -> u16 {
+    (t.clamp(0.0, 1.0) * RAW_MAX as f32).round() as u16
+  }
+
+  /// 14-битное положение ручки 0..16383
+  pub fn raw(&self, id: ParamId) -> u16 {
+    self.raw[id as usize].load(Ordering::Relaxed)
+  }
+
+  pub fn set_raw(&self, id: ParamId, value: u16) {
+    self.raw[id as usize].store(value.min(RAW_MAX), Ordering::Relaxed);
+  }
+
+  /// Положение ручки 0..1
+  pub fn normalized(&self, id: ParamId) -> f32 {
+    self.raw(id) as f32 / RAW_MAX as f32
+  }
+
+  pub fn set_normalized(&self, id: ParamId, t: f32) {
+    self.set_raw(id, Self::to_raw(t));
+  }
+
+  /// Значение в единицах параметра, без модуляции
+  pub fn value(&self, id: ParamId) -> f32 {
+    id.info().denormalize(self.normalized(id))
+  }
+
+  pub fn set_value(&self, id: ParamId, value: f32) {
+    self.set_normalized(id, id.info().normalize(value));
+  }
+
+  /// Положения всех параметров по их номерам
+  pub fn snapshot(&self) -> Vec<u16> {
+    self.raw.iter().map(|raw| raw.load(Ordering::Relaxed)).collect()
+  }
+
+  /// То же, что `snapshot`, но в готовый буфер: для аудиопотока, без выделения памяти
+  pub fn snapshot_into(&self, raw: &mut [u16]) {
+    for (value, store) in raw.iter_mut().zip(&self.raw) {
+      *value = store.load(Ordering::Relaxed);
+    }
+  }
+
+  /// Выставляет положения из `snapshot`, лишние значения пропускаются
+  pub fn restore(&self, raw: &[u16]) {
+    for (store, value) in self.raw.iter().zip(raw) {
+      store.store((*value).min(RAW_MAX), Ordering::Relaxed);
+    }
+  }
+}
@@ -0,0 +1,162 @@
+//! Банк пресетов для Program Change.
+//!
+//! Пресет -- полный набор параметров: всё, что в нём не указано, берётся по умолчанию,
+//! поэтому переключение не зависит от того, что играло до него. Банк лежит в TOML-файле,
+//! пресет выбирается по номеру банка (CC 0/32) и номеру программы.
+//!
+//! ```toml
+//! [[preset]]
+//! name = "Soft pad"
+//! bank = 0         # необязательно, 0..16383
+//! program = 4      # 0..127
+//!
+//! [preset.params]
+//! lpf_cutoff = 1200.0
+//! gate_attack = 400.0
+//! env1_mode = 1    # огибающая 1 по точкам
+//!
+//! [preset.env1]    # форма для режима по точкам, так же env2
+//! points = [[10, 1.0], [200, 0.2, 3.0], [200, 1.0]]   # [время мс, уровень 0..1, кривая], до 64 точек
+//! loop = [1, 2]    # необязательно: сегменты, которые повторяются, пока нота держится
+//! ```
+
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::path::Path;
+
+use serde::Deserialize;
+
+use crate::audiomodules::envelope::{Breakpoint, BreakpointShape, MAX_BREAKPOINTS};
+use crate::params::{ParamId, ParamStore};
+use crate::synth_state::MOD_ENV_COUNT;
+
+#[derive(Deserialize)]
+struct PresetEntry {
+  name: String,
+  #[serde(default)]
+  bank: u16,
+  program: u8,
+  /// Значения в единицах параметров по ключам из `params::PARAMS`
+  #[serde(default)]
+  params: BTreeMap<String, f32>,
+  env1: Option<ShapeEntry>,
+  env2: Option<ShapeEntry>,
+}
+
+/// Форма ломаной огибающей в файле
+#[derive(Deserialize)]
+struct ShapeEntry {
+  /// `[время мс, уровень]` или `[время мс, уровень, кривая]`
+  points: Vec<Vec<f32>>,
+  #[serde(default, rename = "loop")]
+  loop_range: Option<(usize, usize)>,
+}
+
+impl ShapeEntry {
+  fn resolve(&self) -> Result<BreakpointShape, String> {
+    let points = self
+      .points
+      .iter()
+      .map(|point| match *point.as_slice() {
+        [time_ms, level] => Ok(Breakpoint { time_ms: time_ms.max(0.0), level: level.clamp(0.0, 1.0), curve: 0.0 }),
+        [time_ms, level, curve] => Ok(Breakpoint { time_ms: time_ms.max(0.0), level: level.clamp(0.0, 1.0), curve }),
+        _ => Err("a point is [time ms, level] or [time ms, level, curve]".to_string()),
+      })
+      .collect::<Result<Vec<_>, _>>()?;
+    if points.is_empty() {
+      return Err("no points".to_string());
+    }
+    if points.len() > MAX_BREAKPOINTS {
+      return Err(format!("{} points, at most {} are allowed", points.len(), MAX_BREAKPOINTS));
+    }
+    if let Some((start, end)) = self.loop_range {
+      if start > end || end >= points.len() {
+        return Err(format!("loop [{}, {}] is outside points 0..{}", start, end, points.len() - 1));
+      }
+    }
+    Ok(BreakpointShape { points, loop_range: self.loop_range })
+  }
+}
+
+#[derive(Deserialize)]
+struct PatchFile {
+  #[serde(default)]
+  preset: Vec<PresetEntry>,
+}
+
+pub struct Preset {
+  pub name: String,
+  pub bank: u16,
+  pub program: u8,
+  /// 14-битные положения всех параметров, по номеру параметра
+  pub raw: Vec<u16>,
+  /// Формы ломаных огибающих, по номеру огибающей
+  pub shapes: Vec<BreakpointShape>,
+}
+
+pub struct PatchBank {
+  presets: Vec<Preset>,
+}
+
+impl Default for PatchBank {
+  /// Без файла есть только пресет по умолчанию на программе 0
+  fn default() -> Self {
+    Self {
+      presets: vec![Preset {
+        name: "Init".to_string(),
+        bank: 0,
+        program: 0,
+        raw: ParamStore::new().snapshot(),
+        shapes: vec![BreakpointShape::default(); MOD_ENV_COUNT],
+      }],
+    }
+  }
+}
+
+impl PatchBank {
+  pub fn load(path: &Path) -> Result<Self, Box<dyn Error>> {
+    let text = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    let file: PatchFile = toml::from_str(&text).map_err(|e| format!("{}: {}", path.display(), e))?;
+    let presets = file
+      .preset
+      .into_iter()
+      .map(|entry| {
+        if entry.program > 127 || entry.bank > 0x3FFF {
+          return Err(format!("{}: preset '{}' has bank/program out of range", path.display(), entry.name));
+        }
+        let store = ParamStore::new();
+        for (key, value) in &entry.params {
+          let id = ParamId::from_key(key)
+            .ok_or_else(|| format!("{}: preset '{}': unknown parameter '{}'", path.display(), entry.name, key))?;
+          store.set_value(id, *value);
+        }
+        let shapes = [&entry.env1, &entry.env2]
+          .iter()
+          .enumerate()
+          .map(|(i, shape)| match shape {
+            Some(shape) => shape
+              .resolve()
+              .map_err(|e| format!("{}: preset '{}': env{}: {}", path.display(), entry.name, i + 1, e)),
+            None => Ok(BreakpointShape::default()),
+          })
+          .collect::<Result<Vec<_>, _>>()?;
+        Ok(Preset {
+          name: entry.name,
+          bank: entry.bank,
+          program: entry.program,
+          raw: store.snapshot(),
+          shapes,
+        })
+      })
+      .collect::<Result<Vec<_>, _>>()?;
+    Ok(Self { presets })
+  }
+
+  pub fn len(&self) -> usize {
+    self.presets.len()
+  }
+
+  pub fn find(&self, bank: u16, program: u8) -> Option<&Preset> {
+    self.presets.iter().find(|preset| preset.bank == bank && preset.program == program)
+  }
+}
@@ -0,0 +1,128 @@
+//! Рендер в WAV без звуковой карты.
+//!
+//! Цепочка модулей считается блок за блоком так быстро, как получится, а время
+//! для MIDI-событий идёт по посчитанным сэмплам (`Timeline::offline`). Источник --
+//! MIDI-файл или текстовый сценарий событий:
+//!
+//! ```text
+//! # секунды  байты сообщения в hex
+//! 0.0   90 3C 64
+//! 0.5   80 3C 00
+//! 0.5   B0 4A 20
+//! ```
+
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use hound::{SampleFormat, WavSpec, WavWriter};
+
+use crate::audiomodules::AudioModule;
+use crate::midi_events::Timeline;
+use crate::midi_file::TimedMessage;
+use crate::midi_service::MidiSender;
+
+// длина блока рендера: как у звуковой карты, события внутри всё равно ставятся по сэмплам
+const RENDER_BLOCK_FRAMES: usize = 256;
+
+pub struct RenderConfig {
+  pub path: PathBuf,
+  pub sample_rate: u32,
+  /// 16 и 24 -- целые, 32 -- float
+  pub bits: u16,
+  pub channels: u16,
+  /// Сколько секунд писать после последнего события, чтобы дозвучали релиз и реверб
+  pub tail_s: f32,
+}
+
+/// Читает сценарий: на строке время в секундах и байты сообщения в hex, `#` -- комментарий
+pub fn load_event_script(path: &Path) -> Result<Vec<TimedMessage>, Box<dyn Error>> {
+  let text = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+  let mut messages = Vec::new();
+  for (number, line) in text.lines().enumerate() {
+    let line = line.split('#').next().unwrap_or("").trim();
+    if line.is_empty() {
+      continue;
+    }
+    let bad_line = || format!("{}:{}: expected '<seconds> <hex bytes>'", path.display(), number + 1);
+    let mut words = line.split_whitespace();
+    let seconds: f64 = words.next().and_then(|w| w.parse().ok()).filter(|s: &f64| *s >= 0.0).ok_or_else(bad_line)?;
+    let bytes = words.map(|w| u8::from_str_radix(w, 16)).collect::<Result<Vec<u8>, _>>().map_err(|_| bad_line())?;
+    if bytes.is_empty() {
+      return Err(bad_line().into());
+    }
+    messages.push(TimedMessage { time_us: (seconds * 1_000_000.0) as u64, bytes });
+  }
+  messages.sort_by_key(|message| message.time_us);
+  Ok(messages)
+}
+
+/// Прогоняет `messages` через `sender` и пишет выход `modules` в WAV
+pub fn render(
+  config: &RenderConfig,
+  sender: &MidiSender,
+  timeline: &Timeline,
+  modules: Vec<Arc<Mutex<dyn AudioModule>>>,
+  messages: &[TimedMessage],
+) -> Result<(), Box<dyn Error>> {
+  if ![16, 24, 32].contains(&config.bits) {
+    return Err(format!("--bits: expected 16, 24 or 32, got {}", config.bits).into());
+  }
+  let spec = WavSpec {
+    channels: config.channels,
+    sample_rate: config.sample_rate,
+    bits_per_sample: config.bits,
+    sample_format: if config.bits == 32 { SampleFormat::Float } else { SampleFormat::Int },
+  };
+  let mut writer = WavWriter::create(&config.path, spec).map_err(|e| format!("{}: {}", config.path.display(), e))?;
+
+  let channels = config.channels as usize;
+  let sample_rate = config.sample_rate as f64;
+  let end_us = messages.last().map_or(0, |m| m.time_us) + (config.tail_s.max(0.0) as f64 * 1_000_000.0) as u64;
+  let total_frames = (end_us as f64 * sample_rate / 1_000_000.0).ceil() as usize;
+  let int_scale = ((1i64 << (config.bits - 1)) - 1) as f32;
+
+  let started = Instant::now();
+  let mut buffer = vec![0.0f32; RENDER_BLOCK_FRAMES * channels];
+  let mut pending = messages.iter().peekable();
+  let mut done = 0;
+  while done < total_frames {
+    let frames = RENDER_BLOCK_FRAMES.min(total_frames - done);
+    let block_end_us = ((done + frames) as f64 * 1_000_000.0 / sample_rate) as u64;
+    // события блока уходят с их временем, `EventScheduler` расставит их внутри блока
+    while let Some(message) = pending.next_if(|m| m.time_us < block_end_us) {
+      sender.send(message.time_us, &message.bytes);
+    }
+    timeline.set_offline_time(block_end_us);
+
+    let output = &mut buffer[..frames * channels];
+    output.fill(0.0);
+    for module in &modules {
+      if let Ok(mut m) = module.lock() {
+        m.process(output);
+      }
+    }
+    for &sample in output.iter() {
+      let sample = sample.clamp(-1.0, 1.0);
+      match config.bits {
+        32 => writer.write_sample(sample)?,
+        24 => writer.write_sample((sample * int_scale) as i32)?,
+        _ => writer.write_sample((sample * int_scale) as i16)?,
+      }
+    }
+    done += frames;
+  }
+  writer.finalize()?;
+
+  let seconds = total_frames as f64 / sample_rate;
+  let elapsed = started.elapsed().as_secs_f64().max(1e-6);
+  println!(
+    "Rendered {:.1} s to {} in {:.1} s ({:.0}x real time)",
+    seconds,
+    config.path.display(),
+    elapsed,
+    seconds / elapsed
+  );
+  Ok(())
+}
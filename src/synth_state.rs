@@ -1,96 +1,325 @@
 use std::sync::atomic::Ordering;
 
 use std::sync::{Arc, Mutex};
-use std::sync::atomic::{AtomicBool, AtomicU8, AtomicI8};
+use std::sync::atomic::{AtomicBool, AtomicU16, AtomicU8, AtomicU32};
 
+use atomic_float::AtomicF32;
 
+use crate::audiomodules::envelope::BreakpointShape;
+use crate::params::{ParamId, ParamStore, PARAM_COUNT, RAW_MAX};
+
+
+
+/// Кривые отклика на силу нажатия, значения `ParamId::VelocityCurve`
+pub const VELOCITY_LINEAR: u8 = 0;
+pub const VELOCITY_SOFT: u8 = 1;
+pub const VELOCITY_HARD: u8 = 2;
+pub const VELOCITY_FIXED: u8 = 3;
+
+/// Режимы модулирующих огибающих, значения `ParamId::Env1Mode`/`Env2Mode`
+pub const MOD_ENV_DAHDSR: u8 = 0;
+pub const MOD_ENV_BREAKPOINT: u8 = 1;
+
+/// Сколько модулирующих огибающих у синтезатора
+pub const MOD_ENV_COUNT: usize = 2;
+
+/// Сколько LFO у синтезатора
+pub const LFO_COUNT: usize = 2;
+
+/// Сколько ячеек в матрице модуляции
+pub const MOD_SLOT_COUNT: usize = 8;
+
+/// MIDI clock: тиков на четверть
+pub const CLOCK_PPQN: u32 = 24;
+
+/// Темп по MIDI clock держится в этих пределах
+pub const MIN_TEMPO_BPM: f32 = 20.0;
+pub const MAX_TEMPO_BPM: f32 = 300.0;
+
+/// Положение колеса высоты тона в покое
+pub const PITCH_BEND_CENTER: u16 = 0x2000;
+
+/// Каналов нот в нижней зоне MPE не больше 15
+pub const MPE_MAX_MEMBERS: u8 = 15;
+
+/// Больше нот звучать не может: нота и канал у каждой своя. Под столько нот
+/// место выделяется заранее, чтобы Note On в аудиопотоке не выделял память.
+pub const MAX_HELD_NOTES: usize = 128 * 16;
+
+/// CC 74 (тембр MPE) в покое
+pub const TIMBRE_CENTER: u8 = 64;
+
+/// В `controller_raw`: параметр последний раз менялся не с контроллера
+pub const NOT_FROM_CONTROLLER: u16 = u16::MAX;
+
+/// Пресет, который ждёт применения. Буферы выделяются один раз: аудиопоток копирует
+/// в них и из них и меняет их местами, ничего не выделяя и не освобождая.
+pub struct PendingPreset {
+    /// Пресет ещё не применён
+    pub ready: bool,
+    /// Положения параметров по номерам
+    pub raw: Vec<u16>,
+    /// Формы ломаных огибающих по номерам огибающей
+    pub shapes: Vec<BreakpointShape>,
+    /// `false` -- формы остаются как были (SysEx-дамп их не передаёт)
+    pub has_shapes: bool,
+}
+
+impl Default for PendingPreset {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PendingPreset {
+    pub fn new() -> Self {
+        Self {
+            ready: false,
+            raw: vec![0; PARAM_COUNT],
+            shapes: (0..MOD_ENV_COUNT).map(|_| BreakpointShape::preallocated()).collect(),
+            has_shapes: false,
+        }
+    }
+
+    /// Копирует положения и формы в буферы и помечает пресет готовым.
+    /// Положения, которых нет в `raw`, остаются какими были в буфере.
+    pub fn set(&mut self, raw: &[u16], shapes: Option<&[BreakpointShape]>) {
+        for (slot, value) in self.raw.iter_mut().zip(raw) {
+            *slot = (*value).min(RAW_MAX);
+        }
+        if let Some(shapes) = shapes {
+            for (slot, shape) in self.shapes.iter_mut().zip(shapes) {
+                slot.copy_from(shape);
+            }
+        }
+        self.has_shapes = shapes.is_some();
+        self.ready = true;
+    }
+}
+
+/// Звучащая нота вместе с силой нажатия и полифоническим послекасанием
+#[derive(Clone, Copy)]
+pub struct HeldNote {
+    pub note: u8,
+    pub velocity: u8,
+    pub pressure: u8,
+    /// Клавишу уже отпустили, нота звучит, пока её держит педаль
+    pub released: bool,
+    /// Нота была нажата, когда взяли sostenuto, и педаль держит её
+    pub sostenuto: bool,
+    /// MIDI-канал ноты: в MPE у каждой ноты свой
+    pub channel: u8,
+    /// Колесо высоты своего канала MPE, 14 бит
+    pub bend: u16,
+    /// CC 74 своего канала MPE
+    pub timbre: u8,
+}
+
+impl HeldNote {
+    pub fn new(channel: u8, note: u8, velocity: u8) -> Self {
+        Self {
+            note,
+            velocity,
+            pressure: 0,
+            released: false,
+            sostenuto: false,
+            channel,
+            bend: PITCH_BEND_CENTER,
+            timbre: TIMBRE_CENTER,
+        }
+    }
+}
 
 pub struct SynthState {
     pub last_key: AtomicU8,
+    pub last_velocity: AtomicU8,
     pub has_key_pressed: AtomicBool,
-    pub nazatie_knopki: Mutex<Vec<u8>>,
-
-    pub poli_rezim : AtomicBool,
-
-    pub gromkost: Mutex<Vec<f32>>,
-
-    pub waveformis: Vec<AtomicU8>,
-
-    pub sdvig_oktov: Vec<AtomicI8>,
-    pub nnno: Vec<AtomicI8>,
-    pub micro_zdvig: Mutex<Vec<f32>>,
-
-    pub delay_delay_time: AtomicU8,
-    pub delay_feedback: AtomicU8,
-    pub delay_mix: AtomicU8,
-    pub gain_multiply_by: AtomicU8,
-    pub lpf_cutoff: AtomicU8,
-    pub lpf_res_factor: AtomicU8,
-    pub gate_attack: AtomicU8,
-    pub gate_decay: AtomicU8,
-    pub gate_sustain: AtomicU8,
-    pub gate_release: AtomicU8,
-    pub reverb_decay_time: AtomicU8,
-    pub reverb_dry_wet_mix: AtomicU8,
-    pub glide_time: AtomicU8,
-    pub chorus_lfo_freq: AtomicU8,
-    pub chorus_base_delay_sec: AtomicU8,
-    pub chorus_variation_sec: AtomicU8,
-    pub chorus_feedback: AtomicU8,
-    pub chorus_mix: AtomicU8,
-    pub volume_volume: AtomicU8,
+    /// Растёт на каждое Note On, чтобы модули могли заметить новую ноту даже при легато
+    pub note_on_counter: AtomicU32,
+    pub nazatie_knopki: Mutex<Vec<HeldNote>>,
+
+    /// Все ручки синтезатора, см. `params::PARAMS`
+    pub params: ParamStore,
+
+    /// Формы огибающих в режиме `MOD_ENV_BREAKPOINT`, задаются пресетом
+    pub mod_env_breakpoints: Vec<Mutex<BreakpointShape>>,
+    /// Текущие значения огибающих 0..1, пишет `ModEnvelope`
+    pub mod_env_values: Vec<AtomicF32>,
+    /// Текущие значения LFO -1..1, пишет `LfoModule`
+    pub lfo_values: Vec<AtomicF32>,
+    pub tempo_bpm: AtomicF32,
+    /// Транспорт DAW по MIDI clock: играет ли песня и где она, в тиках `CLOCK_PPQN`
+    pub transport_running: AtomicBool,
+    pub song_position: AtomicU32,
+    /// Растёт на Start/Continue и перемотку, чтобы модули подстроились под позицию
+    pub transport_counter: AtomicU32,
+
+    /// Колесо высоты тона, 14 бит, `PITCH_BEND_CENTER` -- в покое
+    pub pitch_bend: AtomicU16,
+    pub mod_wheel: AtomicU8,
+    /// Послекасание на весь канал (0xD0)
+    pub aftertouch: AtomicU8,
+    pub breath: AtomicU8,
+    pub sustain_pedal: AtomicBool,
+    pub sostenuto_pedal: AtomicBool,
+    /// MPE, нижняя зона: канал 1 общий, следующие `mpe_members` каналов -- по ноте на канал.
+    /// 0 -- MPE выключен.
+    pub mpe_members: AtomicU8,
+    /// Смещения параметров от матрицы модуляции для последней ноты, пишет `ModMatrix`
+    pub mod_offsets: Vec<AtomicF32>,
+    /// Положение параметра, выставленное с привязанного CC, или `NOT_FROM_CONTROLLER`.
+    /// Пока параметр стоит там же, обратная связь не отправляет контроллеру его же движение.
+    pub controller_raw: Vec<AtomicU16>,
+
+    /// Новый пресет: `PresetSwitch` применит его в тишине
+    pub pending_preset: Mutex<PendingPreset>,
+    /// Растёт, когда `PresetSwitch` подменил параметры: сглаживание встаёт на новые
+    /// значения сразу, а не тянется от старого пресета
+    pub preset_counter: AtomicU32,
+
+    /// Параметр, который ждёт MIDI learn: номер параметра + 1, 0 -- никто не ждёт
+    midi_learn: AtomicU8,
 }
 
 impl SynthState {
-    pub fn new(kol_osc: usize) -> Arc<Self> {
-        let state = Arc::new(Self {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self {
             last_key: AtomicU8::new(0),
+            last_velocity: AtomicU8::new(0),
             has_key_pressed: AtomicBool::new(false),
-            nazatie_knopki: Mutex::new(Vec::new()),
-            poli_rezim: AtomicBool::new(false),
-
-            gromkost: Mutex::new(vec![0.25; kol_osc]),
-            waveformis: (0..kol_osc).map(|_| AtomicU8::new(0)).collect(),
-            sdvig_oktov: (0..kol_osc).map(|_| AtomicI8::new(0)).collect(),
-            nnno: (0..kol_osc).map(|_| AtomicI8::new(0)).collect(),
-            micro_zdvig: Mutex::new(vec![0.0; kol_osc]),
-
-            delay_delay_time: AtomicU8::new(32),
-            delay_feedback: AtomicU8::new(38),
-            delay_mix: AtomicU8::new(32),
-            gain_multiply_by: AtomicU8::new(64),
-            lpf_cutoff: AtomicU8::new(127),
-            lpf_res_factor: AtomicU8::new(32),
-            gate_attack: AtomicU8::new(3),
-            gate_decay: AtomicU8::new(32),
-            gate_sustain: AtomicU8::new(100),
-            gate_release: AtomicU8::new(32),
-            reverb_decay_time: AtomicU8::new(38),
-            reverb_dry_wet_mix: AtomicU8::new(32),
-            glide_time: AtomicU8::new(6),
-            chorus_lfo_freq: AtomicU8::new(13),
-            volume_volume: AtomicU8::new(127),
-            chorus_base_delay_sec: AtomicU8::new(6),
-            chorus_variation_sec: AtomicU8::new(3),
-            chorus_feedback: AtomicU8::new(32),
-            chorus_mix: AtomicU8::new(32),
-        });
-
-
-        if kol_osc > 0 { state.sdvig_oktov[0].store(0, Ordering::Relaxed); }
-        if kol_osc > 1 { 
-            state.sdvig_oktov[1].store(1, Ordering::Relaxed); 
-            state.waveformis[1].store(1, Ordering::Relaxed);
+            note_on_counter: AtomicU32::new(0),
+            nazatie_knopki: Mutex::new(Vec::with_capacity(MAX_HELD_NOTES)),
+
+            params: ParamStore::new(),
+
+            mod_env_breakpoints: (0..MOD_ENV_COUNT).map(|_| Mutex::new(BreakpointShape::preallocated())).collect(),
+            mod_env_values: (0..MOD_ENV_COUNT).map(|_| AtomicF32::new(0.0)).collect(),
+            lfo_values: (0..LFO_COUNT).map(|_| AtomicF32::new(0.0)).collect(),
+            tempo_bpm: AtomicF32::new(120.0),
+            transport_running: AtomicBool::new(false),
+            song_position: AtomicU32::new(0),
+            transport_counter: AtomicU32::new(0),
+
+            pitch_bend: AtomicU16::new(PITCH_BEND_CENTER),
+            mod_wheel: AtomicU8::new(0),
+            aftertouch: AtomicU8::new(0),
+            breath: AtomicU8::new(0),
+            sustain_pedal: AtomicBool::new(false),
+            sostenuto_pedal: AtomicBool::new(false),
+            mpe_members: AtomicU8::new(0),
+            mod_offsets: (0..PARAM_COUNT).map(|_| AtomicF32::new(0.0)).collect(),
+            controller_raw: (0..PARAM_COUNT).map(|_| AtomicU16::new(NOT_FROM_CONTROLLER)).collect(),
+
+            pending_preset: Mutex::new(PendingPreset::new()),
+            preset_counter: AtomicU32::new(0),
+
+            midi_learn: AtomicU8::new(0),
+        })
+    }
+
+    /// Следующий пришедший CC будет привязан к `id`
+    pub fn arm_midi_learn(&self, id: ParamId) {
+        self.midi_learn.store(id as u8 + 1, Ordering::Relaxed);
+    }
+
+    pub fn cancel_midi_learn(&self) {
+        self.midi_learn.store(0, Ordering::Relaxed);
+    }
+
+    /// Забирает ждущий MIDI learn параметр, если он есть
+    pub fn take_midi_learn(&self) -> Option<ParamId> {
+        match self.midi_learn.swap(0, Ordering::Relaxed) {
+            0 => None,
+            index => ParamId::from_index(index as usize - 1),
         }
-        if kol_osc > 2 {
-            state.nnno[2].store(7, Ordering::Relaxed);   
-            state.waveformis[2].store(2, Ordering::Relaxed); 
+    }
+
+    /// Смещение положения ручки от матрицы модуляции
+    pub fn mod_offset(&self, id: ParamId) -> f32 {
+        self.mod_offsets[id as usize].load(Ordering::Relaxed)
+    }
+
+    /// Стоит ли параметр там, куда его поставил привязанный CC
+    pub fn set_by_controller(&self, id: ParamId) -> bool {
+        self.controller_raw[id as usize].load(Ordering::Relaxed) == self.params.raw(id)
+    }
+
+    /// Положение ручки 0..1 с учётом матрицы модуляции
+    pub fn normalized(&self, id: ParamId) -> f32 {
+        (self.params.normalized(id) + self.mod_offset(id)).clamp(0.0, 1.0)
+    }
+
+    /// Значение параметра в его единицах с учётом матрицы модуляции
+    pub fn value(&self, id: ParamId) -> f32 {
+        id.info().denormalize(self.normalized(id))
+    }
+
+    /// Номер варианта для параметров-переключателей. Модуляцию не учитывает.
+    pub fn choice(&self, id: ParamId) -> u8 {
+        self.params.value(id) as u8
+    }
+
+    pub fn flag(&self, id: ParamId) -> bool {
+        self.params.value(id) >= 0.5
+    }
+
+    /// Сдвиг высоты от колеса в полутонах, вверх и вниз со своими диапазонами
+    pub fn pitch_bend_semitones(&self) -> f32 {
+        let bend = (self.pitch_bend.load(Ordering::Relaxed) as f32 - PITCH_BEND_CENTER as f32) / PITCH_BEND_CENTER as f32;
+        if bend >= 0.0 {
+            bend * self.params.value(ParamId::BendRangeUp)
+        } else {
+            bend * self.params.value(ParamId::BendRangeDown)
         }
-        if kol_osc > 3 {
-            state.micro_zdvig.lock().unwrap()[3] = 0.1;
-            state.waveformis[3].store(3, Ordering::Relaxed);
+    }
+
+    /// Сдвиг высоты ноты MPE в полутонах по колесу её канала
+    pub fn note_bend_semitones(&self, bend: u16) -> f32 {
+        let bend = (bend as f32 - PITCH_BEND_CENTER as f32) / PITCH_BEND_CENTER as f32;
+        bend * self.params.value(ParamId::MpeBendRange)
+    }
+
+    /// Канал нот MPE (0..15)
+    pub fn is_mpe_member(&self, channel: u8) -> bool {
+        (1..=self.mpe_members.load(Ordering::Relaxed)).contains(&channel)
+    }
+
+    /// Позиция песни в четвертях
+    pub fn song_position_beats(&self) -> f32 {
+        self.song_position.load(Ordering::Relaxed) as f32 / CLOCK_PPQN as f32
+    }
+
+    /// Отклик 0..1 на силу нажатия с учётом выбранной кривой
+    pub fn velocity_response(&self, velocity: u8) -> f32 {
+        let v = velocity.min(127) as f32 / 127.0;
+        match self.choice(ParamId::VelocityCurve) {
+            VELOCITY_SOFT => v.sqrt(),
+            VELOCITY_HARD => v * v,
+            VELOCITY_FIXED => 1.0,
+            _ => v,
         }
+    }
 
-        state
+    /// Множитель 0..1 для параметра, к которому подмешана сила нажатия.
+    /// `amount` 0 -- сила нажатия не влияет, 1 -- параметр полностью ей пропорционален.
+    pub fn velocity_scale(&self, amount: ParamId, velocity: u8) -> f32 {
+        let amount = self.value(amount);
+        1.0 - amount * (1.0 - self.velocity_response(velocity))
     }
-}
\ No newline at end of file
+}
+
+/// Часть мультитембрального синтезатора: своё состояние и своя цепочка модулей,
+/// слушает один MIDI-канал или все сразу
+#[derive(Clone)]
+pub struct Part {
+    /// 0..15, `None` -- omni
+    pub channel: Option<u8>,
+    pub state: Arc<SynthState>,
+}
+
+impl Part {
+    /// Часть на первом канале в режиме MPE слушает и каналы нот
+    pub fn listens(&self, channel: u8) -> bool {
+        self.channel.is_none_or(|own| own == channel || (own == 0 && self.state.is_mpe_member(channel)))
+    }
+}
@@ -0,0 +1,336 @@
+//! SysEx-протокол: полный дамп состояния и смена одного параметра.
+//!
+//! Все сообщения начинаются с `F0 7D <устройство> <команда>`: 0x7D -- номер
+//! производителя для некоммерческих разработок, устройство -- номер части (0x7F -- все части).
+//! Числа больше 7 бит передаются парами байт, старшие 7 бит первыми.
+//!
+//! | команда | данные                                                   |
+//! |---------|----------------------------------------------------------|
+//! | `01`    | запрос дампа                                              |
+//! | `02`    | дамп: версия, число параметров (2), параметры (по 2),    |
+//! |         | контрольная сумма                                         |
+//! | `03`    | параметр: номер (2), 14-битное значение (2)               |
+//!
+//! Матрица модуляции -- обычные параметры `mod*_source` и т. д., поэтому входит в дамп.
+//!
+//! Контрольная сумма -- как у Roland: сумма байт данных от версии и до неё
+//! вместе с ней кратна 128. Формы огибающих по точкам в дамп не входят.
+
+use std::error::Error;
+
+use crate::midi_service::EventQueue;
+use crate::params::{ParamId, RAW_MAX};
+use crate::synth_state::SynthState;
+
+pub const SYSEX_START: u8 = 0xF0;
+pub const SYSEX_END: u8 = 0xF7;
+const MANUFACTURER_ID: u8 = 0x7D;
+/// Сообщение для всех частей сразу
+pub const ALL_DEVICES: u8 = 0x7F;
+
+const DUMP_REQUEST: u8 = 0x01;
+const DUMP: u8 = 0x02;
+const PARAM_CHANGE: u8 = 0x03;
+
+const DUMP_VERSION: u8 = 1;
+
+pub enum SysexMessage {
+  DumpRequest,
+  /// Положения параметров по номерам
+  Dump { raw: Vec<u16> },
+  ParamChange(ParamId, u16),
+}
+
+fn push14(out: &mut Vec<u8>, value: u16) {
+  out.push(((value >> 7) & 0x7F) as u8);
+  out.push((value & 0x7F) as u8);
+}
+
+fn read14(data: &[u8], at: usize) -> Option<u16> {
+  Some(((*data.get(at)? as u16) << 7) | *data.get(at + 1)? as u16)
+}
+
+fn checksum(data: &[u8]) -> u8 {
+  let sum: u32 = data.iter().map(|&b| b as u32).sum();
+  ((128 - sum % 128) % 128) as u8
+}
+
+/// Полный дамп состояния для части `device`
+pub fn dump(state: &SynthState, device: u8) -> Vec<u8> {
+  let raw = state.params.snapshot();
+  let mut data = vec![DUMP_VERSION];
+  push14(&mut data, raw.len() as u16);
+  for value in raw {
+    push14(&mut data, value);
+  }
+  data.push(checksum(&data));
+
+  let mut message = vec![SYSEX_START, MANUFACTURER_ID, device, DUMP];
+  message.extend(data);
+  message.push(SYSEX_END);
+  message
+}
+
+/// Разбирает сообщение. `Ok(None)` -- SysEx не наш, его просто пропускаем.
+pub fn parse(message: &[u8]) -> Result<Option<(u8, SysexMessage)>, Box<dyn Error>> {
+  let body = match message {
+    [SYSEX_START, MANUFACTURER_ID, body @ .., SYSEX_END] => body,
+    _ => return Ok(None),
+  };
+  let (&device, body) = body.split_first().ok_or("SysEx: missing device")?;
+  let (&command, data) = body.split_first().ok_or("SysEx: missing command")?;
+  let parsed = match command {
+    DUMP_REQUEST => SysexMessage::DumpRequest,
+    PARAM_CHANGE => {
+      let index = read14(data, 0).ok_or("SysEx: short parameter change")?;
+      let value = read14(data, 2).ok_or("SysEx: short parameter change")?;
+      let id = ParamId::from_index(index as usize).ok_or_else(|| format!("SysEx: unknown parameter {}", index))?;
+      SysexMessage::ParamChange(id, value.min(RAW_MAX))
+    },
+    DUMP => parse_dump(data)?,
+    _ => return Err(format!("SysEx: unknown command {:#04x}", command).into()),
+  };
+  Ok(Some((device, parsed)))
+}
+
+fn parse_dump(data: &[u8]) -> Result<SysexMessage, Box<dyn Error>> {
+  if checksum(data) != 0 {
+    return Err("SysEx dump: checksum mismatch".into());
+  }
+  if data.first() != Some(&DUMP_VERSION) {
+    return Err(format!("SysEx dump: unsupported version {:?}", data.first()).into());
+  }
+  let short = "SysEx dump: truncated";
+  let count = read14(data, 1).ok_or(short)? as usize;
+  let raw = (0..count).map(|i| read14(data, 3 + i * 2).ok_or(short)).collect::<Result<Vec<_>, _>>()?;
+  // после параметров -- только контрольная сумма
+  let expected = 3 + count * 2 + 1;
+  if data.len() < expected {
+    return Err(short.into());
+  }
+  if data.len() > expected {
+    return Err("SysEx dump: unexpected data after parameters".into());
+  }
+  Ok(SysexMessage::Dump { raw })
+}
+
+/// Применяет сообщение к части. Дамп и один параметр уходят через очередь части,
+/// как Program Change и CC: аудиопоток применяет их по порядку, а дамп -- через
+/// `pending_preset`, чтобы смена прошла без щелчка. Возвращает ответ, если он нужен.
+pub fn apply(state: &SynthState, queue: &mut EventQueue, device: u8, message: &SysexMessage) -> Option<Vec<u8>> {
+  match message {
+    SysexMessage::DumpRequest => return Some(dump(state, device)),
+    SysexMessage::Dump { raw } => queue.dump(raw),
+    SysexMessage::ParamChange(id, raw) => queue.set_param(*id, *raw),
+  }
+  None
+}
+
+/// Отдаёт параметры дампа в `pending_preset` сразу, мимо очереди. Для дампа из файла
+/// в консоли: у неё нет очереди, а MIDI с ней по порядку не связан.
+pub fn apply_dump(state: &SynthState, raw: &[u16]) {
+  let mut pending = state.pending_preset.lock().unwrap();
+  // в дампе с меньшим числом параметров недостающие остаются как были
+  state.params.snapshot_into(&mut pending.raw);
+  pending.set(raw, None);
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::sync::Arc;
+
+  use crate::midi_events::{MidiEvent, NotePlayer, EVENT_QUEUE_SIZE, PARAM_CHANGE as EVENT_PARAM_CHANGE};
+  use crate::midi_service::PartEvents;
+  use crate::params::PARAM_COUNT;
+  use crate::presets::PatchBank;
+
+  /// Сообщение с командой и данными, к дампу добавляется контрольная сумма
+  fn message(command: u8, mut data: Vec<u8>) -> Vec<u8> {
+    if command == DUMP {
+      data.push(checksum(&data));
+    }
+    let mut message = vec![SYSEX_START, MANUFACTURER_ID, 0, command];
+    message.extend(data);
+    message.push(SYSEX_END);
+    message
+  }
+
+  /// Применяет сообщение к части, возвращает ответ и события из очереди части
+  fn apply_queued(state: &SynthState, device: u8, message: &SysexMessage) -> (Option<Vec<u8>>, Vec<MidiEvent>) {
+    let (producer, mut consumer) = rtrb::RingBuffer::new(EVENT_QUEUE_SIZE);
+    let mut events = PartEvents::new(producer);
+    let reply = apply(state, &mut EventQueue::new(&mut events, 0, 0, false), device, message);
+    (reply, std::iter::from_fn(|| consumer.pop().ok()).collect())
+  }
+
+  fn parse_err(message: &[u8]) -> String {
+    match parse(message) {
+      Err(e) => e.to_string(),
+      Ok(_) => panic!("message parsed"),
+    }
+  }
+
+  #[test]
+  fn dump_round_trip() {
+    let state = SynthState::new();
+    state.params.set_raw(ParamId::LpfCutoff, 1234);
+    state.params.set_raw(ParamId::Mod1Amount, RAW_MAX);
+    let message = dump(&state, 3);
+
+    let (device, parsed) = parse(&message).unwrap().unwrap();
+    assert_eq!(device, 3);
+    let SysexMessage::Dump { raw } = &parsed else { panic!("not a dump") };
+    assert_eq!(raw, &state.params.snapshot());
+
+    let target = SynthState::new();
+    let (reply, events) = apply_queued(&target, 0, &parsed);
+    assert!(reply.is_none());
+    // дамп применяет аудиопоток, когда дойдёт до событий
+    assert!(!target.pending_preset.lock().unwrap().ready);
+    play(&target, &events);
+    let pending = target.pending_preset.lock().unwrap();
+    assert!(pending.ready);
+    assert_eq!(pending.raw, state.params.snapshot());
+    assert!(!pending.has_shapes);
+  }
+
+  /// Применяет события так, как это делает аудиопоток
+  fn play(state: &SynthState, events: &[MidiEvent]) {
+    let mut player = NotePlayer::new(Arc::new(PatchBank::default()));
+    for event in events {
+      player.apply(state, event);
+    }
+  }
+
+  #[test]
+  fn short_dump_keeps_changes_queued_before_it() {
+    let state = SynthState::new();
+    let (producer, mut consumer) = rtrb::RingBuffer::new(EVENT_QUEUE_SIZE);
+    let mut events = PartEvents::new(producer);
+    let mut queue = EventQueue::new(&mut events, 0, 0, false);
+
+    // ручку повернули перед дампом старой версии, в которой этого параметра ещё нет
+    queue.set_param(ParamId::DelayDivision, 4321);
+    let raw = vec![100; ParamId::DelayDivision as usize];
+    apply(&state, &mut queue, 0, &SysexMessage::Dump { raw });
+    // MIDI-поток ничего не поменял
+    assert_ne!(state.params.raw(ParamId::DelayDivision), 4321);
+
+    play(&state, &std::iter::from_fn(|| consumer.pop().ok()).collect::<Vec<_>>());
+    let pending = state.pending_preset.lock().unwrap();
+    assert!(pending.ready);
+    assert_eq!(pending.raw[ParamId::DelayDivision as usize], 4321);
+    assert_eq!(pending.raw[ParamId::LpfCutoff as usize], 100);
+  }
+
+  #[test]
+  fn dump_after_program_change_wins() {
+    let state = SynthState::new();
+    let (producer, mut consumer) = rtrb::RingBuffer::new(EVENT_QUEUE_SIZE);
+    let mut events = PartEvents::new(producer);
+    let mut queue = EventQueue::new(&mut events, 0, 0, false);
+    let raw = vec![200; PARAM_COUNT];
+    apply(&state, &mut queue, 0, &SysexMessage::Dump { raw: raw.clone() });
+    let events: Vec<_> = std::iter::from_fn(|| consumer.pop().ok()).collect();
+
+    // в одном блоке: Program Change, потом дамп
+    let mut player = NotePlayer::new(Arc::new(PatchBank::default()));
+    player.apply(&state, &MidiEvent { time_us: 0, status: 0xC0, channel: 0, data1: 0, data2: 0, value: 0 });
+    for event in &events {
+      player.apply(&state, event);
+    }
+    let pending = state.pending_preset.lock().unwrap();
+    assert!(pending.ready && !pending.has_shapes);
+    assert_eq!(pending.raw, raw);
+  }
+
+  #[test]
+  fn dump_that_does_not_fit_is_dropped_whole() {
+    let state = SynthState::new();
+    let (producer, mut consumer) = rtrb::RingBuffer::new(8);
+    let mut events = PartEvents::new(producer);
+    apply(&state, &mut EventQueue::new(&mut events, 0, 0, false), 0, &SysexMessage::Dump { raw: vec![0; PARAM_COUNT] });
+    assert!(consumer.pop().is_err());
+  }
+
+  #[test]
+  fn dump_request_is_answered_with_dump() {
+    let state = SynthState::new();
+    let (device, parsed) = parse(&message(DUMP_REQUEST, vec![])).unwrap().unwrap();
+    assert_eq!(device, 0);
+    assert_eq!(apply_queued(&state, 5, &parsed).0, Some(dump(&state, 5)));
+  }
+
+  #[test]
+  fn corrupted_checksum_is_rejected() {
+    let mut message = dump(&SynthState::new(), 0);
+    message[10] ^= 0x01;
+    assert!(parse_err(&message).contains("checksum"));
+  }
+
+  #[test]
+  fn truncated_dump_is_rejected() {
+    // число параметров больше, чем пришло значений
+    let mut data = vec![DUMP_VERSION];
+    push14(&mut data, 4);
+    push14(&mut data, 100);
+    push14(&mut data, 200);
+    assert!(parse_err(&message(DUMP, data)).contains("truncated"));
+
+    // нет контрольной суммы, а байты параметра и так дают сумму, кратную 128
+    let mut message = vec![SYSEX_START, MANUFACTURER_ID, 0, DUMP, DUMP_VERSION];
+    push14(&mut message, 1);
+    push14(&mut message, 126);
+    message.push(SYSEX_END);
+    assert!(parse_err(&message).contains("truncated"));
+  }
+
+  #[test]
+  fn dump_with_trailing_data_is_rejected() {
+    let mut data = vec![DUMP_VERSION];
+    push14(&mut data, 0);
+    data.push(0);
+    assert!(parse_err(&message(DUMP, data)).contains("unexpected data"));
+  }
+
+  #[test]
+  fn unknown_dump_version_is_rejected() {
+    let mut data = vec![DUMP_VERSION + 1];
+    push14(&mut data, 0);
+    assert!(parse_err(&message(DUMP, data)).contains("version"));
+  }
+
+  #[test]
+  fn param_change_goes_through_event_queue() {
+    let state = SynthState::new();
+    let mut data = vec![];
+    push14(&mut data, ParamId::LpfCutoff as u16);
+    push14(&mut data, 1234);
+    let (_, parsed) = parse(&message(PARAM_CHANGE, data)).unwrap().unwrap();
+    let (reply, events) = apply_queued(&state, 0, &parsed);
+    assert!(reply.is_none());
+    // параметр меняет аудиопоток, когда дойдёт до события
+    assert_ne!(state.params.raw(ParamId::LpfCutoff), 1234);
+    let [event] = events[..] else { panic!("expected one event") };
+    assert_eq!((event.status, event.data1, event.value), (EVENT_PARAM_CHANGE, ParamId::LpfCutoff as u8, 1234));
+  }
+
+  #[test]
+  fn param_change_out_of_range_is_rejected() {
+    let mut data = vec![];
+    push14(&mut data, PARAM_COUNT as u16);
+    push14(&mut data, 0);
+    assert!(parse_err(&message(PARAM_CHANGE, data)).contains("unknown parameter"));
+
+    // значение обрезано
+    let mut data = vec![];
+    push14(&mut data, 0);
+    assert!(parse_err(&message(PARAM_CHANGE, data)).contains("short"));
+  }
+
+  #[test]
+  fn foreign_sysex_is_skipped() {
+    assert!(parse(&[SYSEX_START, 0x41, 0x10, 0x42, SYSEX_END]).unwrap().is_none());
+  }
+}